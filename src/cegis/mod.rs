@@ -0,0 +1,380 @@
+use crate::checkpoint::Checkpointer;
+use crate::driver::{self, Search, Step};
+use crate::stats::SearchStats;
+use crate::wasm::{pure_i32_arity, Candidate, Spec, Whitelist};
+use crate::worker::Worker;
+use crate::{exec, solver, SuperoptimizerOpts};
+use parity_wasm::elements::Instruction;
+use structopt::StructOpt;
+use z3::ast::{self, Ast};
+use z3::{Context, Model, SatResult};
+
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(name = "cegis_opts", about = "SMT-based synthesis specific options.")]
+pub struct CegisOpts {
+    #[structopt(
+        long,
+        help = "The length of the shortest programs to solve for.",
+        default_value = "1"
+    )]
+    pub min_length: usize,
+
+    #[structopt(
+        long,
+        help = "The length of the longest programs to solve for, the length of the spec if not set."
+    )]
+    pub max_length: Option<usize>,
+}
+
+/// Returns the cell at given symbolic index, zero if it is out of bounds.
+fn select<'ctx>(
+    ctx: &'ctx Context,
+    cells: &[ast::BV<'ctx>],
+    idx: &ast::Int<'ctx>,
+) -> ast::BV<'ctx> {
+    cells
+        .iter()
+        .enumerate()
+        .fold(ast::BV::from_i64(ctx, 0, 32), |selected, (i, cell)| {
+            idx._eq(&ast::Int::from_u64(ctx, i as u64))
+                .ite(cell, &selected)
+        })
+}
+
+/// A program of given length whose instruction at each position is chosen by the solver, among
+/// the side effect free instructions of the whitelist.
+struct Sketch<'ctx> {
+    ctx: &'ctx Context,
+    choices: Vec<Instruction>,
+    /// The index of the chosen instruction at each position.
+    opcodes: Vec<ast::Int<'ctx>>,
+    /// The number of locals, including the parameters.
+    num_locals: usize,
+}
+
+impl<'ctx> Sketch<'ctx> {
+    fn new(
+        ctx: &'ctx Context,
+        choices: Vec<Instruction>,
+        length: usize,
+        num_locals: usize,
+    ) -> Self {
+        let opcodes = (0..length)
+            .map(|_| ast::Int::fresh_const(ctx, "opcode"))
+            .collect();
+        Self {
+            ctx,
+            choices,
+            opcodes,
+            num_locals,
+        }
+    }
+
+    /// Returns the constraint that each opcode indexes a choice.
+    fn domain(&self) -> ast::Bool<'ctx> {
+        let zero = ast::Int::from_u64(self.ctx, 0);
+        let num_choices = ast::Int::from_u64(self.ctx, self.choices.len() as u64);
+        let bounds: Vec<ast::Bool<'ctx>> = self
+            .opcodes
+            .iter()
+            .flat_map(|opcode| vec![opcode.ge(&zero), opcode.lt(&num_choices)])
+            .collect();
+        ast::Bool::from_bool(self.ctx, true).and(&bounds.iter().collect::<Vec<_>>())
+    }
+
+    /// Runs the program on given input. Returns the constraint that it never pops from an empty
+    /// stack and ends with one value, and that value.
+    ///
+    /// The stack is kept as one cell per position, since no program pushes more values than it
+    /// has instructions, along with a symbolic depth. Each position writes the value of the chosen
+    /// instruction to the cell of its first operand.
+    fn run(
+        &self,
+        converter: &solver::Converter<'ctx>,
+        input: &[i32],
+    ) -> (ast::Bool<'ctx>, ast::BV<'ctx>) {
+        let ctx = self.ctx;
+        let mut locals: Vec<ast::Dynamic<'ctx>> = (0..self.num_locals)
+            .map(|idx| {
+                let value = input.get(idx).cloned().unwrap_or(0);
+                ast::BV::from_i64(ctx, i64::from(value), 32).into()
+            })
+            .collect();
+
+        let mut cells = vec![ast::BV::from_i64(ctx, 0, 32); self.opcodes.len()];
+        let mut depth = ast::Int::from_u64(ctx, 0);
+        let mut valid = Vec::new();
+        for opcode in &self.opcodes {
+            let top = select(ctx, &cells, &depth.sub(&[&ast::Int::from_u64(ctx, 1)]));
+            let second = select(ctx, &cells, &depth.sub(&[&ast::Int::from_u64(ctx, 2)]));
+
+            let mut value = ast::BV::from_i64(ctx, 0, 32);
+            let mut arity = ast::Int::from_u64(ctx, 0);
+            for (idx, instr) in self.choices.iter().enumerate() {
+                let num_operands = pure_i32_arity(instr).unwrap();
+                let mut stack = solver::ValueStack::new();
+                match num_operands {
+                    0 => {}
+                    1 => stack.push(top.clone()),
+                    _ => {
+                        stack.push(second.clone());
+                        stack.push(top.clone());
+                    }
+                }
+                converter.step(instr, &mut stack, &mut locals);
+
+                let chosen = opcode._eq(&ast::Int::from_u64(ctx, idx as u64));
+                value = chosen.ite(&stack.pop_as::<ast::BV<'ctx>>(), &value);
+                arity = chosen.ite(&ast::Int::from_u64(ctx, num_operands as u64), &arity);
+            }
+
+            valid.push(depth.ge(&arity));
+            let position = depth.sub(&[&arity]);
+            cells = cells
+                .iter()
+                .enumerate()
+                .map(|(i, cell)| {
+                    position
+                        ._eq(&ast::Int::from_u64(ctx, i as u64))
+                        .ite(&value, cell)
+                })
+                .collect();
+            depth = position.add(&[&ast::Int::from_u64(ctx, 1)]);
+        }
+        valid.push(depth._eq(&ast::Int::from_u64(ctx, 1)));
+
+        let valid = ast::Bool::from_bool(ctx, true).and(&valid.iter().collect::<Vec<_>>());
+        (valid, cells[0].clone())
+    }
+
+    /// Returns the constraint that excludes the program the model chooses.
+    fn exclude(&self, model: &Model<'ctx>) -> ast::Bool<'ctx> {
+        let chosen: Vec<ast::Bool<'ctx>> = self
+            .opcodes
+            .iter()
+            .map(|opcode| opcode._eq(&model.eval(opcode).unwrap()))
+            .collect();
+        ast::Bool::from_bool(self.ctx, true)
+            .and(&chosen.iter().collect::<Vec<_>>())
+            .not()
+    }
+
+    /// Returns the instructions the model chooses.
+    fn instrs(&self, model: &Model<'ctx>) -> Vec<Instruction> {
+        self.opcodes
+            .iter()
+            .map(|opcode| {
+                let idx = model.eval(opcode).unwrap().as_u64().unwrap();
+                self.choices[idx as usize].clone()
+            })
+            .collect()
+    }
+}
+
+/// The state of a counterexample guided synthesis between [steps](Search::step).
+struct Cegis<'a, 'ctx> {
+    converter: &'a solver::Converter<'ctx>,
+    choices: Vec<Instruction>,
+    num_locals: usize,
+    max_length: usize,
+    /// The length of the programs being solved for.
+    length: usize,
+    /// The sketch of programs of the length and the solver its constraints are asserted to, set
+    /// up on the first step at the length.
+    synthesizer: Option<(Sketch<'ctx>, z3::Solver<'ctx>)>,
+    /// The number of test cases asserted to the solver.
+    num_encoded: usize,
+    /// The constraint that excludes the last solution.
+    exclusion: Option<ast::Bool<'ctx>>,
+}
+
+impl<'a, 'ctx> Search for Cegis<'a, 'ctx> {
+    /// The length of the programs to continue solving for. The constraints are rebuilt from the
+    /// test cases on resume, so the programs of that length rejected on the interpreter before
+    /// the checkpoint are solved for again.
+    type State = usize;
+
+    fn state(&self) -> usize {
+        self.length
+    }
+
+    fn restore(&mut self, length: usize) {
+        log::info!(
+            "CEGIS restarts solving for programs of length {} from the test cases of the checkpoint.",
+            length
+        );
+        self.length = length;
+        self.synthesizer = None;
+    }
+
+    fn step(&mut self, interpreter: &dyn exec::Interpreter, stats: &mut SearchStats) -> Step {
+        if self.length > self.max_length {
+            return Step::Done;
+        }
+        let converter = self.converter;
+        let ctx = converter.ctx();
+        if self.synthesizer.is_none() {
+            log::debug!("Solving for programs of length {}", self.length);
+            let sketch = Sketch::new(ctx, self.choices.clone(), self.length, self.num_locals);
+            let synthesizer = z3::Solver::new(ctx);
+            synthesizer.assert(&sketch.domain());
+            // Stack validity doesn't depend on the input.
+            synthesizer.assert(&sketch.run(converter, &[]).0);
+            self.synthesizer = Some((sketch, synthesizer));
+            self.num_encoded = 0;
+        }
+        let (sketch, synthesizer) = self.synthesizer.as_ref().unwrap();
+
+        let num_test_cases = interpreter.num_test_cases();
+        for idx in self.num_encoded..num_test_cases {
+            // Test cases where the spec traps don't constrain the program.
+            if let (input, Some(expected)) = exec::i32_test_case(interpreter, idx) {
+                let (_, output) = sketch.run(converter, &input);
+                synthesizer.assert(&output._eq(&ast::BV::from_i64(ctx, i64::from(expected), 32)));
+            }
+        }
+        self.num_encoded = num_test_cases;
+
+        stats.iterations += 1;
+        match synthesizer.check() {
+            SatResult::Sat => {
+                let model = synthesizer.get_model();
+                self.exclusion = Some(sketch.exclude(&model));
+                Step::Check(sketch.instrs(&model))
+            }
+            SatResult::Unsat => {
+                self.length += 1;
+                self.synthesizer = None;
+                Step::Continue
+            }
+            SatResult::Unknown => {
+                log::warn!("Failed to solve for programs of length {}.", self.length);
+                self.length += 1;
+                self.synthesizer = None;
+                Step::Continue
+            }
+        }
+    }
+
+    fn reject(&mut self) {
+        if let (Some((_, synthesizer)), Some(exclusion)) =
+            (self.synthesizer.as_ref(), self.exclusion.take())
+        {
+            synthesizer.assert(&exclusion);
+        }
+    }
+}
+
+/// Solves for a program of each length in turn, whose instructions are symbolic choices among the
+/// side effect free instructions of the whitelist, such that it matches the spec on all test
+/// cases. Each solution is verified, and its counterexample is added as a test case to solve
+/// against, until one is verified or no program of the length matches the test cases.
+pub fn search(
+    options: &SuperoptimizerOpts,
+    cegis_options: &CegisOpts,
+    worker: &mut Worker,
+    checkpointer: Option<&mut Checkpointer>,
+    z3_solver: &solver::Z3Solver,
+    interpreter: &mut dyn exec::Interpreter,
+    spec: &mut Spec,
+) -> Option<Candidate> {
+    let instr_whitelist = Whitelist::new(
+        worker.rng(),
+        spec.num_params(),
+        spec.num_locals(),
+        &options.constants,
+        &options.opcodes,
+    );
+    let choices: Vec<Instruction> = instr_whitelist
+        .iter()
+        .filter(|instr| pure_i32_arity(instr).is_some())
+        .cloned()
+        .collect();
+    let max_length = cegis_options
+        .max_length
+        .unwrap_or_else(|| spec.num_instrs());
+    if cegis_options.min_length > max_length {
+        log::warn!(
+            "The spec has {} instructions, fewer than --min-length {}.",
+            max_length,
+            cegis_options.min_length
+        );
+    }
+
+    let mut cegis = Cegis {
+        converter: z3_solver.converter(),
+        choices,
+        num_locals: spec.num_params() + spec.num_locals(),
+        max_length,
+        length: cegis_options.min_length,
+        synthesizer: None,
+        num_encoded: 0,
+        exclusion: None,
+    };
+    let name = format!("CEGIS worker {}", worker.id());
+    driver::drive(
+        &mut cegis,
+        name,
+        options,
+        worker,
+        checkpointer,
+        z3_solver,
+        interpreter,
+        spec,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parity_wasm::elements::{FuncBody, FunctionType, Instructions, ValueType};
+
+    #[test]
+    fn sketch_test() {
+        let cfg = z3::Config::new();
+        let ctx = z3::Context::new(&cfg);
+        let func_type = FunctionType::new(vec![ValueType::I32; 2], Some(ValueType::I32));
+        let spec = FuncBody::new(vec![], Instructions::new(vec![Instruction::End]));
+        let converter = solver::Converter::new(&ctx, &func_type, spec.locals());
+
+        let choices = vec![
+            Instruction::GetLocal(0),
+            Instruction::GetLocal(1),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::I32Eqz,
+        ];
+        let sketch = Sketch::new(&ctx, choices, 3, 2);
+        let synthesizer = z3::Solver::new(&ctx);
+        synthesizer.assert(&sketch.domain());
+        // x - y on three test cases.
+        for (input, expected) in &[([5, 3], 2), ([3, 5], -2), ([0, 1], -1)] {
+            let (valid, output) = sketch.run(&converter, input);
+            synthesizer.assert(&valid);
+            synthesizer.assert(&output._eq(&ast::BV::from_i64(&ctx, *expected, 32)));
+        }
+        assert_eq!(synthesizer.check(), SatResult::Sat);
+        let model = synthesizer.get_model();
+        assert_eq!(
+            sketch.instrs(&model),
+            vec![
+                Instruction::GetLocal(0),
+                Instruction::GetLocal(1),
+                Instruction::I32Sub
+            ]
+        );
+        // It is the only one.
+        synthesizer.assert(&sketch.exclude(&model));
+        assert_eq!(synthesizer.check(), SatResult::Unsat);
+
+        // No program of length 3 computes x - y - 1 with these instructions.
+        let synthesizer = z3::Solver::new(&ctx);
+        synthesizer.assert(&sketch.domain());
+        for (input, expected) in &[([5, 3], 1), ([3, 5], -3), ([0, 1], -2), ([0, 0], -1)] {
+            let (valid, output) = sketch.run(&converter, input);
+            synthesizer.assert(&valid);
+            synthesizer.assert(&output._eq(&ast::BV::from_i64(&ctx, *expected, 32)));
+        }
+        assert_eq!(synthesizer.check(), SatResult::Unsat);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::exec::{self, Word};
+use crate::stats::SearchStats;
+use crate::worker::Worker;
+use crate::{Algorithm, Mode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The state of a search worker, enough to continue the search where it left off. `S` is the
+/// state specific to the search algorithm.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint<S> {
+    /// The seed of the random number generator of the worker.
+    pub seed: u64,
+    /// The number of 32 bit words the worker drew from its random number generator.
+    pub word_pos: u128,
+    /// Inputs of all test cases, including counterexamples and assertion inputs.
+    pub test_inputs: Vec<Vec<Word>>,
+    pub stats: SearchStats,
+    pub state: S,
+}
+
+impl<S> Checkpoint<S> {
+    /// Takes a checkpoint of given worker and interpreter, including the state of the random
+    /// number generator of the worker so that a resumed search draws the same numbers as the one
+    /// that took the checkpoint would have.
+    pub fn new(
+        worker: &Worker,
+        interpreter: &dyn exec::Interpreter,
+        stats: SearchStats,
+        state: S,
+    ) -> Self {
+        let (seed, word_pos) = worker.rng_state();
+        Self {
+            seed,
+            word_pos,
+            test_inputs: interpreter.test_inputs(),
+            stats,
+            state,
+        }
+    }
+
+    /// Restores the random number generator of given worker and the test cases of given
+    /// interpreter, and returns the stats and the search specific state.
+    pub fn restore(
+        self,
+        worker: &mut Worker,
+        interpreter: &mut dyn exec::Interpreter,
+    ) -> (SearchStats, S) {
+        worker.restore_rng(self.seed, self.word_pos);
+        interpreter.clear_test_cases();
+        for input in self.test_inputs {
+            interpreter.add_test_case(input.into_iter().map(to_value).collect());
+        }
+        (self.stats, self.state)
+    }
+}
+
+fn to_value(word: Word) -> wasmer_runtime::Value {
+    match word {
+        Word::I32(x) => wasmer_runtime::Value::I32(x),
+        Word::I64(x) => wasmer_runtime::Value::I64(x),
+    }
+}
+
+/// Returns the checkpoint file of the worker with given id searching given function.
+pub fn checkpoint_path(dir: &Path, func_name: &str, mode: Mode, worker_id: usize) -> PathBuf {
+    dir.join(format!("{}.{:?}.{}.json", func_name, mode, worker_id))
+}
+
+/// The search a checkpoint was taken of. Checkpoints are only resumed by a search of the same
+/// origin, since the state of a search means nothing to another one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Origin {
+    /// The hash of the input module.
+    pub input_hash: u64,
+    pub func_name: String,
+    /// The search algorithm and its options, in their `Debug` format.
+    pub algorithm: String,
+}
+
+impl Origin {
+    pub fn new(input: &[u8], func_name: &str, algorithm: &Algorithm) -> Self {
+        Self {
+            input_hash: exec::cache::hash_binary(input),
+            func_name: String::from(func_name),
+            algorithm: format!("{:?}", algorithm),
+        }
+    }
+}
+
+/// The contents of a checkpoint file.
+#[derive(Serialize, Deserialize)]
+struct CheckpointFile<C> {
+    origin: Origin,
+    checkpoint: C,
+}
+
+/// Loads the checkpoint of a worker when resuming, and saves it periodically.
+pub struct Checkpointer {
+    path: PathBuf,
+    interval: Duration,
+    resume: bool,
+    origin: Origin,
+    last_save: Instant,
+}
+
+impl Checkpointer {
+    pub fn new(path: PathBuf, interval: Duration, resume: bool, origin: Origin) -> Self {
+        Self {
+            path,
+            interval,
+            resume,
+            origin,
+            last_save: Instant::now(),
+        }
+    }
+
+    /// Returns the saved checkpoint if resuming and one exists. Panics if it was taken of a
+    /// search of another origin.
+    pub fn load<S: DeserializeOwned>(&self) -> Option<Checkpoint<S>> {
+        if !self.resume || !self.path.exists() {
+            return None;
+        }
+        let contents = std::fs::read_to_string(&self.path).expect("Failed to read checkpoint.");
+        let file: CheckpointFile<Checkpoint<S>> =
+            serde_json::from_str(&contents).unwrap_or_else(|err| {
+                panic!(
+                    "Failed to parse checkpoint {}: {}",
+                    self.path.display(),
+                    err
+                )
+            });
+        assert!(
+            file.origin == self.origin,
+            "Checkpoint {} was taken of {:?}, can't resume {:?} from it.",
+            self.path.display(),
+            file.origin,
+            self.origin
+        );
+        log::info!("Resuming from checkpoint {}", self.path.display());
+        Some(file.checkpoint)
+    }
+
+    /// Returns whether the checkpoint interval passed since the last save.
+    pub fn is_due(&self) -> bool {
+        self.last_save.elapsed() >= self.interval
+    }
+
+    /// Writes given checkpoint to a temporary file first and then renames it, so that killing the
+    /// process while saving leaves the previous checkpoint intact.
+    pub fn save<S: Serialize>(&mut self, checkpoint: &Checkpoint<S>) {
+        let file = CheckpointFile {
+            origin: self.origin.clone(),
+            checkpoint,
+        };
+        let contents = serde_json::to_string(&file).expect("Failed to serialize checkpoint.");
+        let tmp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, contents).expect("Failed to write checkpoint.");
+        std::fs::rename(&tmp_path, &self.path).expect("Failed to replace checkpoint.");
+        self.last_save = Instant::now();
+        log::debug!("Saved checkpoint {}", self.path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cegis::CegisOpts;
+    use crate::wasm::Candidate;
+    use parity_wasm::elements::Instruction;
+    use structopt::StructOpt;
+
+    #[test]
+    fn checkpoint_test() {
+        let dir = std::env::temp_dir().join(format!("rocinante-checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = checkpoint_path(&dir, "add", Mode::Synthesis, 3);
+        assert_eq!(path, dir.join("add.Synthesis.3.json"));
+
+        let checkpoint = Checkpoint {
+            seed: 42,
+            word_pos: 1 << 70,
+            test_inputs: vec![vec![Word::I32(-1), Word::I64(1 << 40)]],
+            stats: SearchStats {
+                iterations: 7,
+                ..SearchStats::default()
+            },
+            state: Candidate::from_instrs(vec![Instruction::GetLocal(0), Instruction::I32Eqz]),
+        };
+
+        let algorithm = Algorithm::Cegis(CegisOpts::from_iter(&["cegis"]));
+        let origin = Origin::new(&[0, 1, 2], "add", &algorithm);
+
+        // Nothing is loaded unless resuming from an existing checkpoint.
+        let mut checkpointer =
+            Checkpointer::new(path.clone(), Duration::from_secs(60), false, origin.clone());
+        checkpointer.save(&checkpoint);
+        assert!(checkpointer.load::<Candidate>().is_none());
+        assert!(!checkpointer.is_due());
+
+        let checkpointer = Checkpointer::new(path, Duration::from_secs(60), true, origin);
+        let loaded: Checkpoint<Candidate> = checkpointer.load().unwrap();
+        assert_eq!(loaded.seed, checkpoint.seed);
+        assert_eq!(loaded.word_pos, checkpoint.word_pos);
+        assert_eq!(loaded.test_inputs, checkpoint.test_inputs);
+        assert_eq!(loaded.stats, checkpoint.stats);
+        assert_eq!(loaded.state.instrs(), checkpoint.state.instrs());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn origin_test() {
+        let dir = std::env::temp_dir().join(format!("rocinante-origin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = checkpoint_path(&dir, "add", Mode::Synthesis, 0);
+        let algorithm = Algorithm::Cegis(CegisOpts::from_iter(&["cegis"]));
+        let origin = Origin::new(&[0, 1, 2], "add", &algorithm);
+        let checkpoint = Checkpoint {
+            seed: 42,
+            word_pos: 0,
+            test_inputs: vec![],
+            stats: SearchStats::default(),
+            state: (),
+        };
+        let checkpointer =
+            |origin| Checkpointer::new(path.clone(), Duration::from_secs(60), true, origin);
+        checkpointer(origin.clone()).save(&checkpoint);
+        assert!(checkpointer(origin).load::<()>().is_some());
+
+        // The same function of another input, another function, and another algorithm.
+        let other_algorithm =
+            Algorithm::Cegis(CegisOpts::from_iter(&["cegis", "--min-length", "2"]));
+        for other in &[
+            Origin::new(&[3, 4, 5], "add", &algorithm),
+            Origin::new(&[0, 1, 2], "sub", &algorithm),
+            Origin::new(&[0, 1, 2], "add", &other_algorithm),
+        ] {
+            let checkpointer = checkpointer(other.clone());
+            assert!(std::panic::catch_unwind(|| checkpointer.load::<()>()).is_err());
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
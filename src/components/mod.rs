@@ -0,0 +1,481 @@
+use crate::checkpoint::Checkpointer;
+use crate::driver::{self, Search, Step};
+use crate::perf::opcode_name;
+use crate::stats::SearchStats;
+use crate::wasm::{pure_i32_arity, Candidate, Spec, Whitelist};
+use crate::worker::Worker;
+use crate::{exec, solver, SuperoptimizerOpts};
+use parity_wasm::elements::Instruction;
+use structopt::StructOpt;
+use z3::ast::{self, Ast};
+use z3::{Context, Model, SatResult};
+
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(
+    name = "components_opts",
+    about = "Component-based synthesis specific options."
+)]
+pub struct ComponentsOpts {
+    #[structopt(
+        long,
+        help = "Comma separated opcodes of the components to synthesize from, repeated to use one more than once, e.g. `i32.add,i32.add,i32.shl`. `i32.const` stands for all the constants. Every whitelisted operator and constant once if not set.",
+        require_delimiter(true)
+    )]
+    pub components: Vec<String>,
+}
+
+/// Returns the multiset of components with given opcodes among the side effect free
+/// instructions of the whitelist, other than getting locals which are the inputs of the program.
+/// All of them if no opcode is given. Fails on an opcode that matches none of them.
+fn select_components(
+    instr_whitelist: &Whitelist,
+    opcodes: &[String],
+) -> Result<Vec<Instruction>, String> {
+    let operators: Vec<&Instruction> = instr_whitelist
+        .iter()
+        .filter(|instr| pure_i32_arity(instr).is_some())
+        .filter(|instr| match instr {
+            Instruction::GetLocal(_) => false,
+            _ => true,
+        })
+        .collect();
+    if opcodes.is_empty() {
+        return Ok(operators.into_iter().cloned().collect());
+    }
+
+    let mut components = Vec::new();
+    for opcode in opcodes {
+        let len = components.len();
+        components.extend(
+            operators
+                .iter()
+                .filter(|instr| opcode_name(instr) == *opcode)
+                .map(|instr| (*instr).clone()),
+        );
+        if components.len() == len {
+            return Err(format!(
+                "Component {} is not a whitelisted side effect free operator.",
+                opcode
+            ));
+        }
+    }
+    Ok(components)
+}
+
+/// A straight-line program composed of each component once, along with the inputs, in the
+/// encoding of "Oracle-Guided Component-Based Program Synthesis" (Jha et al., 2010).
+///
+/// Every value has a location: the inputs are at the first locations, and each component writes
+/// its output to a location after the ones of its operands. The program returns the value at the
+/// location of its output. Unlike the paper, the output of a component is used at most once, so
+/// that the program lowers to a stack machine without recomputing values.
+struct Library<'ctx> {
+    ctx: &'ctx Context,
+    components: Vec<Instruction>,
+    num_inputs: usize,
+    /// The locations of the output and of each operand of each component.
+    output_locations: Vec<ast::Int<'ctx>>,
+    operand_locations: Vec<Vec<ast::Int<'ctx>>>,
+    /// The location of the value the program returns.
+    return_location: ast::Int<'ctx>,
+}
+
+impl<'ctx> Library<'ctx> {
+    fn new(ctx: &'ctx Context, components: Vec<Instruction>, num_inputs: usize) -> Self {
+        let output_locations = components
+            .iter()
+            .map(|_| ast::Int::fresh_const(ctx, "output_location"))
+            .collect();
+        let operand_locations = components
+            .iter()
+            .map(|component| {
+                (0..pure_i32_arity(component).unwrap())
+                    .map(|_| ast::Int::fresh_const(ctx, "operand_location"))
+                    .collect()
+            })
+            .collect();
+        Self {
+            ctx,
+            components,
+            num_inputs,
+            output_locations,
+            operand_locations,
+            return_location: ast::Int::fresh_const(ctx, "return_location"),
+        }
+    }
+
+    fn num_locations(&self) -> usize {
+        self.num_inputs + self.components.len()
+    }
+
+    fn location(&self, location: usize) -> ast::Int<'ctx> {
+        ast::Int::from_u64(self.ctx, location as u64)
+    }
+
+    /// Returns the well-formedness constraint: locations are in range, components write to
+    /// distinct locations, operands are written before they are read, and only inputs are read
+    /// more than once.
+    fn well_formed(&self) -> ast::Bool<'ctx> {
+        let zero = self.location(0);
+        let first_output = self.location(self.num_inputs);
+        let num_locations = self.location(self.num_locations());
+
+        let mut constraints = vec![
+            self.return_location.ge(&zero),
+            self.return_location.lt(&num_locations),
+        ];
+        for (output, operands) in self.output_locations.iter().zip(&self.operand_locations) {
+            constraints.push(output.ge(&first_output));
+            constraints.push(output.lt(&num_locations));
+            for operand in operands {
+                constraints.push(operand.ge(&zero));
+                constraints.push(operand.lt(output));
+            }
+        }
+        for (i, output) in self.output_locations.iter().enumerate() {
+            for other in &self.output_locations[i + 1..] {
+                constraints.push(output._eq(other).not());
+            }
+        }
+        let reads: Vec<&ast::Int<'ctx>> = self
+            .operand_locations
+            .iter()
+            .flatten()
+            .chain(std::iter::once(&self.return_location))
+            .collect();
+        for (i, read) in reads.iter().enumerate() {
+            for other in &reads[i + 1..] {
+                constraints.push(read._eq(other).implies(&read.lt(&first_output)));
+            }
+        }
+        ast::Bool::from_bool(self.ctx, true).and(&constraints.iter().collect::<Vec<_>>())
+    }
+
+    /// Returns the constraint that the program returns given output on given input: the
+    /// semantics of each component, and the connection constraints that values at the same
+    /// location are equal.
+    fn returns(
+        &self,
+        converter: &solver::Converter<'ctx>,
+        input: &[i32],
+        output: i32,
+    ) -> ast::Bool<'ctx> {
+        let ctx = self.ctx;
+        let mut constraints = Vec::new();
+        // Each value along with its location.
+        let mut values: Vec<(ast::Int<'ctx>, ast::BV<'ctx>)> = input
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| {
+                let value = ast::BV::from_i64(ctx, i64::from(*value), 32);
+                (self.location(idx), value)
+            })
+            .collect();
+
+        let mut locals: Vec<ast::Dynamic<'ctx>> = Vec::new();
+        for (idx, component) in self.components.iter().enumerate() {
+            let mut stack = solver::ValueStack::new();
+            for location in &self.operand_locations[idx] {
+                let operand = ast::BV::fresh_const(ctx, "operand", 32);
+                stack.push(operand.clone());
+                values.push((location.clone(), operand));
+            }
+            converter.step(component, &mut stack, &mut locals);
+            let result = ast::BV::fresh_const(ctx, "result", 32);
+            constraints.push(result._eq(&stack.pop_as::<ast::BV<'ctx>>()));
+            values.push((self.output_locations[idx].clone(), result));
+        }
+        values.push((
+            self.return_location.clone(),
+            ast::BV::from_i64(ctx, i64::from(output), 32),
+        ));
+
+        for (i, (location, value)) in values.iter().enumerate() {
+            for (other_location, other_value) in &values[i + 1..] {
+                constraints.push(
+                    location
+                        ._eq(other_location)
+                        .implies(&value._eq(other_value)),
+                );
+            }
+        }
+        ast::Bool::from_bool(ctx, true).and(&constraints.iter().collect::<Vec<_>>())
+    }
+
+    /// Returns the constraint that excludes the locations the model chooses.
+    fn exclude(&self, model: &Model<'ctx>) -> ast::Bool<'ctx> {
+        let chosen: Vec<ast::Bool<'ctx>> = self
+            .output_locations
+            .iter()
+            .chain(self.operand_locations.iter().flatten())
+            .chain(std::iter::once(&self.return_location))
+            .map(|location| location._eq(&model.eval(location).unwrap()))
+            .collect();
+        ast::Bool::from_bool(self.ctx, true)
+            .and(&chosen.iter().collect::<Vec<_>>())
+            .not()
+    }
+
+    /// Lowers the program the model chooses to stack machine instructions, by emitting the
+    /// operands of each component before it.
+    fn instrs(&self, model: &Model<'ctx>) -> Vec<Instruction> {
+        let eval = |location: &ast::Int<'ctx>| model.eval(location).unwrap().as_u64().unwrap();
+        let mut sources = vec![None; self.num_locations()];
+        for (idx, location) in self.output_locations.iter().enumerate() {
+            sources[eval(location) as usize] = Some(idx);
+        }
+
+        fn emit(
+            location: usize,
+            sources: &[Option<usize>],
+            components: &[Instruction],
+            operands: &[Vec<u64>],
+            instrs: &mut Vec<Instruction>,
+        ) {
+            match sources[location] {
+                None => instrs.push(Instruction::GetLocal(location as u32)),
+                Some(idx) => {
+                    for &operand in &operands[idx] {
+                        emit(operand as usize, sources, components, operands, instrs);
+                    }
+                    instrs.push(components[idx].clone());
+                }
+            }
+        }
+
+        let operands: Vec<Vec<u64>> = self
+            .operand_locations
+            .iter()
+            .map(|locations| locations.iter().map(eval).collect())
+            .collect();
+        let mut instrs = Vec::new();
+        emit(
+            eval(&self.return_location) as usize,
+            &sources,
+            &self.components,
+            &operands,
+            &mut instrs,
+        );
+        instrs
+    }
+}
+
+/// The state of a component-based synthesis between [steps](Search::step).
+struct Components<'a, 'ctx> {
+    converter: &'a solver::Converter<'ctx>,
+    library: Library<'ctx>,
+    synthesizer: z3::Solver<'ctx>,
+    /// The number of test cases asserted to the solver.
+    num_encoded: usize,
+    /// The constraint that excludes the last solution.
+    exclusion: Option<ast::Bool<'ctx>>,
+}
+
+impl<'a, 'ctx> Search for Components<'a, 'ctx> {
+    /// The constraints are rebuilt from the test cases on resume, so the programs rejected on the
+    /// interpreter before the checkpoint are solved for again.
+    type State = ();
+
+    fn state(&self) {}
+
+    fn restore(&mut self, _state: ()) {
+        log::info!("Component-based synthesis restarts from the test cases of the checkpoint.");
+    }
+
+    fn step(&mut self, interpreter: &dyn exec::Interpreter, stats: &mut SearchStats) -> Step {
+        let num_test_cases = interpreter.num_test_cases();
+        for idx in self.num_encoded..num_test_cases {
+            // Test cases where the spec traps don't constrain the program.
+            if let (input, Some(expected)) = exec::i32_test_case(interpreter, idx) {
+                self.synthesizer
+                    .assert(&self.library.returns(self.converter, &input, expected));
+            }
+        }
+        self.num_encoded = num_test_cases;
+
+        stats.iterations += 1;
+        match self.synthesizer.check() {
+            SatResult::Sat => {
+                let model = self.synthesizer.get_model();
+                self.exclusion = Some(self.library.exclude(&model));
+                Step::Check(self.library.instrs(&model))
+            }
+            SatResult::Unsat => {
+                log::info!("No composition of the components matches the spec.");
+                Step::Done
+            }
+            SatResult::Unknown => {
+                log::warn!("Failed to solve for a composition of the components.");
+                Step::Done
+            }
+        }
+    }
+
+    fn reject(&mut self) {
+        if let Some(exclusion) = self.exclusion.take() {
+            self.synthesizer.assert(&exclusion);
+        }
+    }
+}
+
+/// Solves for a composition of the components that matches the spec on all test cases, verifies
+/// it, and adds its counterexample as a test case to solve against, until one is verified or no
+/// composition matches the test cases.
+pub fn search(
+    options: &SuperoptimizerOpts,
+    components_options: &ComponentsOpts,
+    worker: &mut Worker,
+    checkpointer: Option<&mut Checkpointer>,
+    z3_solver: &solver::Z3Solver,
+    interpreter: &mut dyn exec::Interpreter,
+    spec: &mut Spec,
+) -> Option<Candidate> {
+    let instr_whitelist = Whitelist::new(
+        worker.rng(),
+        spec.num_params(),
+        spec.num_locals(),
+        &options.constants,
+        &options.opcodes,
+    );
+    let components = match select_components(&instr_whitelist, &components_options.components) {
+        Ok(components) => components,
+        Err(err) => {
+            log::error!("{}", err);
+            return None;
+        }
+    };
+    let converter = z3_solver.converter();
+    let ctx = converter.ctx();
+
+    let library = Library::new(ctx, components, spec.num_params());
+    let synthesizer = z3::Solver::new(ctx);
+    synthesizer.assert(&library.well_formed());
+    let mut synthesis = Components {
+        converter,
+        library,
+        synthesizer,
+        num_encoded: 0,
+        exclusion: None,
+    };
+    let name = format!("Component-based synthesis worker {}", worker.id());
+    driver::drive(
+        &mut synthesis,
+        name,
+        options,
+        worker,
+        checkpointer,
+        z3_solver,
+        interpreter,
+        spec,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parity_wasm::elements::{FuncBody, FunctionType, Instructions, ValueType};
+
+    #[test]
+    fn select_components_test() {
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 1, 0, &[1, 2], &[]);
+        let components = select_components(
+            &instr_whitelist,
+            &[
+                "i32.add".to_string(),
+                "i32.add".to_string(),
+                "i32.const".to_string(),
+            ],
+        )
+        .unwrap();
+        assert_eq!(components.len(), 4);
+        assert_eq!(components[..2], [Instruction::I32Add, Instruction::I32Add]);
+        assert!(components.contains(&Instruction::I32Const(1)));
+        assert!(components.contains(&Instruction::I32Const(2)));
+
+        let components = select_components(&instr_whitelist, &[]).unwrap();
+        assert!(components.contains(&Instruction::I32Const(2)));
+        assert!(!components.contains(&Instruction::GetLocal(0)));
+        assert!(!components.contains(&Instruction::SetLocal(0)));
+
+        assert!(select_components(&instr_whitelist, &["i32.ad".to_string()]).is_err());
+    }
+
+    #[test]
+    fn library_test() {
+        let cfg = z3::Config::new();
+        let ctx = z3::Context::new(&cfg);
+        let func_type = FunctionType::new(vec![ValueType::I32; 2], Some(ValueType::I32));
+        let spec = FuncBody::new(vec![], Instructions::new(vec![Instruction::End]));
+        let converter = solver::Converter::new(&ctx, &func_type, spec.locals());
+
+        // (x + y) * x from a subtraction, an addition and a multiplication.
+        let components = vec![
+            Instruction::I32Mul,
+            Instruction::I32Sub,
+            Instruction::I32Add,
+        ];
+        let library = Library::new(&ctx, components, 2);
+        let synthesizer = z3::Solver::new(&ctx);
+        synthesizer.assert(&library.well_formed());
+        for &(x, y) in &[(2, 3), (-1, 4), (7, 0), (5, 5), (3, -2), (-4, -6), (100, 7)] {
+            synthesizer.assert(&library.returns(&converter, &[x, y], (x + y) * x));
+        }
+        assert_eq!(synthesizer.check(), SatResult::Sat);
+
+        let instrs = library.instrs(&synthesizer.get_model());
+        let verifier = solver::Z3Solver::new(
+            &ctx,
+            &func_type,
+            &FuncBody::new(
+                vec![],
+                Instructions::new(vec![
+                    Instruction::GetLocal(0),
+                    Instruction::GetLocal(1),
+                    Instruction::I32Add,
+                    Instruction::GetLocal(0),
+                    Instruction::I32Mul,
+                    Instruction::End,
+                ]),
+            ),
+        );
+        assert_eq!(verifier.verify(&instrs), solver::VerifyResult::Verified);
+
+        let model = synthesizer.get_model();
+        assert_eq!(
+            model.eval(&library.exclude(&model)).unwrap().as_bool(),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn shared_output_test() {
+        let cfg = z3::Config::new();
+        let ctx = z3::Context::new(&cfg);
+        let func_type = FunctionType::new(vec![ValueType::I32; 2], Some(ValueType::I32));
+        let spec = FuncBody::new(vec![], Instructions::new(vec![Instruction::End]));
+        let converter = solver::Converter::new(&ctx, &func_type, spec.locals());
+        let square_of_sum = |components| {
+            let library = Library::new(&ctx, components, 2);
+            let synthesizer = z3::Solver::new(&ctx);
+            synthesizer.assert(&library.well_formed());
+            for &(x, y) in &[(2, 3), (-1, 4), (7, 0), (5, 5), (3, -2)] {
+                synthesizer.assert(&library.returns(&converter, &[x, y], (x + y) * (x + y)));
+            }
+            synthesizer.check()
+        };
+
+        // (x + y) * (x + y) reads the sum twice, so it takes an addition for each.
+        assert_eq!(
+            square_of_sum(vec![Instruction::I32Mul, Instruction::I32Add]),
+            SatResult::Unsat
+        );
+        assert_eq!(
+            square_of_sum(vec![
+                Instruction::I32Mul,
+                Instruction::I32Add,
+                Instruction::I32Add
+            ]),
+            SatResult::Sat
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use crate::checkpoint::{Checkpoint, Checkpointer};
+use crate::stats::{Reporter, SearchStats};
+use crate::wasm::{Candidate, Spec};
+use crate::worker::Worker;
+use crate::{exec, solver, SuperoptimizerOpts};
+use parity_wasm::elements::Instruction;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// What a search does after a [step](Search::step).
+pub enum Step {
+    /// Check given candidate against the test cases, and verify it if it passes them.
+    Check(Vec<Instruction>),
+    /// Check given candidate, and if it fails the test cases, [fill its holes](fill_holes)
+    /// instead.
+    Fill(Vec<Instruction>),
+    /// Nothing to check yet.
+    Continue,
+    /// There are no more candidates.
+    Done,
+}
+
+/// A search that proposes candidates one step at a time, which [drive](drive) checks against the
+/// test cases and verifies.
+pub trait Search {
+    /// The state specific to the search kept in a [Checkpoint](Checkpoint).
+    type State: Serialize + DeserializeOwned;
+
+    fn state(&self) -> Self::State;
+
+    /// Continues from given state of a checkpoint.
+    fn restore(&mut self, state: Self::State);
+
+    /// Takes a step, taking the test cases added since the last step into account. Counts the
+    /// candidates it enumerates or solves for in the iterations of given stats.
+    fn step(&mut self, interpreter: &dyn exec::Interpreter, stats: &mut SearchStats) -> Step;
+
+    /// Drops the candidate of the last step, which fails the test cases.
+    fn reject(&mut self) {}
+
+    /// Logs search specific stats once the search ends.
+    fn finish(&self, _name: &str) {}
+}
+
+/// The state [drive](drive) keeps in a [Checkpoint](Checkpoint).
+#[derive(Serialize, Deserialize)]
+struct State<S> {
+    /// The verified candidate, if the search found one.
+    found: Option<Candidate>,
+    search: S,
+}
+
+/// Steps given search until one of its candidates is verified, or it runs out of candidates.
+/// Counterexamples are added to the test cases and shared with the other workers. The search
+/// continues from the checkpoint `checkpointer` loads if there is one, and saves checkpoints
+/// periodically and when it ends.
+#[allow(clippy::too_many_arguments)]
+pub fn drive<S: Search>(
+    search: &mut S,
+    name: String,
+    options: &SuperoptimizerOpts,
+    worker: &mut Worker,
+    mut checkpointer: Option<&mut Checkpointer>,
+    z3_solver: &solver::Z3Solver,
+    interpreter: &mut dyn exec::Interpreter,
+    spec: &mut Spec,
+) -> Option<Candidate> {
+    let mut stats = SearchStats::default();
+    let mut reporter = Reporter::new(name.clone(), Duration::from_secs(options.progress_interval));
+
+    if let Some(checkpoint) = checkpointer
+        .as_ref()
+        .and_then(|checkpointer| checkpointer.load::<State<S::State>>())
+    {
+        let (resumed_stats, state) = checkpoint.restore(worker, interpreter);
+        stats = resumed_stats;
+        if state.found.is_some() {
+            reporter.summarize(&stats);
+            return state.found;
+        }
+        search.restore(state.search);
+    }
+
+    let found = loop {
+        if worker.sync(interpreter).is_none() {
+            log::debug!("{} stopped.", name);
+            break None;
+        }
+        if let Some(checkpointer) = checkpointer.as_mut() {
+            if checkpointer.is_due() {
+                let state = State {
+                    found: None,
+                    search: search.state(),
+                };
+                checkpointer.save(&Checkpoint::new(worker, interpreter, stats.clone(), state));
+            }
+        }
+
+        let step = search.step(interpreter, &mut stats);
+        stats.num_test_cases = interpreter.num_test_cases();
+        reporter.report(&stats);
+        let (instrs, holes) = match step {
+            Step::Check(instrs) => (instrs, false),
+            Step::Fill(instrs) => (instrs, true),
+            Step::Continue => continue,
+            Step::Done => break None,
+        };
+
+        // Candidates are checked on the interpreter even if they were solved for against the test
+        // cases, since Z3 doesn't trap where the interpreter does, for instance on division by
+        // zero.
+        if !interpreter
+            .get_test_outputs(spec.get_binary_with_instrs(&instrs))
+            .is_empty()
+        {
+            if holes {
+                if let Some(filled) =
+                    fill_holes(&mut stats, worker, z3_solver, interpreter, spec, &instrs)
+                {
+                    break Some(Candidate::from_instrs(filled));
+                }
+            }
+            search.reject();
+            continue;
+        }
+        match stats.verify(|| z3_solver.verify(&instrs)) {
+            solver::VerifyResult::Verified => break Some(Candidate::from_instrs(instrs)),
+            solver::VerifyResult::CounterExample(values) => {
+                interpreter.add_test_case(values.clone());
+                worker.share_counterexample(values);
+            }
+        }
+    };
+
+    stats.num_test_cases = interpreter.num_test_cases();
+    reporter.summarize(&stats);
+    search.finish(&name);
+    if let Some(checkpointer) = checkpointer {
+        let state = State {
+            found: found.clone(),
+            search: search.state(),
+        };
+        checkpointer.save(&Checkpoint::new(worker, interpreter, stats, state));
+    }
+    found
+}
+
+/// Solves for the constants of given instructions against the test cases, and verifies the
+/// candidate they fill in. Counterexamples are added to the test cases, and the constants solved
+/// for again, until the filled in candidate is verified or no constants pass the test cases.
+/// Returns the verified candidate, if any.
+pub fn fill_holes(
+    stats: &mut SearchStats,
+    worker: &Worker,
+    z3_solver: &solver::Z3Solver,
+    interpreter: &mut dyn exec::Interpreter,
+    spec: &mut Spec,
+    instrs: &[Instruction],
+) -> Option<Vec<Instruction>> {
+    let holes: Vec<usize> = instrs
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, instr)| match instr {
+            Instruction::I32Const(_) => Some(idx),
+            _ => None,
+        })
+        .collect();
+    if holes.is_empty() {
+        return None;
+    }
+
+    loop {
+        let constants =
+            z3_solver.solve_constants(instrs, &holes, &exec::i32_test_cases(interpreter))?;
+        let mut filled = instrs.to_vec();
+        for (&idx, constant) in holes.iter().zip(constants) {
+            filled[idx] = Instruction::I32Const(constant);
+        }
+        // Z3 doesn't trap where the interpreter does, for instance on division by zero.
+        if !interpreter
+            .get_test_outputs(spec.get_binary_with_instrs(&filled))
+            .is_empty()
+        {
+            return None;
+        }
+        match stats.verify(|| z3_solver.verify(&filled)) {
+            solver::VerifyResult::Verified => return Some(filled),
+            solver::VerifyResult::CounterExample(values) => {
+                interpreter.add_test_case(values.clone());
+                worker.share_counterexample(values);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perf::LatencyTable;
+    use crate::{parity_wasm_utils, search_worker, worker, Mode};
+    use parity_wasm::elements::Module;
+    use structopt::StructOpt;
+
+    /// Runs the synthesis step of a single worker with given command line arguments on the
+    /// function named `func_name` in given module, and returns the candidate it found, if any.
+    fn synthesize(wat: &str, func_name: &'static str, args: &[&str]) -> Option<Candidate> {
+        let spec_binary = wat::parse_str(wat).unwrap();
+        let options =
+            SuperoptimizerOpts::from_iter(["rocinante", "spec.wat"].iter().chain(args.iter()));
+        let candidates = worker::run(
+            &[0],
+            chrono::Duration::minutes(1),
+            Mode::Synthesis,
+            move |worker| {
+                let module = Module::from_bytes(&spec_binary).unwrap();
+                let (func_type, func_body) = parity_wasm_utils::func_by_name(&module, func_name);
+                search_worker(
+                    worker,
+                    &spec_binary,
+                    func_name,
+                    func_type,
+                    func_body,
+                    &[],
+                    &options,
+                    Mode::Synthesis,
+                    None,
+                    &LatencyTable::default(),
+                )
+            },
+        );
+        candidates.into_iter().next()
+    }
+
+    #[test]
+    fn wasmtime_test() {
+        let candidate = synthesize(
+            r#"(module
+                 (func (export "sub") (param i32 i32) (result i32)
+                   local.get 0
+                   local.get 1
+                   i32.sub))"#,
+            "sub",
+            &["--interpreter-kind", "Wasmtime", "cegis"],
+        )
+        .unwrap();
+        assert_eq!(
+            candidate.instrs(),
+            &[
+                Instruction::GetLocal(0),
+                Instruction::GetLocal(1),
+                Instruction::I32Sub
+            ]
+        );
+    }
+
+    #[test]
+    fn wasmtime_holes_test() {
+        let candidate = synthesize(
+            r#"(module
+                 (func (export "add7") (param i32) (result i32)
+                   local.get 0
+                   i32.const 7
+                   i32.add))"#,
+            "add7",
+            &[
+                "--interpreter-kind",
+                "Wasmtime",
+                "enumerative",
+                "--strategy",
+                "TopDown",
+                "--holes",
+            ],
+        )
+        .unwrap();
+        // 7 isn't among the default constants, so it was filled in.
+        assert_eq!(candidate.instrs().len(), 3);
+        assert!(candidate
+            .instrs()
+            .iter()
+            .any(|instr| matches!(instr, Instruction::I32Const(7) | Instruction::I32Const(-7))));
+    }
+}
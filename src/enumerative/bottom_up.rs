@@ -0,0 +1,665 @@
+use super::{prune, EnumerativeOpts};
+use crate::checkpoint::Checkpointer;
+use crate::driver::{self, Search, Step};
+use crate::stats::SearchStats;
+use crate::wasm::{eval_i32, pure_i32_arity, Candidate, Spec, Whitelist};
+use crate::worker::Worker;
+use crate::{exec, solver, SuperoptimizerOpts};
+use parity_wasm::elements::{Instruction, ValueType};
+use std::collections::HashMap;
+
+/// A program in the bank, an instruction applied to the values of smaller programs.
+struct Node {
+    instr: Instruction,
+    /// Nodes whose values are the operands of the instruction, only the first `arity` are used.
+    operands: [usize; 2],
+    arity: usize,
+    /// The number of instructions of the program.
+    size: usize,
+    class: usize,
+}
+
+/// Programs with the same outputs on all test cases.
+struct Class {
+    outputs: Vec<Option<i32>>,
+    /// The member larger programs are composed from.
+    rep: usize,
+    members: Vec<usize>,
+}
+
+/// An instruction and the representatives of its operands, and the latest epoch any of them
+/// became a representative in.
+struct Combo {
+    instr: Instruction,
+    operands: [usize; 2],
+    arity: usize,
+    epoch: usize,
+}
+
+/// Side effect free programs that push one i32 value, grouped into classes of observationally
+/// equivalent ones. Only the representative of each class is composed into larger programs.
+///
+/// Each counterexample starts a new epoch. Classes whose members disagree on it are split, and
+/// the representatives of the new classes are tagged with the epoch, so that the programs
+/// composed from them can be caught up with by passes restricted to that epoch.
+struct Bank {
+    leaves: Vec<Instruction>,
+    operators: Vec<Instruction>,
+    inputs: Vec<Vec<i32>>,
+    expected: Vec<Option<i32>>,
+    nodes: Vec<Node>,
+    classes: Vec<Class>,
+    class_by_outputs: HashMap<Vec<Option<i32>>, usize>,
+    /// Representatives of each size, along with the epoch they became representatives in.
+    reps: Vec<Vec<(usize, usize)>>,
+    epoch: usize,
+}
+
+impl Bank {
+    /// `local_types` are the types of the parameters followed by the ones of the locals, only the
+    /// i32 ones are leaves.
+    fn new<'a>(
+        instrs: impl IntoIterator<Item = &'a Instruction>,
+        local_types: &[ValueType],
+        inputs: Vec<Vec<i32>>,
+        expected: Vec<Option<i32>>,
+    ) -> Self {
+        let (leaves, operators) = instrs
+            .into_iter()
+            .filter(|instr| pure_i32_arity(instr).is_some())
+            .filter(|instr| match instr {
+                Instruction::GetLocal(idx) => local_types[*idx as usize] == ValueType::I32,
+                _ => true,
+            })
+            .cloned()
+            .partition(|instr| pure_i32_arity(instr) == Some(0));
+        Self {
+            leaves,
+            operators,
+            inputs,
+            expected,
+            nodes: Vec::new(),
+            classes: Vec::new(),
+            class_by_outputs: HashMap::new(),
+            reps: vec![Vec::new()],
+            epoch: 0,
+        }
+    }
+
+    /// Returns the pass over the programs of given size composed from representatives that
+    /// became ones between given epochs.
+    fn pass(&mut self, size: usize, min_epoch: usize, max_epoch: usize) -> Pass {
+        while self.reps.len() <= size {
+            self.reps.push(Vec::new());
+        }
+        Pass {
+            size,
+            min_epoch,
+            max_epoch,
+            op: 0,
+            first_size: 1,
+            i: 0,
+            j: 0,
+        }
+    }
+
+    /// Adds the program of given combo. Returns its node if it is the representative of a new
+    /// class, or `None` if it is equivalent to a program in the bank.
+    fn add(&mut self, combo: Combo) -> Option<usize> {
+        let size = 1 + combo.operands[..combo.arity]
+            .iter()
+            .map(|&operand| self.nodes[operand].size)
+            .sum::<usize>();
+        let mut node = Node {
+            instr: combo.instr,
+            operands: combo.operands,
+            arity: combo.arity,
+            size,
+            class: 0,
+        };
+
+        // The outputs of the operands are the ones of their classes.
+        let outputs: Vec<Option<i32>> = (0..self.inputs.len())
+            .map(|test| {
+                let mut operands = [None; 2];
+                for (value, &operand) in operands.iter_mut().zip(&node.operands[..node.arity]) {
+                    *value = self.classes[self.nodes[operand].class].outputs[test];
+                }
+                apply(&node.instr, &self.inputs[test], &operands[..node.arity])
+            })
+            .collect();
+
+        let idx = self.nodes.len();
+        if let Some(&class) = self.class_by_outputs.get(&outputs) {
+            node.class = class;
+            self.nodes.push(node);
+            self.classes[class].members.push(idx);
+            return None;
+        }
+
+        node.class = self.classes.len();
+        self.nodes.push(node);
+        self.class_by_outputs
+            .insert(outputs.clone(), self.classes.len());
+        self.classes.push(Class {
+            outputs,
+            rep: idx,
+            members: vec![idx],
+        });
+        self.reps[size].push((idx, combo.epoch));
+        Some(idx)
+    }
+
+    /// Adds a test case, and splits the classes whose members disagree on it. Returns the
+    /// representatives of the new classes.
+    fn refine(&mut self, input: Vec<i32>, expected: Option<i32>) -> Vec<usize> {
+        self.epoch += 1;
+        // Operands come before the nodes they are used by.
+        let mut values: Vec<Option<i32>> = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let mut operands = [None; 2];
+            for (value, &operand) in operands.iter_mut().zip(&node.operands[..node.arity]) {
+                *value = values[operand];
+            }
+            values.push(apply(&node.instr, &input, &operands[..node.arity]));
+        }
+        self.inputs.push(input);
+        self.expected.push(expected);
+
+        let mut new_reps = Vec::new();
+        // Classes split off in this loop already have their outputs on the new test case.
+        let num_classes = self.classes.len();
+        for class in 0..num_classes {
+            let rep_value = values[self.classes[class].rep];
+            let mut groups: Vec<(Option<i32>, Vec<usize>)> = Vec::new();
+            for &member in &self.classes[class].members {
+                match groups
+                    .iter_mut()
+                    .find(|(value, _)| *value == values[member])
+                {
+                    Some((_, group)) => group.push(member),
+                    None => groups.push((values[member], vec![member])),
+                }
+            }
+
+            for (value, members) in groups {
+                if value == rep_value {
+                    self.classes[class].outputs.push(value);
+                    self.classes[class].members = members;
+                    continue;
+                }
+                // The outputs of the class may already have the value of the new test case.
+                let mut outputs = self.classes[class].outputs[..self.inputs.len() - 1].to_vec();
+                outputs.push(value);
+                let rep = *members
+                    .iter()
+                    .min_by_key(|&&member| (self.nodes[member].size, member))
+                    .unwrap();
+                for &member in &members {
+                    self.nodes[member].class = self.classes.len();
+                }
+                self.reps[self.nodes[rep].size].push((rep, self.epoch));
+                self.classes.push(Class {
+                    outputs,
+                    rep,
+                    members,
+                });
+                new_reps.push(rep);
+            }
+        }
+
+        self.class_by_outputs = self
+            .classes
+            .iter()
+            .enumerate()
+            .map(|(idx, class)| (class.outputs.clone(), idx))
+            .collect();
+        new_reps
+    }
+
+    /// Returns whether given node has the same outputs as the spec on all test cases.
+    fn matches_spec(&self, node: usize) -> bool {
+        self.classes[self.nodes[node].class].outputs == self.expected
+    }
+
+    /// Returns up to the last `len` instructions of the program of given combo.
+    fn tail(&self, combo: &Combo, len: usize) -> Vec<Instruction> {
+        let mut tail = vec![combo.instr.clone()];
+        let mut operands = combo.operands[..combo.arity].to_vec();
+        // The last operand ends right before the instruction that uses it.
+        while let Some(operand) = operands.pop() {
+            if tail.len() == len {
+                break;
+            }
+            let node = &self.nodes[operand];
+            tail.push(node.instr.clone());
+            operands.extend_from_slice(&node.operands[..node.arity]);
+        }
+        tail.reverse();
+        tail
+    }
+
+    /// Returns the rule that prunes the program of given combo, if any.
+    fn pruned_by(&self, combo: &Combo) -> Option<prune::Rule> {
+        if combo.arity == 2
+            && !prune::canonical_operands(&combo.instr, combo.operands[0], combo.operands[1])
+        {
+            return Some(prune::Rule::CommutativeOrder);
+        }
+        // The instructions equivalent windows are replaced with are operators.
+        prune::tail_pruned_by(&self.tail(combo, prune::MAX_WINDOW), &self.operators)
+    }
+
+    fn instrs(&self, node: usize) -> Vec<Instruction> {
+        let node = &self.nodes[node];
+        let mut instrs: Vec<Instruction> = node.operands[..node.arity]
+            .iter()
+            .flat_map(|&operand| self.instrs(operand))
+            .collect();
+        instrs.push(node.instr.clone());
+        instrs
+    }
+}
+
+/// Returns the value of given leaf or operator on given input, with given operand values. Traps
+/// of operands propagate.
+fn apply(instr: &Instruction, input: &[i32], operands: &[Option<i32>]) -> Option<i32> {
+    match instr {
+        // Locals other than parameters start out zero.
+        Instruction::GetLocal(idx) => Some(input.get(*idx as usize).cloned().unwrap_or(0)),
+        Instruction::I32Const(c) => Some(*c),
+        _ => {
+            let mut values = [0; 2];
+            for (value, operand) in values.iter_mut().zip(operands) {
+                *value = (*operand)?;
+            }
+            eval_i32(instr, &values[..operands.len()])
+        }
+    }
+}
+
+/// A resumable iteration over the combos of one size, restricted to the ones whose latest
+/// operand became a representative between `min_epoch` and `max_epoch`.
+struct Pass {
+    size: usize,
+    min_epoch: usize,
+    max_epoch: usize,
+    op: usize,
+    /// The size of the first operand of binary operators.
+    first_size: usize,
+    i: usize,
+    j: usize,
+}
+
+impl Pass {
+    fn combo(
+        &self,
+        instr: &Instruction,
+        operands: [usize; 2],
+        arity: usize,
+        epoch: usize,
+    ) -> Option<Combo> {
+        if self.min_epoch <= epoch && epoch <= self.max_epoch {
+            Some(Combo {
+                instr: instr.clone(),
+                operands,
+                arity,
+                epoch,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn next(&mut self, bank: &Bank) -> Option<Combo> {
+        if self.size == 1 {
+            while let Some(leaf) = bank.leaves.get(self.op) {
+                self.op += 1;
+                if let Some(combo) = self.combo(leaf, [0; 2], 0, 0) {
+                    return Some(combo);
+                }
+            }
+            return None;
+        }
+
+        while let Some(instr) = bank.operators.get(self.op) {
+            if pure_i32_arity(instr) == Some(1) {
+                let reps = &bank.reps[self.size - 1];
+                while let Some(&(operand, epoch)) = reps.get(self.i) {
+                    self.i += 1;
+                    if let Some(combo) = self.combo(instr, [operand, 0], 1, epoch) {
+                        return Some(combo);
+                    }
+                }
+            } else {
+                while self.first_size + 1 < self.size {
+                    let first_reps = &bank.reps[self.first_size];
+                    let second_reps = &bank.reps[self.size - 1 - self.first_size];
+                    while let Some(&(first, first_epoch)) = first_reps.get(self.i) {
+                        while let Some(&(second, second_epoch)) = second_reps.get(self.j) {
+                            self.j += 1;
+                            let epoch = first_epoch.max(second_epoch);
+                            if let Some(combo) = self.combo(instr, [first, second], 2, epoch) {
+                                return Some(combo);
+                            }
+                        }
+                        self.i += 1;
+                        self.j = 0;
+                    }
+                    self.first_size += 1;
+                    self.i = 0;
+                }
+            }
+            self.op += 1;
+            self.first_size = 1;
+            self.i = 0;
+            self.j = 0;
+        }
+        None
+    }
+}
+
+/// Adds the test cases from given index on to the bank, and queues catch-up passes over the
+/// combos of the representatives it splits off, for each size up to `size`.
+fn refine(
+    bank: &mut Bank,
+    interpreter: &dyn exec::Interpreter,
+    from: usize,
+    size: usize,
+    passes: &mut Vec<Pass>,
+    pending: &mut Vec<usize>,
+) {
+    for idx in from..interpreter.num_test_cases() {
+        let (input, expected) = exec::i32_test_case(interpreter, idx);
+        pending.extend(bank.refine(input, expected));
+        let epoch = bank.epoch;
+        // Smaller sizes run first, their new representatives are operands of larger ones.
+        for size in (2..=size).rev() {
+            let pass = bank.pass(size, epoch, epoch);
+            passes.push(pass);
+        }
+    }
+}
+
+/// The state of a bottom-up search between [steps](Search::step).
+struct BottomUp {
+    pruning: bool,
+    max_length: usize,
+    bank: Bank,
+    passes: Vec<Pass>,
+    /// Representatives to check against the spec.
+    pending: Vec<usize>,
+    size: usize,
+    /// The number of test cases added to the bank.
+    num_refined: usize,
+    pruner: prune::Pruner,
+}
+
+impl Search for BottomUp {
+    /// The bank is rebuilt from the test cases on resume, which is cheap since programs are
+    /// evaluated without an interpreter.
+    type State = ();
+
+    fn state(&self) {}
+
+    fn restore(&mut self, _state: ()) {
+        log::info!("Bottom-up search restarts from the test cases of the checkpoint.");
+    }
+
+    fn step(&mut self, interpreter: &dyn exec::Interpreter, stats: &mut SearchStats) -> Step {
+        if self.num_refined < interpreter.num_test_cases() {
+            refine(
+                &mut self.bank,
+                interpreter,
+                self.num_refined,
+                self.size,
+                &mut self.passes,
+                &mut self.pending,
+            );
+            self.num_refined = interpreter.num_test_cases();
+        }
+
+        if let Some(node) = self.pending.pop() {
+            if !self.bank.matches_spec(node) {
+                return Step::Continue;
+            }
+            return Step::Check(self.bank.instrs(node));
+        }
+
+        let combo = match self.passes.last_mut() {
+            Some(pass) => match pass.next(&self.bank) {
+                Some(combo) => combo,
+                None => {
+                    self.passes.pop();
+                    return Step::Continue;
+                }
+            },
+            None if self.size < self.max_length => {
+                self.size += 1;
+                let pass = self.bank.pass(self.size, 0, self.bank.epoch);
+                self.passes.push(pass);
+                return Step::Continue;
+            }
+            None => return Step::Done,
+        };
+
+        stats.iterations += 1;
+        if self.pruning && self.pruner.record(self.bank.pruned_by(&combo)) {
+            return Step::Continue;
+        }
+        if let Some(node) = self.bank.add(combo) {
+            self.pending.push(node);
+        }
+        Step::Continue
+    }
+
+    fn finish(&self, name: &str) {
+        self.pruner.report(name);
+    }
+}
+
+/// Composes side effect free programs bottom-up, by increasing size, from the representatives
+/// of classes of observationally equivalent smaller ones, until one is verified. Instructions
+/// that set locals are not enumerated.
+pub fn search(
+    options: &SuperoptimizerOpts,
+    enumerative_options: &EnumerativeOpts,
+    worker: &mut Worker,
+    checkpointer: Option<&mut Checkpointer>,
+    z3_solver: &solver::Z3Solver,
+    interpreter: &mut dyn exec::Interpreter,
+    spec: &mut Spec,
+) -> Option<Candidate> {
+    let instr_whitelist = Whitelist::new(
+        worker.rng(),
+        spec.num_params(),
+        spec.num_locals(),
+        &options.constants,
+        &options.opcodes,
+    );
+    let local_types: Vec<ValueType> = spec
+        .param_types()
+        .iter()
+        .chain(spec.local_types())
+        .cloned()
+        .collect();
+    // The test cases are added on the first step.
+    let mut bottom_up = BottomUp {
+        pruning: enumerative_options.pruning,
+        max_length: spec.num_instrs(),
+        bank: Bank::new(instr_whitelist.iter(), &local_types, Vec::new(), Vec::new()),
+        passes: Vec::new(),
+        pending: Vec::new(),
+        size: 0,
+        num_refined: 0,
+        pruner: prune::Pruner::default(),
+    };
+    let name = format!("Bottom-up search worker {}", worker.id());
+    driver::drive(
+        &mut bottom_up,
+        name,
+        options,
+        worker,
+        checkpointer,
+        z3_solver,
+        interpreter,
+        spec,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs passes over all combos up to given size, and returns the representatives found.
+    fn enumerate(bank: &mut Bank, max_size: usize) -> Vec<usize> {
+        let mut reps = Vec::new();
+        for size in 1..=max_size {
+            let mut pass = bank.pass(size, 0, bank.epoch);
+            while let Some(combo) = pass.next(bank) {
+                reps.extend(bank.add(combo));
+            }
+        }
+        reps
+    }
+
+    #[test]
+    fn bank_test() {
+        let instrs = [
+            Instruction::GetLocal(0),
+            Instruction::GetLocal(1),
+            Instruction::I32Add,
+            Instruction::I32Sub,
+        ];
+        // x + y on two test cases.
+        let mut bank = Bank::new(
+            &instrs,
+            &[ValueType::I32; 2],
+            vec![vec![1, 2], vec![5, 0]],
+            vec![Some(3), Some(5)],
+        );
+
+        let reps = enumerate(&mut bank, 3);
+        // Only stack valid programs are composed: x, y, and the four additions and subtractions
+        // of them.
+        assert_eq!(bank.nodes.len(), 10);
+        // x + y and y + x are equivalent, as are x - x and y - y.
+        assert_eq!(reps.len(), 8);
+        let matches: Vec<_> = reps
+            .iter()
+            .filter(|&&node| bank.matches_spec(node))
+            .collect();
+        assert_eq!(matches.len(), 1);
+        let instrs = bank.instrs(*matches[0]);
+        assert_eq!(instrs.len(), 3);
+        assert_eq!(instrs[2], Instruction::I32Add);
+
+        // A test case where x - x and y - y still agree splits nothing.
+        assert!(bank.refine(vec![3, 3], Some(6)).is_empty());
+        // Size 5 programs compose size 3 representatives with leaves.
+        let mut pass = bank.pass(5, 0, bank.epoch);
+        let mut num_combos = 0;
+        while pass.next(&bank).is_some() {
+            num_combos += 1;
+        }
+        // 2 binary operators with size 3 and 1 operands in either order: 2 * (6 * 2 + 2 * 6).
+        assert_eq!(num_combos, 48);
+    }
+
+    #[test]
+    fn local_types_test() {
+        let instrs = [
+            Instruction::GetLocal(0),
+            Instruction::GetLocal(1),
+            Instruction::GetLocal(2),
+            Instruction::I32Eqz,
+        ];
+        // The i64 local is not a leaf, the i32 one starts out zero.
+        let mut bank = Bank::new(
+            &instrs,
+            &[ValueType::I32, ValueType::I64, ValueType::I32],
+            vec![vec![3]],
+            vec![Some(1)],
+        );
+        assert_eq!(
+            bank.leaves,
+            vec![Instruction::GetLocal(0), Instruction::GetLocal(2)]
+        );
+        let reps = enumerate(&mut bank, 2);
+        let matching: Vec<_> = reps
+            .iter()
+            .filter(|&&node| bank.matches_spec(node))
+            .map(|&node| bank.instrs(node))
+            .collect();
+        assert_eq!(
+            matching,
+            vec![vec![Instruction::GetLocal(2), Instruction::I32Eqz]]
+        );
+    }
+
+    #[test]
+    fn pruned_by_test() {
+        let instrs = [
+            Instruction::GetLocal(0),
+            Instruction::I32Const(0),
+            Instruction::I32Sub,
+            Instruction::I32Eqz,
+        ];
+        let mut bank = Bank::new(&instrs, &[ValueType::I32], vec![vec![1]], vec![Some(1)]);
+        // x and 0 are the first nodes, x - 0 is equivalent to x but still added.
+        assert_eq!(enumerate(&mut bank, 1).len(), 2);
+        let sub = Combo {
+            instr: Instruction::I32Sub,
+            operands: [0, 1],
+            arity: 2,
+            epoch: 0,
+        };
+        assert_eq!(bank.pruned_by(&sub), Some(prune::Rule::IdentityOp));
+        assert_eq!(bank.add(sub), None);
+        let combo = Combo {
+            instr: Instruction::I32Eqz,
+            operands: [2, 0],
+            arity: 1,
+            epoch: 0,
+        };
+        assert_eq!(bank.tail(&combo, 2), instrs[2..].to_vec());
+        assert_eq!(bank.tail(&combo, 8), instrs.to_vec());
+        // Eqz of a difference is an equality, once equality is whitelisted.
+        assert_eq!(bank.pruned_by(&combo), None);
+        bank.operators.push(Instruction::I32Eq);
+        assert_eq!(bank.pruned_by(&combo), Some(prune::Rule::Equivalence));
+    }
+
+    #[test]
+    fn refine_test() {
+        let instrs = [Instruction::GetLocal(0), Instruction::I32Const(0)];
+        // x and 0 agree on the only test case.
+        let mut bank = Bank::new(
+            &instrs,
+            &[ValueType::I32; 2],
+            vec![vec![0, 0]],
+            vec![Some(0)],
+        );
+        let reps = enumerate(&mut bank, 1);
+        assert_eq!(reps.len(), 1);
+        assert!(bank.matches_spec(reps[0]));
+
+        // Now they don't, the split off one is tagged with the new epoch.
+        let new_reps = bank.refine(vec![2, 0], Some(2));
+        assert_eq!(new_reps.len(), 1);
+        assert_eq!(bank.epoch, 1);
+        assert_eq!(bank.reps[1].len(), 2);
+        assert_eq!(bank.reps[1][1], (new_reps[0], 1));
+        assert_eq!(
+            bank.classes.len(),
+            bank.class_by_outputs.len(),
+            "Every class has distinct outputs."
+        );
+        let matching: Vec<_> = [reps[0], new_reps[0]]
+            .iter()
+            .filter(|&&node| bank.matches_spec(node))
+            .map(|&node| bank.instrs(node))
+            .collect();
+        assert_eq!(matching, vec![vec![Instruction::GetLocal(0)]]);
+    }
+}
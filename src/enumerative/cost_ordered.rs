@@ -0,0 +1,306 @@
+use super::{prune, EnumerativeOpts};
+use crate::checkpoint::Checkpointer;
+use crate::driver::{self, Search, Step};
+use crate::perf::PerfModel;
+use crate::stats::SearchStats;
+use crate::wasm::{Candidate, Spec, Whitelist};
+use crate::worker::Worker;
+use crate::{exec, solver, SuperoptimizerOpts};
+use parity_wasm::elements::Instruction;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// A prefix in the queue. Cheaper prefixes are greater, so that they are popped first from the
+/// max-heap, and so are shorter ones among equally expensive prefixes. Entries compare by these
+/// keys only, not by their instructions.
+struct QueueEntry(Candidate);
+
+impl QueueEntry {
+    fn key(&self) -> (u32, usize) {
+        (self.0.cost(), self.0.next_index())
+    }
+}
+
+impl Ord for QueueEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key()).reverse()
+    }
+}
+
+impl PartialOrd for QueueEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueueEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for QueueEntry {}
+
+/// The state of a cost-ordered search kept in a [Checkpoint](crate::checkpoint::Checkpoint).
+#[derive(Serialize, Deserialize)]
+struct SearchState {
+    /// The instructions appended to each prefix in the queue, without the Nops they are padded
+    /// with.
+    queue: Vec<Candidate>,
+    cost: u32,
+}
+
+/// The state of a cost-ordered search between [steps](Search::step).
+struct CostOrdered {
+    name: String,
+    pruning: bool,
+    holes: bool,
+    /// The length of the spec, which candidates are padded to.
+    length: usize,
+    instr_whitelist: Whitelist,
+    enumerated: Vec<Instruction>,
+    /// The latency of each enumerated instruction.
+    latencies: Vec<u32>,
+    queue: BinaryHeap<QueueEntry>,
+    /// The cost of the prefixes popped so far never decreases.
+    cost: u32,
+    pruner: prune::Pruner,
+}
+
+impl CostOrdered {
+    /// Returns the prefix with given instructions appended, along with their latencies.
+    fn prefix(&self, instrs: &[Instruction]) -> Candidate {
+        instrs
+            .iter()
+            .fold(Candidate::new(self.length), |prefix, instr| {
+                let latency = self
+                    .enumerated
+                    .iter()
+                    .position(|enumerated| enumerated == instr)
+                    .map(|idx| self.latencies[idx])
+                    .expect("Checkpoint has a prefix of instructions that aren't enumerated.");
+                prefix
+                    .try_append_with_cost(&self.instr_whitelist, instr.clone(), latency)
+                    .expect(
+                        "Checkpoint has a prefix that can't be appended to, should never happen.",
+                    )
+            })
+    }
+}
+
+impl Search for CostOrdered {
+    type State = SearchState;
+
+    fn state(&self) -> SearchState {
+        SearchState {
+            queue: self
+                .queue
+                .iter()
+                .map(|QueueEntry(prefix)| {
+                    Candidate::from_instrs(prefix.instrs()[..prefix.next_index()].to_vec())
+                })
+                .collect(),
+            cost: self.cost,
+        }
+    }
+
+    fn restore(&mut self, state: SearchState) {
+        self.queue = state
+            .queue
+            .iter()
+            .map(|prefix| QueueEntry(self.prefix(prefix.instrs())))
+            .collect();
+        self.cost = state.cost;
+    }
+
+    fn step(&mut self, _interpreter: &dyn exec::Interpreter, stats: &mut SearchStats) -> Step {
+        let prefix = match self.queue.pop() {
+            Some(QueueEntry(prefix)) => prefix,
+            None => return Step::Done,
+        };
+        stats.iterations += 1;
+        if prefix.cost() > self.cost {
+            self.cost = prefix.cost();
+            log::debug!("{}: extending prefixes of cost {}", self.name, self.cost);
+        }
+
+        for (instr, &latency) in self.enumerated.iter().zip(&self.latencies) {
+            if let Ok(candidate) =
+                prefix.try_append_with_cost(&self.instr_whitelist, instr.clone(), latency)
+            {
+                let instrs = &candidate.instrs()[..candidate.next_index()];
+                if self.pruning
+                    && self
+                        .pruner
+                        .record(prune::prefix_pruned_by(instrs, &self.enumerated))
+                {
+                    continue;
+                }
+                self.queue.push(QueueEntry(candidate));
+            }
+        }
+
+        // The prefix itself is a candidate, without the Nops it is padded with up to the length
+        // of the spec. Its prefixes were checked when they were appended to.
+        let instrs = &prefix.instrs()[..prefix.next_index()];
+        if prefix.num_values_on_stack() != 1
+            || (self.pruning && self.pruner.record(prune::complete_pruned_by(instrs)))
+        {
+            return Step::Continue;
+        }
+        if self.holes {
+            Step::Fill(instrs.to_vec())
+        } else {
+            Step::Check(instrs.to_vec())
+        }
+    }
+
+    fn finish(&self, name: &str) {
+        self.pruner.report(name);
+    }
+}
+
+/// Extends prefixes in order of their accumulated cost under the performance model, so that the
+/// first verified candidate is the cheapest one up to the length of the spec. Prefixes that can't
+/// be completed to a stack valid candidate are dropped as soon as they are appended to.
+#[allow(clippy::too_many_arguments)]
+pub fn search(
+    options: &SuperoptimizerOpts,
+    enumerative_options: &EnumerativeOpts,
+    perf_model: &dyn PerfModel,
+    worker: &mut Worker,
+    checkpointer: Option<&mut Checkpointer>,
+    z3_solver: &solver::Z3Solver,
+    interpreter: &mut dyn exec::Interpreter,
+    spec: &mut Spec,
+) -> Option<Candidate> {
+    let instr_whitelist = Whitelist::new(
+        worker.rng(),
+        spec.num_params(),
+        spec.num_locals(),
+        &options.constants,
+        &options.opcodes,
+    );
+    let enumerated = super::enumerated_instrs(&instr_whitelist, enumerative_options.holes);
+    let latencies: Vec<u32> = enumerated
+        .iter()
+        .map(|instr| perf_model.cost(std::slice::from_ref(instr)))
+        .collect();
+
+    let pruning = enumerative_options.pruning
+        && {
+            let preserves_cost = prune::preserves_cost(perf_model);
+            if !preserves_cost {
+                log::warn!("Pruning is disabled, its rules don't keep the cheapest candidates under the latencies.");
+            }
+            preserves_cost
+        };
+
+    let name = format!("Cost-ordered search worker {}", worker.id());
+    let mut queue = BinaryHeap::new();
+    queue.push(QueueEntry(Candidate::new(spec.num_instrs())));
+    let mut cost_ordered = CostOrdered {
+        name: name.clone(),
+        pruning,
+        holes: enumerative_options.holes,
+        length: spec.num_instrs(),
+        instr_whitelist,
+        enumerated,
+        latencies,
+        queue,
+        cost: 0,
+        pruner: prune::Pruner::default(),
+    };
+    driver::drive(
+        &mut cost_ordered,
+        name,
+        options,
+        worker,
+        checkpointer,
+        z3_solver,
+        interpreter,
+        spec,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_order_test() {
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 2, 0, &[], &[]);
+        let candidate = Candidate::new(3);
+        let append = |prefix: &Candidate, instr, cost| {
+            prefix
+                .try_append_with_cost(&instr_whitelist, instr, cost)
+                .unwrap()
+        };
+        let cheap = append(&candidate, Instruction::GetLocal(0), 1);
+        let also_cheap = append(&candidate, Instruction::GetLocal(1), 1);
+        let expensive = append(&candidate, Instruction::I32Const(0), 2);
+        let longer = append(&cheap, Instruction::GetLocal(1), 0);
+        assert_eq!(longer.cost(), 1);
+
+        let mut queue = BinaryHeap::new();
+        for prefix in &[&expensive, &longer, &cheap, &also_cheap] {
+            queue.push(QueueEntry((*prefix).clone()));
+        }
+        let popped: Vec<Candidate> =
+            std::iter::from_fn(|| queue.pop().map(|entry| entry.0)).collect();
+        // Prefixes with the same cost and length come out in any order, but none is lost.
+        assert!(
+            popped[..2] == [cheap.clone(), also_cheap.clone()]
+                || popped[..2] == [also_cheap, cheap]
+        );
+        assert_eq!(popped[2..], [longer, expensive]);
+    }
+
+    #[test]
+    fn state_test() {
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 2, 0, &[0], &[]);
+        let enumerated: Vec<Instruction> = instr_whitelist.iter().cloned().collect();
+        let latencies = enumerated.iter().map(|_| 1).collect();
+        let cost_ordered = || CostOrdered {
+            name: String::from("Cost-ordered search"),
+            pruning: true,
+            holes: false,
+            length: 3,
+            instr_whitelist: instr_whitelist.clone(),
+            enumerated: enumerated.clone(),
+            latencies: latencies.clone(),
+            queue: BinaryHeap::new(),
+            cost: 0,
+            pruner: prune::Pruner::default(),
+        };
+
+        let mut saved = cost_ordered();
+        saved.cost = 1;
+        for instrs in &[
+            vec![Instruction::GetLocal(0)],
+            vec![Instruction::GetLocal(0), Instruction::I32Const(0)],
+        ] {
+            let prefix = saved.prefix(instrs);
+            saved.queue.push(QueueEntry(prefix));
+        }
+
+        // The queue comes back with the costs of its prefixes.
+        let mut restored = cost_ordered();
+        restored.restore(saved.state());
+        assert_eq!(restored.cost, 1);
+        let pop_all = |queue: BinaryHeap<QueueEntry>| -> Vec<Candidate> {
+            queue
+                .into_sorted_vec()
+                .into_iter()
+                .map(|entry| entry.0)
+                .collect()
+        };
+        let popped = pop_all(restored.queue);
+        assert_eq!(popped, pop_all(saved.queue));
+        assert_eq!(
+            popped.iter().map(Candidate::cost).collect::<Vec<u32>>(),
+            vec![2, 1]
+        );
+    }
+}
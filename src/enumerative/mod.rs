@@ -1,16 +1,218 @@
+use crate::checkpoint::{Checkpoint, Checkpointer};
+use crate::driver;
+use crate::perf::PerfModel;
+use crate::stats::{Reporter, SearchStats};
+use crate::worker::Worker;
 use crate::SuperoptimizerOpts;
 use crate::{exec, solver, wasm};
+use clap::arg_enum;
 use itertools::Itertools;
+use parity_wasm::elements::Instruction;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use structopt::StructOpt;
 
+pub mod bottom_up;
+pub mod cost_ordered;
+pub mod prune;
+pub mod top_down;
+
+arg_enum! {
+    /// How candidates are enumerated.
+    ///
+    /// - `Exhaustive`: every sequence of whitelisted instructions by increasing length, including
+    ///   the ones that set locals.
+    /// - `BottomUp`: side effect free programs composed from smaller ones, one per class of
+    ///   programs with the same test case outputs.
+    /// - `TopDown`: every sequence of whitelisted instructions by increasing length, extending
+    ///   prefixes that keep the stack valid. Workers split the prefixes among them, and each one
+    ///   moves on to the next length on its own, so with several workers the first verified
+    ///   candidate isn't necessarily the shortest.
+    /// - `CostOrdered`: every sequence of whitelisted instructions by increasing cost under the
+    ///   latency model, so that the first verified one is the cheapest.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Strategy {
+        Exhaustive,
+        BottomUp,
+        TopDown,
+        CostOrdered,
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(
+    name = "enumerative_opts",
+    about = "Enumerative search specific options."
+)]
+pub struct EnumerativeOpts {
+    #[structopt(
+        long,
+        help="How candidates are enumerated.",
+        possible_values=&Strategy::variants(),
+        default_value="Exhaustive")]
+    pub strategy: Strategy,
+
+    #[structopt(
+        long = "no-pruning",
+        help = "Enumerates candidates that pruning rules show to be equivalent to others.",
+        parse(from_flag = std::ops::Not::not)
+    )]
+    pub pruning: bool,
+
+    #[structopt(
+        long,
+        help = "Enumerates a single constant in place of the whitelisted ones. For candidates that fail the test cases, solves its values with Z3 and adds the counterexamples to the test cases until the values are verified. For functions of i32 values only, not supported by the BottomUp strategy."
+    )]
+    pub holes: bool,
+}
+
+/// The constant that stands for every constant when enumerating with holes. It isn't the identity
+/// of any operator nor part of a known equivalence, so pruning treats it as an arbitrary constant.
+const HOLE: i32 = 0x5555_5555;
+
+/// Returns the instructions candidates are enumerated from, in the order of the whitelist. With
+/// holes, a single constant replaces the whitelisted ones and
+/// [fill_holes](driver::fill_holes) solves its values.
+fn enumerated_instrs(whitelist: &wasm::Whitelist, holes: bool) -> Vec<Instruction> {
+    if !holes {
+        return whitelist.iter().cloned().collect();
+    }
+    let mut instrs = Vec::new();
+    let mut has_hole = false;
+    for instr in whitelist.iter() {
+        match instr {
+            Instruction::I32Const(_) if has_hole => {}
+            Instruction::I32Const(_) => {
+                instrs.push(Instruction::I32Const(HOLE));
+                has_hole = true;
+            }
+            _ => instrs.push(instr.clone()),
+        }
+    }
+    instrs
+}
+
+/// Returns the test outputs of each seen candidate.
+fn eval_seen_candidates(
+    interpreter: &dyn exec::Interpreter,
+    spec: &mut wasm::Spec,
+    seen_candidates: &[Vec<parity_wasm::elements::Instruction>],
+) -> Vec<Vec<exec::TestOutput>> {
+    seen_candidates
+        .iter()
+        .map(|seen_candidate| {
+            interpreter.get_test_outputs(spec.get_binary_with_instrs(seen_candidate))
+        })
+        .collect()
+}
+
+/// The state of an enumerative search kept in a [Checkpoint](Checkpoint).
+#[derive(Clone, Serialize, Deserialize)]
+struct SearchState {
+    /// The whitelist in the order its instructions are enumerated in, which depends on the seed.
+    whitelist: wasm::Whitelist,
+    /// The length and the index among the candidates of that length to continue from.
+    length: usize,
+    position: usize,
+    seen_candidates: Vec<wasm::Candidate>,
+    /// The verified candidate, if the search found one.
+    found: Option<wasm::Candidate>,
+}
+
+impl SearchState {
+    fn new(
+        whitelist: &wasm::Whitelist,
+        length: usize,
+        position: usize,
+        seen_candidates: &[Vec<Instruction>],
+        found: Option<&wasm::Candidate>,
+    ) -> Self {
+        Self {
+            whitelist: whitelist.clone(),
+            length,
+            position,
+            seen_candidates: seen_candidates
+                .iter()
+                .map(|instrs| wasm::Candidate::from_instrs(instrs.clone()))
+                .collect(),
+            found: found.cloned(),
+        }
+    }
+}
+
+/// Enumerates candidates with the strategy of given options until one is verified. The search
+/// continues from the checkpoint `checkpointer` loads if there is one, and saves checkpoints
+/// periodically and when it ends.
+#[allow(clippy::too_many_arguments)]
 pub fn search(
     options: &SuperoptimizerOpts,
-    rx: &std::sync::mpsc::Receiver<()>,
+    enumerative_options: &EnumerativeOpts,
+    perf_model: &dyn PerfModel,
+    worker: &mut Worker,
+    checkpointer: Option<&mut Checkpointer>,
     z3_solver: &solver::Z3Solver,
     interpreter: &mut dyn exec::Interpreter,
     spec: &mut wasm::Spec,
 ) -> Option<wasm::Candidate> {
-    let instr_whitelist =
-        wasm::Whitelist::new(spec.num_params(), spec.num_locals(), &options.constants);
+    match enumerative_options.strategy {
+        Strategy::Exhaustive => exhaustive_search(
+            options,
+            enumerative_options,
+            worker,
+            checkpointer,
+            z3_solver,
+            interpreter,
+            spec,
+        ),
+        Strategy::BottomUp => bottom_up::search(
+            options,
+            enumerative_options,
+            worker,
+            checkpointer,
+            z3_solver,
+            interpreter,
+            spec,
+        ),
+        Strategy::TopDown => top_down::search(
+            options,
+            enumerative_options,
+            worker,
+            checkpointer,
+            z3_solver,
+            interpreter,
+            spec,
+        ),
+        Strategy::CostOrdered => cost_ordered::search(
+            options,
+            enumerative_options,
+            perf_model,
+            worker,
+            checkpointer,
+            z3_solver,
+            interpreter,
+            spec,
+        ),
+    }
+}
+
+/// Enumerates candidates by increasing length until one is verified, skipping the ones the
+/// [prune](prune) rules show to be equivalent to another candidate.
+fn exhaustive_search(
+    options: &SuperoptimizerOpts,
+    enumerative_options: &EnumerativeOpts,
+    worker: &mut Worker,
+    mut checkpointer: Option<&mut Checkpointer>,
+    z3_solver: &solver::Z3Solver,
+    interpreter: &mut dyn exec::Interpreter,
+    spec: &mut wasm::Spec,
+) -> Option<wasm::Candidate> {
+    let mut instr_whitelist = wasm::Whitelist::new(
+        worker.rng(),
+        spec.num_params(),
+        spec.num_locals(),
+        &options.constants,
+        &options.opcodes,
+    );
 
     let max_length = spec.num_instrs();
 
@@ -20,8 +222,37 @@ pub fn search(
     let mut seen_candidates: Vec<Vec<parity_wasm::elements::Instruction>> = Vec::new();
     let mut seen_states: Vec<_> = Vec::new();
 
+    let mut stats = SearchStats::default();
+    let name = format!("Enumerative search worker {}", worker.id());
+    let mut reporter = Reporter::new(name.clone(), Duration::from_secs(options.progress_interval));
+    let mut pruner = prune::Pruner::default();
+
+    let mut start_length = 1;
+    let mut start_position = 0;
+    if let Some(checkpoint) = checkpointer
+        .as_ref()
+        .and_then(|checkpointer| checkpointer.load::<SearchState>())
+    {
+        let (resumed_stats, state) = checkpoint.restore(worker, interpreter);
+        stats = resumed_stats;
+        if state.found.is_some() {
+            reporter.summarize(&stats);
+            return state.found;
+        }
+        instr_whitelist = state.whitelist;
+        start_length = state.length;
+        start_position = state.position;
+        seen_candidates = state
+            .seen_candidates
+            .iter()
+            .map(|candidate| candidate.instrs().to_vec())
+            .collect();
+        seen_states = eval_seen_candidates(interpreter, spec, &seen_candidates);
+    }
+
+    let enumerated = enumerated_instrs(&instr_whitelist, enumerative_options.holes);
     // Enumerates programs of length i to max_length
-    for i in 1..=max_length {
+    for i in start_length..=max_length {
         // Creates a multi cartesian product of iterators over the whitelisted instructions.
         // For example, if we're given [1, 2, 3], then there are 9 length 2 candidates as following
         // [1, 1], [1, 2], [1, 3].
@@ -30,39 +261,111 @@ pub fn search(
         // Iterators are cheap to copy and each refer to an element in the vector it was created
         // from.
         let iter = (0..i)
-            .map(|_| instr_whitelist.iter())
-            .multi_cartesian_product();
+            .map(|_| enumerated.iter())
+            .multi_cartesian_product()
+            .enumerate()
+            .skip(if i == start_length { start_position } else { 0 });
 
-        for candidate in iter {
-            if rx.try_recv().is_ok() {
-                println!("Enumerative search timed out.");
-                return None;
+        for (position, candidate) in iter {
+            match worker.sync(interpreter) {
+                None => {
+                    log::debug!("Enumerative search stopped.");
+                    reporter.summarize(&stats);
+                    pruner.report(&name);
+                    if let Some(checkpointer) = checkpointer {
+                        let state =
+                            SearchState::new(&instr_whitelist, i, position, &seen_candidates, None);
+                        checkpointer.save(&Checkpoint::new(worker, interpreter, stats, state));
+                    }
+                    return None;
+                }
+                Some(0) => {}
+                Some(_) => {
+                    seen_states = eval_seen_candidates(interpreter, spec, &seen_candidates);
+                }
             }
+            if let Some(checkpointer) = checkpointer.as_mut() {
+                if checkpointer.is_due() {
+                    let state =
+                        SearchState::new(&instr_whitelist, i, position, &seen_candidates, None);
+                    checkpointer.save(&Checkpoint::new(worker, interpreter, stats.clone(), state));
+                }
+            }
+            stats.iterations += 1;
+            stats.num_test_cases = interpreter.num_test_cases();
+            reporter.report(&stats);
 
             if let wasm::StackState::Valid = wasm::check_stack_state(&instr_whitelist, &candidate) {
+                if enumerative_options.pruning
+                    && pruner.record(prune::pruned_by(&candidate, &enumerated))
+                {
+                    continue;
+                }
                 // Explicitly copy the instruction list to keep track of them.
-                let instrs: Vec<parity_wasm::elements::Instruction> =
+                let mut instrs: Vec<parity_wasm::elements::Instruction> =
                     candidate.iter().map(|&item| item.clone()).collect();
                 // Get test outputs returns the output values that are different from the spec, so
                 // if this vector is empty, all test cases pass.
-                let test_outputs =
+                let mut test_outputs =
                     interpreter.get_test_outputs(spec.get_binary_with_instrs(&instrs));
+                let mut verified = false;
+                if !test_outputs.is_empty() && enumerative_options.holes {
+                    let num_test_cases = interpreter.num_test_cases();
+                    match driver::fill_holes(
+                        &mut stats,
+                        worker,
+                        z3_solver,
+                        interpreter,
+                        spec,
+                        &instrs,
+                    ) {
+                        Some(filled) => {
+                            instrs = filled;
+                            test_outputs.clear();
+                            verified = true;
+                        }
+                        // Filling the holes may have added counterexamples.
+                        None if interpreter.num_test_cases() > num_test_cases => {
+                            test_outputs =
+                                interpreter.get_test_outputs(spec.get_binary_with_instrs(&instrs));
+                            seen_states = eval_seen_candidates(interpreter, spec, &seen_candidates);
+                        }
+                        None => {}
+                    }
+                }
                 if test_outputs.is_empty() {
-                    match z3_solver.verify(&instrs) {
+                    let result = if verified {
+                        solver::VerifyResult::Verified
+                    } else {
+                        stats.verify(|| z3_solver.verify(&instrs))
+                    };
+                    match result {
                         solver::VerifyResult::Verified => {
-                            return Some(wasm::Candidate::from_instrs(instrs));
+                            let found = wasm::Candidate::from_instrs(instrs);
+                            reporter.summarize(&stats);
+                            pruner.report(&name);
+                            if let Some(checkpointer) = checkpointer {
+                                let state = SearchState::new(
+                                    &instr_whitelist,
+                                    i,
+                                    position,
+                                    &seen_candidates,
+                                    Some(&found),
+                                );
+                                checkpointer.save(&Checkpoint::new(
+                                    worker,
+                                    interpreter,
+                                    stats,
+                                    state,
+                                ));
+                            }
+                            return Some(found);
                         }
                         solver::VerifyResult::CounterExample(values) => {
-                            interpreter.add_test_case(values);
+                            interpreter.add_test_case(values.clone());
+                            worker.share_counterexample(values);
                             seen_candidates.push(instrs);
-                            seen_states = seen_candidates
-                                .iter()
-                                .map(|seen_candidate| {
-                                    interpreter.get_test_outputs(
-                                        spec.get_binary_with_instrs(seen_candidate),
-                                    )
-                                })
-                                .collect();
+                            seen_states = eval_seen_candidates(interpreter, spec, &seen_candidates);
                         }
                     }
                 } else {
@@ -82,5 +385,12 @@ pub fn search(
         }
     }
 
+    reporter.summarize(&stats);
+    pruner.report(&name);
+    if let Some(checkpointer) = checkpointer {
+        // Resuming from past the last length ends the search right away.
+        let state = SearchState::new(&instr_whitelist, max_length + 1, 0, &seen_candidates, None);
+        checkpointer.save(&Checkpoint::new(worker, interpreter, stats, state));
+    }
     None
 }
@@ -0,0 +1,519 @@
+use crate::perf::PerfModel;
+use crate::wasm::pure_i32_arity;
+use parity_wasm::elements::Instruction;
+use std::borrow::Borrow;
+
+/// Rules that prune candidates equivalent to another candidate that is enumerated anyway.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rule {
+    /// The operands of a commutative operator are not in canonical order.
+    CommutativeOrder,
+    /// A value is written to a local that is overwritten or never read afterwards, and computing
+    /// the value can't trap.
+    DeadStore,
+    /// An operator leaves its operand unchanged, e.g. `i32.const 0; i32.add`.
+    IdentityOp,
+    /// A window of instructions is equivalent to a shorter one in [EQUIVALENCES](EQUIVALENCES).
+    Equivalence,
+}
+
+pub const RULES: [Rule; 4] = [
+    Rule::CommutativeOrder,
+    Rule::DeadStore,
+    Rule::IdentityOp,
+    Rule::Equivalence,
+];
+
+/// The length of the longest window a rule looks at.
+pub const MAX_WINDOW: usize = 4;
+
+/// Operators and the constant second operand that leaves the first one unchanged.
+const IDENTITIES: [(Instruction, i32); 13] = [
+    (Instruction::I32Add, 0),
+    (Instruction::I32Sub, 0),
+    (Instruction::I32Mul, 1),
+    (Instruction::I32DivS, 1),
+    (Instruction::I32DivU, 1),
+    (Instruction::I32And, -1),
+    (Instruction::I32Or, 0),
+    (Instruction::I32Xor, 0),
+    (Instruction::I32Shl, 0),
+    (Instruction::I32ShrS, 0),
+    (Instruction::I32ShrU, 0),
+    (Instruction::I32Rotl, 0),
+    (Instruction::I32Rotr, 0),
+];
+
+/// Windows of instructions and the shorter windows they are equivalent to, each verified by z3
+/// in the tests.
+const EQUIVALENCES: [(&[Instruction], &[Instruction]); 17] = [
+    (
+        &[Instruction::I32Const(0), Instruction::I32Eq],
+        &[Instruction::I32Eqz],
+    ),
+    (
+        &[
+            Instruction::I32Const(0),
+            Instruction::I32Ne,
+            Instruction::I32Eqz,
+        ],
+        &[Instruction::I32Eqz],
+    ),
+    (
+        &[
+            Instruction::I32Eqz,
+            Instruction::I32Eqz,
+            Instruction::I32Eqz,
+        ],
+        &[Instruction::I32Eqz],
+    ),
+    (
+        &[
+            Instruction::I32Const(-1),
+            Instruction::I32Xor,
+            Instruction::I32Const(-1),
+            Instruction::I32Xor,
+        ],
+        &[],
+    ),
+    (
+        &[Instruction::I32Sub, Instruction::I32Eqz],
+        &[Instruction::I32Eq],
+    ),
+    (
+        &[Instruction::I32Xor, Instruction::I32Eqz],
+        &[Instruction::I32Eq],
+    ),
+    (
+        &[
+            Instruction::I32Xor,
+            Instruction::I32Const(0),
+            Instruction::I32Ne,
+        ],
+        &[Instruction::I32Ne],
+    ),
+    (
+        &[Instruction::I32Eq, Instruction::I32Eqz],
+        &[Instruction::I32Ne],
+    ),
+    (
+        &[Instruction::I32Ne, Instruction::I32Eqz],
+        &[Instruction::I32Eq],
+    ),
+    (
+        &[Instruction::I32LtS, Instruction::I32Eqz],
+        &[Instruction::I32GeS],
+    ),
+    (
+        &[Instruction::I32LtU, Instruction::I32Eqz],
+        &[Instruction::I32GeU],
+    ),
+    (
+        &[Instruction::I32GtS, Instruction::I32Eqz],
+        &[Instruction::I32LeS],
+    ),
+    (
+        &[Instruction::I32GtU, Instruction::I32Eqz],
+        &[Instruction::I32LeU],
+    ),
+    (
+        &[Instruction::I32LeS, Instruction::I32Eqz],
+        &[Instruction::I32GtS],
+    ),
+    (
+        &[Instruction::I32LeU, Instruction::I32Eqz],
+        &[Instruction::I32GtU],
+    ),
+    (
+        &[Instruction::I32GeS, Instruction::I32Eqz],
+        &[Instruction::I32LtS],
+    ),
+    (
+        &[Instruction::I32GeU, Instruction::I32Eqz],
+        &[Instruction::I32LtU],
+    ),
+];
+
+fn is_commutative(instr: &Instruction) -> bool {
+    match instr {
+        Instruction::I32Add
+        | Instruction::I32Mul
+        | Instruction::I32And
+        | Instruction::I32Or
+        | Instruction::I32Xor
+        | Instruction::I32Eq
+        | Instruction::I32Ne => true,
+        _ => false,
+    }
+}
+
+/// The order of instructions that push a value without popping any, locals before constants.
+fn leaf_key(instr: &Instruction) -> Option<(u8, i64)> {
+    match instr {
+        Instruction::GetLocal(idx) => Some((0, i64::from(*idx))),
+        Instruction::I32Const(c) => Some((1, i64::from(*c))),
+        _ => None,
+    }
+}
+
+/// Returns whether the operands of a binary operator, given by their order in the enumeration,
+/// are in canonical order.
+pub fn canonical_operands(instr: &Instruction, first: usize, second: usize) -> bool {
+    !is_commutative(instr) || first <= second
+}
+
+fn nth_last<I: Borrow<Instruction>>(instrs: &[I], n: usize) -> Option<&Instruction> {
+    instrs
+        .len()
+        .checked_sub(n + 1)
+        .map(|idx| instrs[idx].borrow())
+}
+
+/// Returns whether given instructions end with a commutative operator applied to two leaves
+/// that aren't in canonical order, locals before constants.
+fn leaves_out_of_order<I: Borrow<Instruction>>(instrs: &[I]) -> bool {
+    match (
+        nth_last(instrs, 0),
+        nth_last(instrs, 2).and_then(leaf_key),
+        nth_last(instrs, 1).and_then(leaf_key),
+    ) {
+        (Some(instr), Some(first), Some(second)) => is_commutative(instr) && first > second,
+        _ => false,
+    }
+}
+
+/// Returns the rule that prunes the candidate ending with given instructions, considering only
+/// the identities and equivalences of the windows that end at the last instruction. Operand
+/// order is up to the caller, and stores aren't considered since later instructions may read
+/// them. A window is only replaced with instructions among the `enumerated` ones, otherwise the
+/// equivalent candidate isn't enumerated.
+pub fn tail_pruned_by<I: Borrow<Instruction>>(
+    instrs: &[I],
+    enumerated: &[Instruction],
+) -> Option<Rule> {
+    let instr = instrs.last()?.borrow();
+    match (nth_last(instrs, 1), instr) {
+        (Some(Instruction::I32Const(c)), _)
+            if IDENTITIES
+                .iter()
+                .any(|(op, identity)| op == instr && identity == c) =>
+        {
+            return Some(Rule::IdentityOp);
+        }
+        (Some(Instruction::GetLocal(x)), Instruction::SetLocal(y)) if x == y => {
+            return Some(Rule::IdentityOp);
+        }
+        // Same as `tee_local x`.
+        (Some(Instruction::SetLocal(x)), Instruction::GetLocal(y))
+            if x == y && enumerated.contains(&Instruction::TeeLocal(*x)) =>
+        {
+            return Some(Rule::Equivalence);
+        }
+        _ => {}
+    }
+
+    let ends_with = |pattern: &[Instruction]| {
+        pattern.len() <= instrs.len()
+            && instrs[instrs.len() - pattern.len()..]
+                .iter()
+                .zip(pattern)
+                .all(|(instr, expected)| instr.borrow() == expected)
+    };
+    if EQUIVALENCES.iter().any(|(pattern, replacement)| {
+        ends_with(pattern) && replacement.iter().all(|instr| enumerated.contains(instr))
+    }) {
+        return Some(Rule::Equivalence);
+    }
+
+    None
+}
+
+/// Returns the rule that prunes the candidates extending given prefix, considering the windows
+/// that end at its last instruction. See [tail_pruned_by](tail_pruned_by) for `enumerated`.
+pub fn prefix_pruned_by<I: Borrow<Instruction>>(
+    instrs: &[I],
+    enumerated: &[Instruction],
+) -> Option<Rule> {
+    if leaves_out_of_order(instrs) {
+        Some(Rule::CommutativeOrder)
+    } else {
+        tail_pruned_by(instrs, enumerated)
+    }
+}
+
+/// Returns whether no rule replaces instructions with more expensive ones under given model, so
+/// that pruning keeps the cheapest of equivalent candidates.
+pub fn preserves_cost(perf_model: &dyn PerfModel) -> bool {
+    let tee = [Instruction::TeeLocal(0)];
+    let set_get = [Instruction::SetLocal(0), Instruction::GetLocal(0)];
+    perf_model.cost(&tee) <= perf_model.cost(&set_get)
+        && EQUIVALENCES
+            .iter()
+            .all(|(pattern, replacement)| perf_model.cost(replacement) <= perf_model.cost(pattern))
+}
+
+/// Returns whether given instruction traps on some operands.
+fn may_trap(instr: &Instruction) -> bool {
+    match instr {
+        Instruction::I32DivS
+        | Instruction::I32DivU
+        | Instruction::I32RemS
+        | Instruction::I32RemU => true,
+        _ => pure_i32_arity(instr).is_none(),
+    }
+}
+
+/// Returns whether the instructions that compute the value on top of the stack after given
+/// instructions can't trap. Instructions whose values are stored in between count as well.
+fn computed_without_traps<I: Borrow<Instruction>>(instrs: &[I]) -> bool {
+    let mut num_needed = 1;
+    for instr in instrs.iter().rev() {
+        let (pops, pushes) = match instr.borrow() {
+            Instruction::SetLocal(_) => (1, 0),
+            Instruction::TeeLocal(_) => (1, 1),
+            instr if may_trap(instr) => return false,
+            instr => (pure_i32_arity(instr).unwrap(), 1),
+        };
+        num_needed = num_needed + pops - pushes;
+        if num_needed == 0 {
+            return true;
+        }
+    }
+    false
+}
+
+/// Returns whether given instructions write a local that is overwritten or never read after.
+/// Locals are dead at the end of the function, so a store to a local that isn't read later only
+/// drops a value, unless computing the value traps.
+fn has_dead_store<I: Borrow<Instruction>>(instrs: &[I]) -> bool {
+    instrs.iter().enumerate().any(|(pos, instr)| {
+        let local = match instr.borrow() {
+            Instruction::SetLocal(idx) if computed_without_traps(&instrs[..pos]) => *idx,
+            Instruction::TeeLocal(idx) => *idx,
+            _ => return false,
+        };
+        let is_read = instrs[pos + 1..]
+            .iter()
+            .find_map(|next| match next.borrow() {
+                Instruction::GetLocal(idx) if *idx == local => Some(true),
+                Instruction::SetLocal(idx) | Instruction::TeeLocal(idx) if *idx == local => {
+                    Some(false)
+                }
+                _ => None,
+            })
+            .unwrap_or(false);
+        !is_read
+    })
+}
+
+/// Returns the rule that prunes given complete candidate, considering only the rules that don't
+/// apply to prefixes. Use it for candidates whose prefixes were all checked with
+/// [prefix_pruned_by](prefix_pruned_by).
+pub fn complete_pruned_by<I: Borrow<Instruction>>(instrs: &[I]) -> Option<Rule> {
+    if has_dead_store(instrs) {
+        Some(Rule::DeadStore)
+    } else {
+        None
+    }
+}
+
+/// Returns the first rule that prunes given complete candidate, where the operands of
+/// commutative operators are only compared if they are leaves. See
+/// [tail_pruned_by](tail_pruned_by) for `enumerated`.
+pub fn pruned_by<I: Borrow<Instruction>>(instrs: &[I], enumerated: &[Instruction]) -> Option<Rule> {
+    (1..=instrs.len())
+        .find_map(|end| prefix_pruned_by(&instrs[..end], enumerated))
+        .or_else(|| complete_pruned_by(instrs))
+}
+
+/// Counts how many candidates each rule pruned.
+#[derive(Debug, Default, Clone)]
+pub struct Pruner {
+    num_checked: u64,
+    num_pruned: [u64; 4],
+}
+
+impl Pruner {
+    /// Records the result of checking a candidate, and returns whether it is pruned.
+    pub fn record(&mut self, rule: Option<Rule>) -> bool {
+        self.num_checked += 1;
+        match rule {
+            Some(rule) => {
+                let idx = RULES.iter().position(|r| *r == rule).unwrap();
+                self.num_pruned[idx] += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Logs the number and the share of checked candidates each rule pruned.
+    pub fn report(&self, name: &str) {
+        log::info!("{} pruned of {} candidates:", name, self.num_checked);
+        for (rule, num_pruned) in RULES.iter().zip(&self.num_pruned) {
+            log::info!(
+                "  {:?}: {} ({:.1}%)",
+                rule,
+                num_pruned,
+                100.0 * *num_pruned as f64 / self.num_checked.max(1) as f64
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::{VerifyResult, Z3Solver};
+    use crate::wasm::Whitelist;
+    use parity_wasm::elements::{FuncBody, FunctionType, Instructions, ValueType};
+
+    /// Returns the number of values given pure instructions pop from below the ones they push.
+    fn num_inputs(instrs: &[Instruction]) -> usize {
+        let mut depth: i32 = 0;
+        let mut num_inputs = 0;
+        for instr in instrs {
+            depth -= pure_i32_arity(instr).unwrap() as i32;
+            num_inputs = num_inputs.max(-depth);
+            depth += 1;
+        }
+        num_inputs as usize
+    }
+
+    #[test]
+    fn pruned_by_test() {
+        use Instruction::*;
+        let all: Vec<Instruction> = Whitelist::new(&mut rand::thread_rng(), 3, 0, &[-1, 0, 1], &[])
+            .iter()
+            .cloned()
+            .collect();
+
+        assert_eq!(
+            pruned_by(&[GetLocal(1), GetLocal(0), I32Add], &all),
+            Some(Rule::CommutativeOrder)
+        );
+        assert_eq!(pruned_by(&[GetLocal(1), GetLocal(0), I32Sub], &all), None);
+        assert_eq!(
+            pruned_by(&[I32Const(0), GetLocal(0), I32Add], &all),
+            Some(Rule::CommutativeOrder)
+        );
+        assert_eq!(
+            pruned_by(&[GetLocal(0), I32Const(0), I32Add], &all),
+            Some(Rule::IdentityOp)
+        );
+        assert_eq!(
+            pruned_by(&[GetLocal(0), I32Const(-1), I32And], &all),
+            Some(Rule::IdentityOp)
+        );
+        assert_eq!(pruned_by(&[GetLocal(0), I32Const(1), I32And], &all), None);
+        assert_eq!(
+            pruned_by(&[GetLocal(0), SetLocal(1), GetLocal(1)], &all),
+            Some(Rule::Equivalence)
+        );
+        assert_eq!(
+            pruned_by(&[GetLocal(0), TeeLocal(1), GetLocal(1), I32Mul], &all),
+            None
+        );
+        assert_eq!(
+            pruned_by(
+                &[GetLocal(0), TeeLocal(1), TeeLocal(1), GetLocal(1), I32Mul],
+                &all
+            ),
+            Some(Rule::DeadStore)
+        );
+        assert_eq!(
+            pruned_by(&[GetLocal(0), GetLocal(1), SetLocal(2)], &all),
+            Some(Rule::DeadStore)
+        );
+        // Dropping the store would drop the division and its trap too.
+        assert_eq!(
+            pruned_by(
+                &[GetLocal(0), GetLocal(0), GetLocal(1), I32DivS, SetLocal(2)],
+                &all
+            ),
+            None
+        );
+        assert_eq!(
+            pruned_by(
+                &[GetLocal(0), GetLocal(1), I32DivS, GetLocal(0), SetLocal(2)],
+                &all
+            ),
+            Some(Rule::DeadStore)
+        );
+        assert_eq!(
+            pruned_by(&[GetLocal(0), GetLocal(1), I32DivU, TeeLocal(2)], &all),
+            Some(Rule::DeadStore)
+        );
+        assert_eq!(
+            pruned_by(&[GetLocal(0), GetLocal(1), I32Sub, I32Eqz], &all),
+            Some(Rule::Equivalence)
+        );
+
+        // Windows are kept if their replacements aren't enumerated.
+        let restricted = [GetLocal(0), GetLocal(1), SetLocal(1), I32Sub, I32Eqz];
+        assert_eq!(
+            pruned_by(&[GetLocal(0), GetLocal(1), I32Sub, I32Eqz], &restricted),
+            None
+        );
+        assert_eq!(
+            pruned_by(&[GetLocal(0), SetLocal(1), GetLocal(1)], &restricted),
+            None
+        );
+
+        // Only windows ending at the last instruction are considered.
+        assert_eq!(
+            tail_pruned_by(&[GetLocal(0), I32Const(0), I32Add, I32Eqz], &all),
+            None
+        );
+        assert!(canonical_operands(&I32Sub, 3, 2));
+        assert!(!canonical_operands(&I32Add, 3, 2));
+
+        assert!(preserves_cost(&crate::perf::LatencyTable::default()));
+        assert!(!preserves_cost(&crate::perf::LatencyTable::parse(
+            "i32.eq 9"
+        )));
+
+        let mut pruner = Pruner::default();
+        assert!(!pruner.record(None));
+        assert!(pruner.record(Some(Rule::IdentityOp)));
+        assert_eq!(pruner.num_checked, 2);
+        assert_eq!(pruner.num_pruned, [0, 0, 1, 0]);
+    }
+
+    /// Verifies that `get_local 0 .. get_local n; pattern` is equivalent to the same locals
+    /// followed by `replacement`.
+    fn verify(pattern: &[Instruction], replacement: &[Instruction]) {
+        assert!(replacement.len() < pattern.len());
+        let num_inputs = num_inputs(pattern);
+        let func_type = FunctionType::new(vec![ValueType::I32; num_inputs], Some(ValueType::I32));
+        let inputs: Vec<Instruction> = (0..num_inputs as u32).map(Instruction::GetLocal).collect();
+
+        let mut spec = inputs.clone();
+        spec.extend_from_slice(pattern);
+        spec.push(Instruction::End);
+        let spec = FuncBody::new(vec![], Instructions::new(spec));
+        let mut candidate = inputs;
+        candidate.extend_from_slice(replacement);
+
+        let cfg = z3::Config::new();
+        let ctx = z3::Context::new(&cfg);
+        let solver = Z3Solver::new(&ctx, &func_type, &spec);
+        assert_eq!(
+            solver.verify(&candidate),
+            VerifyResult::Verified,
+            "{:?} is not equivalent to {:?}",
+            pattern,
+            replacement
+        );
+    }
+
+    #[test]
+    fn equivalences_test() {
+        for (op, identity) in IDENTITIES.iter() {
+            verify(&[Instruction::I32Const(*identity), op.clone()], &[]);
+        }
+        for (pattern, replacement) in EQUIVALENCES.iter() {
+            verify(pattern, replacement);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use super::{prune, EnumerativeOpts};
+use crate::checkpoint::Checkpointer;
+use crate::driver::{self, Search, Step};
+use crate::parity_wasm_utils::instrs_to_bytes;
+use crate::stats::SearchStats;
+use crate::wasm::{AppendError, Candidate, Spec, Whitelist};
+use crate::worker::Worker;
+use crate::{exec, solver, SuperoptimizerOpts};
+use parity_wasm::elements::Instruction;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// The length of the prefixes split among workers.
+const SPLIT_LENGTH: usize = 2;
+
+/// Returns whether the worker with given id enumerates the candidates starting with given
+/// instructions, up to [SPLIT_LENGTH](SPLIT_LENGTH) of them. Prefixes are assigned by their hash
+/// rather than their position, since each worker enumerates the whitelist in its own order.
+fn owns(worker_id: usize, num_workers: usize, instrs: &[Instruction]) -> bool {
+    if num_workers == 1 {
+        return true;
+    }
+    let mut hasher = DefaultHasher::new();
+    instrs_to_bytes(&instrs[..instrs.len().min(SPLIT_LENGTH)]).hash(&mut hasher);
+    hasher.finish() % num_workers as u64 == worker_id as u64
+}
+
+/// The state of a top-down search kept in a [Checkpoint](crate::checkpoint::Checkpoint).
+#[derive(Serialize, Deserialize)]
+struct SearchState {
+    /// The length of the candidates being enumerated.
+    length: usize,
+    /// The instructions appended to each prefix left to extend, without the Nops they are padded
+    /// with.
+    prefixes: Vec<Candidate>,
+}
+
+/// The state of a top-down search between [steps](Search::step).
+struct TopDown {
+    pruning: bool,
+    holes: bool,
+    worker_id: usize,
+    num_workers: usize,
+    instr_whitelist: Whitelist,
+    enumerated: Vec<Instruction>,
+    max_length: usize,
+    /// The length of the candidates being enumerated.
+    length: usize,
+    prefixes: Vec<Candidate>,
+    pruner: prune::Pruner,
+    num_underflows: u64,
+    num_overflows: u64,
+}
+
+impl TopDown {
+    /// Returns the prefix of a candidate of the current length with given instructions appended.
+    fn prefix(&self, instrs: &[Instruction]) -> Candidate {
+        instrs
+            .iter()
+            .fold(Candidate::new(self.length), |prefix, instr| {
+                prefix
+                    .try_append(&self.instr_whitelist, instr.clone())
+                    .expect(
+                        "Checkpoint has a prefix that can't be appended to, should never happen.",
+                    )
+            })
+    }
+}
+
+impl Search for TopDown {
+    type State = SearchState;
+
+    fn state(&self) -> SearchState {
+        SearchState {
+            length: self.length,
+            prefixes: self
+                .prefixes
+                .iter()
+                .map(|prefix| {
+                    Candidate::from_instrs(prefix.instrs()[..prefix.next_index()].to_vec())
+                })
+                .collect(),
+        }
+    }
+
+    fn restore(&mut self, state: SearchState) {
+        self.length = state.length;
+        self.prefixes = state
+            .prefixes
+            .iter()
+            .map(|prefix| self.prefix(prefix.instrs()))
+            .collect();
+    }
+
+    fn step(&mut self, _interpreter: &dyn exec::Interpreter, stats: &mut SearchStats) -> Step {
+        let prefix = match self.prefixes.pop() {
+            Some(prefix) => prefix,
+            None if self.length < self.max_length => {
+                self.length += 1;
+                self.prefixes.push(Candidate::new(self.length));
+                return Step::Continue;
+            }
+            None => {
+                // Resuming from past the last length ends the search right away.
+                self.length = self.max_length + 1;
+                return Step::Done;
+            }
+        };
+        stats.iterations += 1;
+
+        let instrs = &prefix.instrs()[..prefix.next_index()];
+        if prefix.next_index() < self.length {
+            for instr in &self.enumerated {
+                let candidate = match prefix.try_append(&self.instr_whitelist, instr.clone()) {
+                    Ok(candidate) => candidate,
+                    Err(AppendError::StackUnderflow) => {
+                        self.num_underflows += 1;
+                        continue;
+                    }
+                    Err(AppendError::StackOverflow) => {
+                        self.num_overflows += 1;
+                        continue;
+                    }
+                    Err(AppendError::NextIndexOutOfBounds) => unreachable!(),
+                };
+                let instrs = &candidate.instrs()[..candidate.next_index()];
+                if instrs.len() == SPLIT_LENGTH && !owns(self.worker_id, self.num_workers, instrs) {
+                    continue;
+                }
+                if self.pruning
+                    && self
+                        .pruner
+                        .record(prune::prefix_pruned_by(instrs, &self.enumerated))
+                {
+                    continue;
+                }
+                self.prefixes.push(candidate);
+            }
+            return Step::Continue;
+        }
+
+        if prefix.num_values_on_stack() != 1
+            || (instrs.len() < SPLIT_LENGTH && !owns(self.worker_id, self.num_workers, instrs))
+            // Its prefixes were checked when they were appended to.
+            || (self.pruning && self.pruner.record(prune::complete_pruned_by(instrs)))
+        {
+            return Step::Continue;
+        }
+        if self.holes {
+            Step::Fill(instrs.to_vec())
+        } else {
+            Step::Check(instrs.to_vec())
+        }
+    }
+
+    fn finish(&self, name: &str) {
+        log::info!(
+            "{} dropped {} prefixes underflowing and {} overflowing the stack",
+            name,
+            self.num_underflows,
+            self.num_overflows
+        );
+        self.pruner.report(name);
+    }
+}
+
+/// Extends prefixes one instruction at a time, depth first, for each length of candidates up to
+/// the length of the spec. Prefixes that underflow the stack, or push more values than the rest
+/// of the candidate can pop, are dropped before they are extended. Workers split the prefixes of
+/// length [SPLIT_LENGTH](SPLIT_LENGTH) among them. Each worker moves on to the next length as soon
+/// as it is done with its own prefixes, so only a single worker is guaranteed to find the
+/// shortest candidate first.
+pub fn search(
+    options: &SuperoptimizerOpts,
+    enumerative_options: &EnumerativeOpts,
+    worker: &mut Worker,
+    checkpointer: Option<&mut Checkpointer>,
+    z3_solver: &solver::Z3Solver,
+    interpreter: &mut dyn exec::Interpreter,
+    spec: &mut Spec,
+) -> Option<Candidate> {
+    let instr_whitelist = Whitelist::new(
+        worker.rng(),
+        spec.num_params(),
+        spec.num_locals(),
+        &options.constants,
+        &options.opcodes,
+    );
+    let enumerated = super::enumerated_instrs(&instr_whitelist, enumerative_options.holes);
+    let mut top_down = TopDown {
+        pruning: enumerative_options.pruning,
+        holes: enumerative_options.holes,
+        worker_id: worker.id(),
+        num_workers: worker.num_workers(),
+        instr_whitelist,
+        enumerated,
+        max_length: spec.num_instrs(),
+        // The first step moves on to candidates of length 1.
+        length: 0,
+        prefixes: Vec::new(),
+        pruner: prune::Pruner::default(),
+        num_underflows: 0,
+        num_overflows: 0,
+    };
+    let name = format!("Top-down search worker {}", worker.id());
+    driver::drive(
+        &mut top_down,
+        name,
+        options,
+        worker,
+        checkpointer,
+        z3_solver,
+        interpreter,
+        spec,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_test() {
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 2, 0, &[0], &[]);
+        let top_down = || TopDown {
+            pruning: true,
+            holes: false,
+            worker_id: 0,
+            num_workers: 1,
+            instr_whitelist: instr_whitelist.clone(),
+            enumerated: instr_whitelist.iter().cloned().collect(),
+            max_length: 3,
+            length: 3,
+            prefixes: Vec::new(),
+            pruner: prune::Pruner::default(),
+            num_underflows: 0,
+            num_overflows: 0,
+        };
+
+        let mut saved = top_down();
+        for instrs in &[
+            vec![Instruction::GetLocal(1)],
+            vec![Instruction::GetLocal(0), Instruction::I32Const(0)],
+        ] {
+            let prefix = saved.prefix(instrs);
+            saved.prefixes.push(prefix);
+        }
+
+        // The prefixes come back in the order they are extended in, padded to the length.
+        let mut restored = top_down();
+        restored.length = 1;
+        restored.restore(saved.state());
+        assert_eq!(restored.length, 3);
+        assert_eq!(restored.prefixes, saved.prefixes);
+        assert_eq!(restored.prefixes[1].next_index(), 2);
+        assert_eq!(restored.prefixes[1].instrs().len(), 3);
+    }
+}
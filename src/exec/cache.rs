@@ -0,0 +1,222 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+/// Returns the cache key of a candidate binary. The binary is generated from the candidate
+/// instruction sequence, so the same sequence always has the same key.
+pub fn hash_binary(binary: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    binary.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A bounded map that evicts the least recently used entry when it is full.
+pub struct LruCache<V> {
+    capacity: usize,
+    /// Incremented on every access, used to order entries by recency.
+    tick: u64,
+    entries: HashMap<u64, (V, u64)>,
+    /// Maps the last access tick of each entry to its key, the first one is the least recently
+    /// used.
+    order: BTreeMap<u64, u64>,
+}
+
+impl<V> LruCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::with_capacity(capacity),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn contains(&self, key: u64) -> bool {
+        self.entries.contains_key(&key)
+    }
+
+    /// Returns the value for given key and marks it as most recently used.
+    pub fn get(&mut self, key: u64) -> Option<&V> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(&key) {
+            Some((value, last_used)) => {
+                self.order.remove(last_used);
+                self.order.insert(tick, key);
+                *last_used = tick;
+                Some(value)
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: u64, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.tick += 1;
+        if let Some((_, last_used)) = self.entries.remove(&key) {
+            self.order.remove(&last_used);
+        } else if self.entries.len() >= self.capacity {
+            if let Some((&oldest_tick, &oldest_key)) = self.order.iter().next() {
+                self.order.remove(&oldest_tick);
+                self.entries.remove(&oldest_key);
+            }
+        }
+        self.order.insert(self.tick, key);
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Hit and miss counters of [EvalCache](EvalCache).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub cost_hits: u64,
+    pub cost_misses: u64,
+    pub module_hits: u64,
+    pub module_misses: u64,
+}
+
+/// Caches the costs of recently evaluated candidates, and the compiled modules of recently seen
+/// candidates so that revisiting one doesn't compile it again.
+///
+/// Entries are keyed by [hash_binary](hash_binary), and keep the binary they were computed for
+/// so that a binary whose hash collides with a cached one is a miss instead of a wrong hit.
+pub struct EvalCache<M> {
+    costs: LruCache<(Vec<u8>, u32)>,
+    modules: LruCache<(Vec<u8>, M)>,
+    stats: CacheStats,
+}
+
+impl<M> EvalCache<M> {
+    pub fn new(cost_capacity: usize, module_capacity: usize) -> Self {
+        Self {
+            costs: LruCache::new(cost_capacity),
+            modules: LruCache::new(module_capacity),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn cost(&mut self, key: u64, binary: &[u8]) -> Option<u32> {
+        let cost = match self.costs.get(key) {
+            Some((cached, cost)) if cached.as_slice() == binary => Some(*cost),
+            _ => None,
+        };
+        if cost.is_some() {
+            self.stats.cost_hits += 1;
+        } else {
+            self.stats.cost_misses += 1;
+        }
+        cost
+    }
+
+    pub fn insert_cost(&mut self, key: u64, binary: &[u8], cost: u32) {
+        self.costs.insert(key, (binary.to_vec(), cost));
+    }
+
+    /// Returns the compiled module of given binary, compiling it with `compile` if it's not
+    /// cached.
+    pub fn module_or_insert_with<F: FnOnce() -> M>(
+        &mut self,
+        key: u64,
+        binary: &[u8],
+        compile: F,
+    ) -> &M {
+        let hit = match self.modules.get(key) {
+            Some((cached, _)) => cached.as_slice() == binary,
+            None => false,
+        };
+        if hit {
+            self.stats.module_hits += 1;
+        } else {
+            self.stats.module_misses += 1;
+            self.modules.insert(key, (binary.to_vec(), compile()));
+        }
+        &self.modules.get(key).unwrap().1
+    }
+
+    /// Drops all cached costs, which are stale once the set of test cases changes. Compiled
+    /// modules are kept.
+    pub fn clear_costs(&mut self) {
+        self.costs.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_eviction_test() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        // Touch 1 so that 2 becomes the least recently used.
+        assert_eq!(cache.get(1), Some(&"a"));
+        cache.insert(3, "c");
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(1));
+        assert!(!cache.contains(2));
+        assert!(cache.contains(3));
+
+        // Overwriting doesn't evict anything.
+        cache.insert(3, "d");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(3), Some(&"d"));
+    }
+
+    #[test]
+    fn eval_cache_stats_test() {
+        let mut cache: EvalCache<u8> = EvalCache::new(4, 4);
+        assert_eq!(cache.cost(7, &[1]), None);
+        cache.insert_cost(7, &[1], 32);
+        assert_eq!(cache.cost(7, &[1]), Some(32));
+
+        assert_eq!(*cache.module_or_insert_with(7, &[1], || 1), 1);
+        assert_eq!(*cache.module_or_insert_with(7, &[1], || 2), 1);
+
+        cache.clear_costs();
+        assert_eq!(cache.cost(7, &[1]), None);
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                cost_hits: 1,
+                cost_misses: 2,
+                module_hits: 1,
+                module_misses: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn eval_cache_collision_test() {
+        // Two binaries with the same key never get each other's entries.
+        let mut cache: EvalCache<u8> = EvalCache::new(4, 4);
+        cache.insert_cost(7, &[1], 32);
+        assert_eq!(cache.cost(7, &[2]), None);
+
+        assert_eq!(*cache.module_or_insert_with(7, &[1], || 1), 1);
+        assert_eq!(*cache.module_or_insert_with(7, &[2], || 2), 2);
+        assert_eq!(cache.stats().module_misses, 2);
+    }
+}
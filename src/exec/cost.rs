@@ -0,0 +1,275 @@
+use clap::arg_enum;
+use serde::{Deserialize, Serialize};
+
+arg_enum! {
+    /// Distance between the expected and the actual output value of a test case.
+    ///
+    /// - `Hamming`: the number of differing bits.
+    /// - `Arithmetic`: the absolute difference of the values as signed integers.
+    /// - `Weighted`: the number of differing bits, where lower bits weigh more.
+    /// - `TestCases`: the number of test cases whose outputs differ.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CostFunction {
+        Hamming,
+        Arithmetic,
+        Weighted,
+        TestCases,
+    }
+}
+
+/// A test case value, independent of the runtime that produced it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Word {
+    I32(i32),
+    I64(i64),
+}
+
+impl Word {
+    pub fn bit_width(self) -> u32 {
+        match self {
+            Word::I32(_) => 32,
+            Word::I64(_) => 64,
+        }
+    }
+}
+
+/// Conversion from runtime specific values to [Word](Word).
+pub trait AsWord {
+    fn as_word(&self) -> Word;
+}
+
+/// Comparison of runtime specific traps.
+pub trait SameTrap {
+    fn same_trap(&self, other: &Self) -> bool;
+}
+
+/// The outcome of running a function on a test case input.
+#[derive(Debug)]
+pub enum Outcome<'a, T, E> {
+    Return(&'a [T]),
+    Trap(&'a E),
+    /// The function was stopped because it ran out of fuel. Only candidates are given fuel.
+    OutOfFuel,
+}
+
+impl<'a, T, E> Outcome<'a, T, E> {
+    pub fn new<V: AsRef<[T]>>(output: &'a Result<V, E>, out_of_fuel: bool) -> Self {
+        match output {
+            Ok(vals) => Outcome::Return(vals.as_ref()),
+            Err(_) if out_of_fuel => Outcome::OutOfFuel,
+            Err(err) => Outcome::Trap(err),
+        }
+    }
+}
+
+/// Computes the distance between the expected and the actual output of a test case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cost {
+    function: CostFunction,
+    /// The cost of each expected value when only one of the spec and the candidate traps, and
+    /// the cost of a test case where both trap differently.
+    trap_penalty: u32,
+}
+
+impl Cost {
+    pub fn new(function: CostFunction, trap_penalty: u32) -> Self {
+        Self {
+            function,
+            trap_penalty,
+        }
+    }
+
+    /// Returns the distance between two values of the same type.
+    pub fn value_distance(&self, expected: Word, actual: Word) -> u32 {
+        let (diff_bits, bit_width) = match (expected, actual) {
+            (Word::I32(x), Word::I32(y)) => ((x ^ y) as u32 as u64, 32),
+            (Word::I64(x), Word::I64(y)) => ((x ^ y) as u64, 64),
+            unexpected => panic!(
+                "Spec and candidate value types don't match: {:?}",
+                unexpected
+            ),
+        };
+
+        match self.function {
+            CostFunction::Hamming => diff_bits.count_ones(),
+            CostFunction::Arithmetic => {
+                let diff = match (expected, actual) {
+                    (Word::I32(x), Word::I32(y)) => (i128::from(x) - i128::from(y)).abs(),
+                    (Word::I64(x), Word::I64(y)) => (i128::from(x) - i128::from(y)).abs(),
+                    _ => unreachable!(),
+                };
+                if diff > i128::from(u32::max_value()) {
+                    u32::max_value()
+                } else {
+                    diff as u32
+                }
+            }
+            CostFunction::Weighted => (0..bit_width)
+                .filter(|i| diff_bits & (1u64 << i) != 0)
+                .map(|i| bit_width - i)
+                .sum(),
+            CostFunction::TestCases => (diff_bits != 0) as u32,
+        }
+    }
+
+    /// Returns the distance between the expected and the actual outcome of a test case. Running
+    /// out of fuel costs as much as the worst wrong output.
+    pub fn distance<T: AsWord, E: SameTrap>(
+        &self,
+        expected: Outcome<T, E>,
+        actual: Outcome<T, E>,
+    ) -> u32 {
+        let dist = match (expected, actual) {
+            (Outcome::Return(vals1), Outcome::Return(vals2)) => {
+                assert_eq!(
+                    vals1.len(),
+                    vals2.len(),
+                    "Spec and candidate function return type don't match."
+                );
+
+                vals1
+                    .iter()
+                    .zip(vals2.iter())
+                    .fold(0u32, |dist, (val1, val2)| {
+                        dist.saturating_add(self.value_distance(val1.as_word(), val2.as_word()))
+                    })
+            }
+            (Outcome::Return(vals), Outcome::OutOfFuel)
+            | (Outcome::OutOfFuel, Outcome::Return(vals)) => {
+                let bit_widths: Vec<u32> = vals.iter().map(|v| v.as_word().bit_width()).collect();
+                self.max_distance(&bit_widths)
+            }
+            (Outcome::Return(vals), Outcome::Trap(_))
+            | (Outcome::Trap(_), Outcome::Return(vals)) => {
+                self.trap_penalty.saturating_mul(vals.len() as u32)
+            }
+            (Outcome::Trap(_), Outcome::OutOfFuel) | (Outcome::OutOfFuel, Outcome::Trap(_)) => {
+                self.trap_penalty
+            }
+            (Outcome::Trap(err1), Outcome::Trap(err2)) => {
+                if err1.same_trap(err2) {
+                    0
+                } else {
+                    self.trap_penalty
+                }
+            }
+            (Outcome::OutOfFuel, Outcome::OutOfFuel) => 0,
+        };
+
+        if self.function == CostFunction::TestCases {
+            dist.min(1)
+        } else {
+            dist
+        }
+    }
+
+    /// Returns the largest distance of a single test case whose output has values of given
+    /// bit widths.
+    pub fn max_distance(&self, bit_widths: &[u32]) -> u32 {
+        let trap_distance = self.trap_penalty.saturating_mul(bit_widths.len() as u32);
+        match self.function {
+            CostFunction::Hamming => bit_widths.iter().sum::<u32>().max(trap_distance),
+            CostFunction::Arithmetic => u32::max_value(),
+            CostFunction::Weighted => bit_widths
+                .iter()
+                .map(|w| w * (w + 1) / 2)
+                .sum::<u32>()
+                .max(trap_distance),
+            CostFunction::TestCases => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl AsWord for Word {
+        fn as_word(&self) -> Word {
+            *self
+        }
+    }
+
+    impl SameTrap for &str {
+        fn same_trap(&self, other: &Self) -> bool {
+            self == other
+        }
+    }
+
+    #[test]
+    fn value_distance_test() {
+        let hamming = Cost::new(CostFunction::Hamming, 32);
+        let arithmetic = Cost::new(CostFunction::Arithmetic, 32);
+        let weighted = Cost::new(CostFunction::Weighted, 32);
+        let test_cases = Cost::new(CostFunction::TestCases, 32);
+
+        // 0 and -1 differ in every bit, but are arithmetically close.
+        assert_eq!(hamming.value_distance(Word::I32(0), Word::I32(-1)), 32);
+        assert_eq!(arithmetic.value_distance(Word::I32(0), Word::I32(-1)), 1);
+        assert_eq!(weighted.value_distance(Word::I32(0), Word::I32(-1)), 528);
+        assert_eq!(test_cases.value_distance(Word::I32(0), Word::I32(-1)), 1);
+
+        // Lowest bit weighs the most.
+        assert_eq!(weighted.value_distance(Word::I32(0), Word::I32(1)), 32);
+        assert_eq!(
+            weighted.value_distance(Word::I32(0), Word::I32(i32::min_value())),
+            1
+        );
+
+        assert_eq!(
+            arithmetic.value_distance(Word::I64(i64::min_value()), Word::I64(i64::max_value())),
+            u32::max_value()
+        );
+        assert_eq!(test_cases.value_distance(Word::I64(7), Word::I64(7)), 0);
+    }
+
+    #[test]
+    fn trap_distance_test() {
+        let hamming = Cost::new(CostFunction::Hamming, 10);
+        let test_cases = Cost::new(CostFunction::TestCases, 10);
+        let vals = [Word::I32(1)];
+        let (unreachable, div_by_zero) = ("unreachable", "integer divide by zero");
+
+        assert_eq!(
+            hamming.distance(Outcome::Return(&vals), Outcome::Trap(&unreachable)),
+            10
+        );
+        assert_eq!(
+            hamming.distance(Outcome::Trap(&unreachable), Outcome::Return(&vals)),
+            10
+        );
+        assert_eq!(
+            hamming.distance::<Word, _>(Outcome::Trap(&unreachable), Outcome::Trap(&unreachable)),
+            0
+        );
+        // Trapping differently from the spec costs as much as one wrong value.
+        assert_eq!(
+            hamming.distance::<Word, _>(Outcome::Trap(&unreachable), Outcome::Trap(&div_by_zero)),
+            10
+        );
+        assert_eq!(
+            test_cases.distance(Outcome::Return(&vals), Outcome::Trap(&unreachable)),
+            1
+        );
+    }
+
+    #[test]
+    fn out_of_fuel_distance_test() {
+        let hamming = Cost::new(CostFunction::Hamming, 10);
+        let test_cases = Cost::new(CostFunction::TestCases, 10);
+        let vals = [Word::I32(1)];
+
+        assert_eq!(
+            hamming.distance::<_, &str>(Outcome::Return(&vals), Outcome::OutOfFuel),
+            32
+        );
+        assert_eq!(
+            test_cases.distance::<_, &str>(Outcome::Return(&vals), Outcome::OutOfFuel),
+            1
+        );
+
+        let output: Result<Vec<Word>, ()> = Err(());
+        assert_matches!(Outcome::new(&output, true), Outcome::OutOfFuel);
+        assert_matches!(Outcome::new(&output, false), Outcome::Trap(_));
+    }
+}
@@ -1,10 +1,41 @@
 use clap::arg_enum;
+use parity_wasm::elements::{FunctionType, ValueType};
+use rand::Rng;
+use std::time::{Duration, Instant};
 
+pub use self::cache::CacheStats;
+pub mod cache;
+pub use self::cost::{AsWord, Cost, CostFunction, Word};
+pub mod cost;
 pub mod wasmer;
 pub mod wasmtime;
 
 const NUM_TEST_CASES: usize = 16;
 
+/// The number of compiled candidate modules each interpreter keeps around.
+const NUM_CACHED_MODULES: usize = 128;
+
+/// The output of a candidate on a test case, independent of the runtime that produced it. `None`
+/// if the candidate trapped or ran out of fuel.
+pub type TestOutput = Option<Vec<Word>>;
+
+/// Expected outcome of an invocation, as written in a `.wast` assertion.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expected {
+    /// `assert_return`, the invocation returns the given values.
+    Return(Vec<wasmer_runtime::Value>),
+    /// `assert_trap`, the invocation traps. The trap message is ignored.
+    Trap,
+}
+
+/// An `assert_return` or `assert_trap` directive for an exported function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assertion {
+    pub func_name: String,
+    pub input: Vec<wasmer_runtime::Value>,
+    pub expected: Expected,
+}
+
 arg_enum! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum InterpreterKind {
@@ -18,37 +49,260 @@ pub trait Interpreter {
     fn print_test_cases(&self);
 
     // NOTE(taegyunkim): The return type of this function is unsigned instead of
-    // signed because it represents the sum of hamming distances. When it overflows,
-    // rust will panic.
+    // signed because it represents the sum of distances given by the cost function. When it
+    // overflows, rust will panic.
     fn eval_test_cases(&self, binary: &[u8]) -> u32;
 
     /// Returns test case outputs that are different from the expected outputs.
-    fn get_test_outputs(
-        &self,
-        binary: &[u8],
-    ) -> Vec<wasmer_runtime::error::CallResult<Vec<wasmer_runtime::Value>>>;
+    fn get_test_outputs(&self, binary: &[u8]) -> Vec<TestOutput>;
 
     /// Score for an invalid WASM program.
     fn score_invalid(&self) -> u32 {
-        self.num_test_cases() as u32 * self.return_bit_width()
+        (self.num_test_cases() as u32).saturating_mul(self.max_test_case_cost())
     }
 
     fn add_test_case(&mut self, input: Vec<::wasmer_runtime::Value>);
 
+    /// Returns the inputs of all test cases in the order they were added.
+    fn test_inputs(&self) -> Vec<Vec<Word>>;
+
+    /// Returns the spec outputs of all test cases in the order they were added, `None` for the
+    /// ones where the spec traps.
+    fn expected_outputs(&self) -> Vec<Option<Vec<Word>>>;
+
+    fn clear_test_cases(&mut self);
+
+    /// Runs the candidate function in given binary on the input of the assertion, and returns
+    /// whether the outcome matches the expected one.
+    fn check_assertion(&self, binary: &[u8], assertion: &Assertion) -> bool;
+
     fn return_type_len(&self) -> usize;
 
     fn return_bit_width(&self) -> u32;
 
+    /// Returns the largest cost a single failing test case can have.
+    fn max_test_case_cost(&self) -> u32;
+
     fn num_test_cases(&self) -> usize;
+
+    /// Returns hit and miss counters of the cost and compiled module caches.
+    fn cache_stats(&self) -> CacheStats;
+
+    /// Times `samples` passes of calls over the test case inputs, after `warmup` untimed passes.
+    /// Benchmarks the spec if `binary` is `None`, otherwise the function named candidate in it,
+    /// which must not be instrumented with fuel.
+    fn benchmark(&self, binary: Option<&[u8]>, warmup: usize, samples: usize) -> Vec<Duration>;
+}
+
+/// Calls `pass` `warmup` times, then returns the time of each of `samples` more calls.
+pub fn time_passes<F: FnMut()>(warmup: usize, samples: usize, mut pass: F) -> Vec<Duration> {
+    for _ in 0..warmup {
+        pass();
+    }
+    (0..samples)
+        .map(|_| {
+            let start = Instant::now();
+            pass();
+            start.elapsed()
+        })
+        .collect()
+}
+
+/// Returns the output of a candidate on a test case if it differs from the expected output of the
+/// spec. A candidate that runs out of fuel always differs.
+fn differing_output<T: AsWord, E: cost::SameTrap, V: AsRef<[T]>>(
+    expected: &Result<V, E>,
+    actual: &Result<V, E>,
+    out_of_fuel: bool,
+) -> Option<TestOutput> {
+    let words = |vals: &V| -> Vec<Word> { vals.as_ref().iter().map(AsWord::as_word).collect() };
+    match (expected, actual) {
+        (Ok(expected), Ok(actual)) if words(expected) == words(actual) => None,
+        (Err(expected), Err(actual)) if !out_of_fuel && expected.same_trap(actual) => None,
+        (_, Ok(actual)) => Some(Some(words(actual))),
+        (_, Err(_)) => Some(None),
+    }
 }
 
-pub fn get_interpreter(
+fn to_i32(word: &Word) -> i32 {
+    match word {
+        Word::I32(x) => *x,
+        unsupported => panic!("Only i32 values are supported, got {:?}.", unsupported),
+    }
+}
+
+/// Returns whether functions of given type take and return i32 values only, the ones the
+/// interpreters generate test cases for.
+pub fn is_i32_only(func_type: &FunctionType) -> bool {
+    func_type
+        .params()
+        .iter()
+        .all(|&param| param == ValueType::I32)
+        && func_type.return_type() == Some(ValueType::I32)
+}
+
+/// Returns the input and the expected output of the test case at given index, for functions that
+/// take and return i32 values only.
+pub fn i32_test_case(interpreter: &dyn Interpreter, idx: usize) -> (Vec<i32>, Option<i32>) {
+    let input = interpreter.test_inputs()[idx].iter().map(to_i32).collect();
+    let expected = interpreter.expected_outputs()[idx].as_ref().map(|output| {
+        assert_eq!(output.len(), 1, "Doesn't support multi-value returns.");
+        to_i32(&output[0])
+    });
+    (input, expected)
+}
+
+/// Returns the inputs and the expected outputs of the test cases that don't trap, for functions
+/// that take and return i32 values only.
+pub fn i32_test_cases(interpreter: &dyn Interpreter) -> Vec<(Vec<i32>, i32)> {
+    (0..interpreter.num_test_cases())
+        .filter_map(|idx| match i32_test_case(interpreter, idx) {
+            (input, Some(expected)) => Some((input, expected)),
+            (_, None) => None,
+        })
+        .collect()
+}
+
+pub fn get_interpreter<R: Rng + ?Sized>(
+    rng: &mut R,
     kind: InterpreterKind,
     spec: &[u8],
     func_name: &str,
+    cache_size: usize,
+    cost: Cost,
+    fuel: u32,
 ) -> Box<dyn Interpreter> {
     match kind {
-        InterpreterKind::Wasmer => Box::new(wasmer::Wasmer::new(spec, func_name)),
-        InterpreterKind::Wasmtime => Box::new(wasmtime::Wasmtime::new(spec, func_name)),
+        InterpreterKind::Wasmer => Box::new(wasmer::Wasmer::new(
+            rng, spec, func_name, cache_size, cost, fuel,
+        )),
+        InterpreterKind::Wasmtime => Box::new(wasmtime::Wasmtime::new(
+            rng, spec, func_name, cache_size, cost, fuel,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::Spec;
+    use parity_wasm::elements::{BlockType, FuncBody, Instruction, Instructions};
+    use wasmer_runtime::Value;
+
+    #[test]
+    fn check_assertion_test() {
+        let spec = wat::parse_str(
+            r#"(module
+                 (func (export "div") (param i32 i32) (result i32)
+                   local.get 0
+                   local.get 1
+                   i32.div_s))"#,
+        )
+        .unwrap();
+        let func_type = FunctionType::new(vec![ValueType::I32; 2], Some(ValueType::I32));
+        let func_body = FuncBody::new(vec![], Instructions::new(vec![Instruction::End]));
+        let mut candidate = Spec::new(&func_type, &func_body, 100, &[]);
+        let binary = candidate
+            .get_binary_with_instrs(&[
+                Instruction::GetLocal(0),
+                Instruction::GetLocal(1),
+                Instruction::I32DivS,
+            ])
+            .to_vec();
+        let spin = candidate
+            .get_binary_with_instrs(&[
+                Instruction::Loop(BlockType::NoResult),
+                Instruction::Br(0),
+                Instruction::End,
+                Instruction::GetLocal(0),
+            ])
+            .to_vec();
+
+        let assertion = |input: Vec<Value>, expected| Assertion {
+            func_name: String::from("div"),
+            input,
+            expected,
+        };
+        for kind in &[InterpreterKind::Wasmer, InterpreterKind::Wasmtime] {
+            let interpreter = get_interpreter(
+                &mut rand::thread_rng(),
+                *kind,
+                &spec,
+                "div",
+                16,
+                Cost::new(CostFunction::Hamming, 32),
+                100,
+            );
+            let check =
+                |input, expected| interpreter.check_assertion(&binary, &assertion(input, expected));
+
+            let returns = |x| Expected::Return(vec![Value::I32(x)]);
+            assert!(check(vec![Value::I32(6), Value::I32(3)], returns(2)));
+            assert!(!check(vec![Value::I32(6), Value::I32(3)], returns(3)));
+            assert!(!check(vec![Value::I32(6), Value::I32(3)], Expected::Trap));
+            assert!(check(vec![Value::I32(1), Value::I32(0)], Expected::Trap));
+            assert!(!check(vec![Value::I32(1), Value::I32(0)], returns(0)));
+            // A result of another type never matches.
+            assert!(!check(
+                vec![Value::I32(6), Value::I32(3)],
+                Expected::Return(vec![Value::I64(2)])
+            ));
+            // Running out of fuel doesn't count as a trap.
+            assert!(!interpreter.check_assertion(
+                &spin,
+                &assertion(vec![Value::I32(1), Value::I32(0)], Expected::Trap)
+            ));
+        }
+    }
+
+    #[test]
+    fn get_test_outputs_test() {
+        let spec = wat::parse_str(
+            r#"(module
+                 (func (export "div") (param i32 i32) (result i32)
+                   local.get 0
+                   local.get 1
+                   i32.div_s))"#,
+        )
+        .unwrap();
+        let func_type = FunctionType::new(vec![ValueType::I32; 2], Some(ValueType::I32));
+        let func_body = FuncBody::new(vec![], Instructions::new(vec![Instruction::End]));
+        let mut candidate = Spec::new(&func_type, &func_body, 100, &[]);
+        let div = candidate
+            .get_binary_with_instrs(&[
+                Instruction::GetLocal(0),
+                Instruction::GetLocal(1),
+                Instruction::I32DivS,
+            ])
+            .to_vec();
+        let sub = candidate
+            .get_binary_with_instrs(&[
+                Instruction::GetLocal(0),
+                Instruction::GetLocal(1),
+                Instruction::I32Sub,
+            ])
+            .to_vec();
+
+        for kind in &[InterpreterKind::Wasmer, InterpreterKind::Wasmtime] {
+            let mut interpreter = get_interpreter(
+                &mut rand::thread_rng(),
+                *kind,
+                &spec,
+                "div",
+                16,
+                Cost::new(CostFunction::Hamming, 32),
+                100,
+            );
+            interpreter.clear_test_cases();
+            interpreter.add_test_case(vec![Value::I32(6), Value::I32(3)]);
+            interpreter.add_test_case(vec![Value::I32(1), Value::I32(0)]);
+
+            // The spec itself passes, including the test case it traps on.
+            assert!(interpreter.get_test_outputs(&div).is_empty());
+            assert_eq!(
+                interpreter.get_test_outputs(&sub),
+                vec![Some(vec![Word::I32(3)]), Some(vec![Word::I32(1)])]
+            );
+        }
     }
 }
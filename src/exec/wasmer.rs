@@ -1,6 +1,13 @@
-use super::{Interpreter, InterpreterKind, NUM_TEST_CASES};
+use super::cache::{hash_binary, CacheStats, EvalCache};
+use super::cost::{AsWord, Cost, Outcome, SameTrap, Word};
+use super::{
+    differing_output, time_passes, Assertion, Expected, Interpreter, InterpreterKind, TestOutput,
+    NUM_CACHED_MODULES, NUM_TEST_CASES,
+};
+use crate::parity_wasm_utils::{fuel_to_i32, FUEL_GLOBAL, OUT_OF_FUEL};
 use rand::Rng;
-use wasmer_runtime::error::CallResult;
+use std::cell::RefCell;
+use std::time::Duration;
 use wasmer_runtime::*;
 
 pub type Input = Vec<Value>;
@@ -14,16 +21,27 @@ pub struct Wasmer {
     func_name: String,
     test_cases: TestCases,
     return_type_bits: Vec<u32>,
+    cache: RefCell<EvalCache<Instance>>,
+    cost: Cost,
+    /// Fuel given to each candidate execution.
+    fuel: u32,
 }
 
 impl Wasmer {
-    pub fn new(spec: &[u8], func_name: &str) -> Self {
+    pub fn new<R: Rng + ?Sized>(
+        rng: &mut R,
+        spec: &[u8],
+        func_name: &str,
+        cache_size: usize,
+        cost: Cost,
+        fuel: u32,
+    ) -> Self {
         let import_object = imports! {};
         let instance = instantiate(spec, &import_object).unwrap();
         let func = instance.dyn_func(func_name).unwrap();
         let mut inputs: Vec<Input> = Vec::with_capacity(NUM_TEST_CASES);
         for _ in 0..NUM_TEST_CASES {
-            inputs.push(gen_random_input(func.signature().params()));
+            inputs.push(gen_random_input(rng, func.signature().params()));
         }
         let outputs: Vec<Output> = inputs.iter().map(|input| func.call(input)).collect();
         let test_cases = inputs.into_iter().zip(outputs.into_iter()).collect();
@@ -47,8 +65,32 @@ impl Wasmer {
             func_name: String::from(func_name),
             test_cases,
             return_type_bits,
+            cache: RefCell::new(EvalCache::new(cache_size, NUM_CACHED_MODULES)),
+            cost,
+            fuel,
         }
     }
+
+    /// Calls `f` with the candidate instance of given binary, which is instantiated only if it
+    /// isn't in the module cache.
+    fn with_candidate<T, F: FnOnce(&Instance) -> T>(&self, key: u64, binary: &[u8], f: F) -> T {
+        let mut cache = self.cache.borrow_mut();
+        let instance = cache.module_or_insert_with(key, binary, || {
+            let import_object = imports! {};
+            instantiate(binary, &import_object).unwrap()
+        });
+        f(instance)
+    }
+
+    /// Refills the fuel of the candidate instance and calls the candidate function. Returns the
+    /// output, and whether the candidate ran out of fuel.
+    fn call_candidate(&self, instance: &Instance, input: &[Value]) -> (Output, bool) {
+        let fuel: Global = instance.exports.get(FUEL_GLOBAL).unwrap();
+        fuel.set(Value::I32(fuel_to_i32(self.fuel)));
+        let output = instance.dyn_func("candidate").unwrap().call(input);
+        let out_of_fuel = output.is_err() && fuel.get() == Value::I32(OUT_OF_FUEL);
+        (output, out_of_fuel)
+    }
 }
 
 impl Interpreter for Wasmer {
@@ -59,41 +101,84 @@ impl Interpreter for Wasmer {
     fn print_test_cases(&self) {}
 
     fn eval_test_cases(&self, binary: &[u8]) -> u32 {
-        let import_object = imports! {};
-        let instance_or_err = instantiate(binary, &import_object);
-        let instance = instance_or_err.unwrap();
-        let func_or_err = instance.dyn_func("candidate");
-        let func = func_or_err.unwrap();
-        let mut dist = 0;
-        for (input, expected_output) in &self.test_cases {
-            let actual_output = func.call(&input);
-            dist += hamming_distance(&expected_output, &actual_output);
+        let key = hash_binary(binary);
+        if let Some(cost) = self.cache.borrow_mut().cost(key, binary) {
+            return cost;
         }
+
+        let dist = self.with_candidate(key, binary, |instance| {
+            let mut dist = 0;
+            for (input, expected_output) in &self.test_cases {
+                let (actual_output, out_of_fuel) = self.call_candidate(instance, &input);
+                dist = self
+                    .cost
+                    .distance(
+                        Outcome::new(expected_output, false),
+                        Outcome::new(&actual_output, out_of_fuel),
+                    )
+                    .saturating_add(dist);
+            }
+            dist
+        });
+        self.cache.borrow_mut().insert_cost(key, binary, dist);
         dist
     }
 
-    fn get_test_outputs(&self, binary: &[u8]) -> Vec<CallResult<Vec<Value>>> {
-        let import_object = imports! {};
-        let instance_or_err = instantiate(binary, &import_object);
-        let instance = instance_or_err.unwrap();
-        let func_or_err = instance.dyn_func("candidate");
-        let func = func_or_err.unwrap();
-
-        let mut diffs = Vec::new();
-        for (input, expected_output) in &self.test_cases {
-            let actual_output = func.call(&input);
-            if *expected_output != actual_output {
-                diffs.push(actual_output);
+    fn get_test_outputs(&self, binary: &[u8]) -> Vec<TestOutput> {
+        self.with_candidate(hash_binary(binary), binary, |instance| {
+            let mut diffs = Vec::new();
+            for (input, expected_output) in &self.test_cases {
+                let (actual_output, out_of_fuel) = self.call_candidate(instance, &input);
+                if let Some(diff) = differing_output(expected_output, &actual_output, out_of_fuel) {
+                    diffs.push(diff);
+                }
             }
-        }
 
-        diffs
+            diffs
+        })
     }
 
     fn add_test_case(&mut self, input: Vec<::wasmer_runtime::Value>) {
         let func = self.instance.dyn_func(&self.func_name).unwrap();
         let output = func.call(&input);
         self.test_cases.push((input, output));
+        self.cache.borrow_mut().clear_costs();
+    }
+
+    fn test_inputs(&self) -> Vec<Vec<Word>> {
+        self.test_cases
+            .iter()
+            .map(|(input, _)| input.iter().map(AsWord::as_word).collect())
+            .collect()
+    }
+
+    fn expected_outputs(&self) -> Vec<Option<Vec<Word>>> {
+        self.test_cases
+            .iter()
+            .map(|(_, output)| {
+                output
+                    .as_ref()
+                    .ok()
+                    .map(|vals| vals.iter().map(AsWord::as_word).collect())
+            })
+            .collect()
+    }
+
+    fn clear_test_cases(&mut self) {
+        self.test_cases.clear();
+        self.cache.borrow_mut().clear_costs();
+    }
+
+    fn check_assertion(&self, binary: &[u8], assertion: &Assertion) -> bool {
+        self.with_candidate(hash_binary(binary), binary, |instance| {
+            let (output, out_of_fuel) = self.call_candidate(instance, &assertion.input);
+            match (&assertion.expected, output) {
+                (Expected::Return(expected), Ok(actual)) => *expected == actual,
+                // Running out of fuel isn't a trap of the candidate itself.
+                (Expected::Trap, Err(_)) => !out_of_fuel,
+                _ => false,
+            }
+        })
     }
 
     fn return_type_len(&self) -> usize {
@@ -104,56 +189,58 @@ impl Interpreter for Wasmer {
         self.return_type_bits.iter().sum()
     }
 
+    fn max_test_case_cost(&self) -> u32 {
+        self.cost.max_distance(&self.return_type_bits)
+    }
+
     fn num_test_cases(&self) -> usize {
         self.test_cases.len()
     }
-}
 
-fn hamming_distance(output1: &Output, output2: &Output) -> u32 {
-    let mut dist = 0;
-
-    match (output1, output2) {
-        (Ok(val_vec1), Ok(val_vec2)) => {
-            assert_eq!(
-                val_vec1.len(),
-                val_vec2.len(),
-                "Spec and candidate function return type don't match."
-            );
-
-            for (val1, val2) in val_vec1.iter().zip(val_vec2.iter()) {
-                match (val1, val2) {
-                    (Value::I32(x), Value::I32(y)) => {
-                        dist += (x ^ y).count_ones();
-                    }
-                    _ => {
-                        panic!("type not supported.");
-                    }
-                }
-            }
-        }
-        (Ok(val_vec), Err(_)) | (Err(_), Ok(val_vec)) => {
-            for val in val_vec {
-                match val {
-                    Value::I32(_) => dist += 32,
-                    _ => panic!("type not supported."),
-                }
+    fn cache_stats(&self) -> CacheStats {
+        self.cache.borrow().stats()
+    }
+
+    fn benchmark(&self, binary: Option<&[u8]>, warmup: usize, samples: usize) -> Vec<Duration> {
+        let candidate;
+        let func = match binary {
+            Some(binary) => {
+                let import_object = imports! {};
+                candidate = instantiate(binary, &import_object).unwrap();
+                candidate.dyn_func("candidate").unwrap()
             }
-        }
-        (Err(err1), Err(err2)) => {
-            if err1 != err2 {
-                dist += 32
+            None => self.instance.dyn_func(&self.func_name).unwrap(),
+        };
+
+        time_passes(warmup, samples, || {
+            for (input, _) in &self.test_cases {
+                let _ = func.call(input);
             }
-        }
+        })
     }
+}
 
-    dist
+impl SameTrap for error::CallError {
+    fn same_trap(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+impl AsWord for Value {
+    fn as_word(&self) -> Word {
+        match self {
+            Value::I32(x) => Word::I32(*x),
+            Value::I64(x) => Word::I64(*x),
+            unimplemented => panic!("{:?} type not supported.", unimplemented),
+        }
+    }
 }
 
-fn gen_random_input(param_types: &[types::Type]) -> Input {
+fn gen_random_input<R: Rng + ?Sized>(rng: &mut R, param_types: &[types::Type]) -> Input {
     let mut inputs = Vec::with_capacity(param_types.len());
     for param_type in param_types {
         let arg = match param_type {
-            types::Type::I32 => Value::I32(rand::thread_rng().gen::<i32>()),
+            types::Type::I32 => Value::I32(rng.gen::<i32>()),
             unexpected => {
                 panic!("{:?} type not supported.", unexpected);
             }
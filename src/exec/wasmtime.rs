@@ -1,6 +1,14 @@
-use super::{Interpreter, InterpreterKind, NUM_TEST_CASES};
+use super::cache::{hash_binary, CacheStats, EvalCache};
+use super::cost::{AsWord, Cost, Outcome, SameTrap, Word};
+use super::{
+    differing_output, time_passes, Assertion, Expected, Interpreter, InterpreterKind, TestOutput,
+    NUM_CACHED_MODULES, NUM_TEST_CASES,
+};
+use crate::parity_wasm_utils::{fuel_to_i32, FUEL_GLOBAL, OUT_OF_FUEL};
 use rand::Rng;
 use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::time::Duration;
 use wasmtime::*;
 
 pub type Input = Vec<Val>;
@@ -15,10 +23,21 @@ pub struct Wasmtime {
     func_name: String,
     test_cases: TestCases,
     return_type_bits: Vec<u32>,
+    cache: RefCell<EvalCache<Instance>>,
+    cost: Cost,
+    /// Fuel given to each candidate execution.
+    fuel: u32,
 }
 
 impl Wasmtime {
-    pub fn new(spec: &[u8], func_name: &str) -> Self {
+    pub fn new<R: Rng + ?Sized>(
+        rng: &mut R,
+        spec: &[u8],
+        func_name: &str,
+        cache_size: usize,
+        cost: Cost,
+        fuel: u32,
+    ) -> Self {
         let store = wasmtime::Store::default();
         let module = Module::new(&store, &spec).unwrap();
         let instance = Instance::new(&module, &[]).unwrap();
@@ -31,7 +50,7 @@ impl Wasmtime {
 
         let mut inputs: Vec<Input> = Vec::with_capacity(NUM_TEST_CASES);
         for _ in 0..NUM_TEST_CASES {
-            inputs.push(gen_random_input(func.ty().params()));
+            inputs.push(gen_random_input(rng, func.ty().params()));
         }
         let outputs: Vec<Output> = inputs.iter().map(|input| func.call(input)).collect();
         let test_cases = inputs.into_iter().zip(outputs.into_iter()).collect();
@@ -55,41 +74,89 @@ impl Wasmtime {
             func_name: String::from(func_name),
             test_cases,
             return_type_bits,
+            cache: RefCell::new(EvalCache::new(cache_size, NUM_CACHED_MODULES)),
+            cost,
+            fuel,
         }
     }
-}
 
-impl Interpreter for Wasmtime {
-    fn kind(&self) -> InterpreterKind {
-        InterpreterKind::Wasmtime
+    /// Calls `f` with the candidate instance of given binary, which is compiled only if it isn't
+    /// in the module cache.
+    fn with_candidate<T, F: FnOnce(&Instance) -> T>(&self, key: u64, binary: &[u8], f: F) -> T {
+        let mut cache = self.cache.borrow_mut();
+        let instance = cache.module_or_insert_with(key, binary, || {
+            let module = Module::new(&self.store, &binary).unwrap();
+            Instance::new(&module, &[]).unwrap()
+        });
+        f(instance)
     }
 
-    fn print_test_cases(&self) {}
+    /// Refills the fuel of the candidate instance and calls the candidate function. Returns the
+    /// output, and whether the candidate ran out of fuel.
+    fn call_candidate(&self, instance: &Instance, input: &[Val]) -> (Output, bool) {
+        let fuel = instance
+            .get_export(FUEL_GLOBAL)
+            .expect("Candidate doesn't export fuel global, should never happen.")
+            .global()
+            .expect("Export fuel is not a global, should never happen.");
+        fuel.set(Val::I32(fuel_to_i32(self.fuel)))
+            .expect("Fuel global is not a mutable i32, should never happen.");
 
-    fn eval_test_cases(&self, binary: &[u8]) -> u32 {
-        let module_or_err = Module::new(&self.store, &binary);
-        let module = module_or_err.unwrap();
-        let instance_or_err = Instance::new(&module, &[]);
-        let instance = instance_or_err.unwrap();
         let func = instance
             .get_export("candidate")
             .expect("Export with name candidate doesn't exist, should never happen.")
             .func()
             .expect("Export candidate is not a function, should never happen.")
             .borrow();
-        let mut dist = 0;
-        for (input, expected_output) in &self.test_cases {
-            let actual_output = func.call(&input);
-            dist += hamming_distance(&expected_output, &actual_output);
+        let output = func.call(input);
+        let out_of_fuel = output.is_err() && fuel.get().unwrap_i32() == OUT_OF_FUEL;
+        (output, out_of_fuel)
+    }
+}
+
+impl Interpreter for Wasmtime {
+    fn kind(&self) -> InterpreterKind {
+        InterpreterKind::Wasmtime
+    }
+
+    fn print_test_cases(&self) {}
+
+    fn eval_test_cases(&self, binary: &[u8]) -> u32 {
+        let key = hash_binary(binary);
+        if let Some(cost) = self.cache.borrow_mut().cost(key, binary) {
+            return cost;
         }
+
+        let dist = self.with_candidate(key, binary, |instance| {
+            let mut dist = 0;
+            for (input, expected_output) in &self.test_cases {
+                let (actual_output, out_of_fuel) = self.call_candidate(instance, &input);
+                dist = self
+                    .cost
+                    .distance(
+                        Outcome::new(expected_output, false),
+                        Outcome::new(&actual_output, out_of_fuel),
+                    )
+                    .saturating_add(dist);
+            }
+            dist
+        });
+        self.cache.borrow_mut().insert_cost(key, binary, dist);
         dist
     }
 
-    fn get_test_outputs(
-        &self,
-        _binary: &[u8],
-    ) -> Vec<wasmer_runtime::error::CallResult<Vec<wasmer_runtime::Value>>> {
-        panic!("Unimplemented.");
+    fn get_test_outputs(&self, binary: &[u8]) -> Vec<TestOutput> {
+        self.with_candidate(hash_binary(binary), binary, |instance| {
+            let mut diffs = Vec::new();
+            for (input, expected_output) in &self.test_cases {
+                let (actual_output, out_of_fuel) = self.call_candidate(instance, &input);
+                if let Some(diff) = differing_output(expected_output, &actual_output, out_of_fuel) {
+                    diffs.push(diff);
+                }
+            }
+
+            diffs
+        })
     }
 
     fn add_test_case(&mut self, input: Vec<::wasmer_runtime::Value>) {
@@ -101,16 +168,55 @@ impl Interpreter for Wasmtime {
             .unwrap()
             .borrow();
 
-        let wasmtime_input: Vec<Val> = input
-            .into_iter()
-            .map(|i| match i {
-                ::wasmer_runtime::Value::I32(x) => Val::I32(x),
-                unimplemented => panic!("type not implemented {:?}", unimplemented),
-            })
-            .collect();
+        let wasmtime_input = to_wasmtime_values(&input);
 
         let output = func.call(&wasmtime_input);
         self.test_cases.push((wasmtime_input, output));
+        self.cache.borrow_mut().clear_costs();
+    }
+
+    fn test_inputs(&self) -> Vec<Vec<Word>> {
+        self.test_cases
+            .iter()
+            .map(|(input, _)| input.iter().map(AsWord::as_word).collect())
+            .collect()
+    }
+
+    fn expected_outputs(&self) -> Vec<Option<Vec<Word>>> {
+        self.test_cases
+            .iter()
+            .map(|(_, output)| {
+                output
+                    .as_ref()
+                    .ok()
+                    .map(|vals| vals.iter().map(AsWord::as_word).collect())
+            })
+            .collect()
+    }
+
+    fn clear_test_cases(&mut self) {
+        self.test_cases.clear();
+        self.cache.borrow_mut().clear_costs();
+    }
+
+    fn check_assertion(&self, binary: &[u8], assertion: &Assertion) -> bool {
+        self.with_candidate(hash_binary(binary), binary, |instance| {
+            let (output, out_of_fuel) =
+                self.call_candidate(instance, &to_wasmtime_values(&assertion.input));
+            match (&assertion.expected, output) {
+                (Expected::Return(expected), Ok(actual)) => {
+                    expected.len() == actual.len()
+                        && expected.iter().zip(actual.iter()).all(|pair| match pair {
+                            (::wasmer_runtime::Value::I32(x), Val::I32(y)) => x == y,
+                            (::wasmer_runtime::Value::I64(x), Val::I64(y)) => x == y,
+                            _ => false,
+                        })
+                }
+                // Running out of fuel isn't a trap of the candidate itself.
+                (Expected::Trap, Err(_)) => !out_of_fuel,
+                _ => false,
+            }
+        })
     }
 
     fn return_type_len(&self) -> usize {
@@ -121,16 +227,57 @@ impl Interpreter for Wasmtime {
         self.return_type_bits.iter().sum()
     }
 
+    fn max_test_case_cost(&self) -> u32 {
+        self.cost.max_distance(&self.return_type_bits)
+    }
+
     fn num_test_cases(&self) -> usize {
         self.test_cases.len()
     }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.cache.borrow().stats()
+    }
+
+    fn benchmark(&self, binary: Option<&[u8]>, warmup: usize, samples: usize) -> Vec<Duration> {
+        let (instance, func_name) = match binary {
+            Some(binary) => {
+                let module = Module::new(&self.store, &binary).unwrap();
+                (Instance::new(&module, &[]).unwrap(), "candidate")
+            }
+            None => (self.instance.clone(), self.func_name.as_str()),
+        };
+        let func = instance
+            .get_export(func_name)
+            .unwrap()
+            .func()
+            .unwrap()
+            .borrow();
+
+        time_passes(warmup, samples, || {
+            for (input, _) in &self.test_cases {
+                let _ = func.call(input);
+            }
+        })
+    }
 }
 
-fn gen_random_input(param_types: &[ValType]) -> Input {
+fn to_wasmtime_values(values: &[::wasmer_runtime::Value]) -> Input {
+    values
+        .iter()
+        .map(|v| match v {
+            ::wasmer_runtime::Value::I32(x) => Val::I32(*x),
+            ::wasmer_runtime::Value::I64(x) => Val::I64(*x),
+            unimplemented => panic!("type not implemented {:?}", unimplemented),
+        })
+        .collect()
+}
+
+fn gen_random_input<R: Rng + ?Sized>(rng: &mut R, param_types: &[ValType]) -> Input {
     let mut input = Vec::with_capacity(param_types.len());
     for param_type in param_types {
         let arg = match param_type {
-            ValType::I32 => Val::I32(rand::thread_rng().gen::<i32>()),
+            ValType::I32 => Val::I32(rng.gen::<i32>()),
             unimplemented => {
                 panic!("{:?} type not implemented.", unimplemented);
             }
@@ -140,39 +287,18 @@ fn gen_random_input(param_types: &[ValType]) -> Input {
     input
 }
 
-fn hamming_distance(output1: &Output, output2: &Output) -> u32 {
-    let mut dist = 0;
-
-    match (output1, output2) {
-        (Ok(val_vec1), Ok(val_vec2)) => {
-            assert_eq!(
-                val_vec1.len(),
-                val_vec2.len(),
-                "Spec and candidate function return type don't match."
-            );
-
-            for (val1, val2) in val_vec1.iter().zip(val_vec2.iter()) {
-                match (val1, val2) {
-                    (Val::I32(x), Val::I32(y)) => {
-                        dist += (x ^ y).count_ones();
-                    }
-                    unimplemented => {
-                        panic!("{:?} type not supported.", unimplemented);
-                    }
-                }
-            }
-        }
-        (Ok(val_vec), Err(_)) | (Err(_), Ok(val_vec)) => {
-            for val in val_vec.iter() {
-                match val {
-                    Val::I32(_) => dist += 32,
-                    _ => panic!("type not supported."),
-                }
-            }
-        }
-        (Err(_), Err(_)) => {
-            // TODO(taegyunkim): Figure out a right way to compare traps.
+impl SameTrap for Trap {
+    fn same_trap(&self, other: &Self) -> bool {
+        self.message() == other.message()
+    }
+}
+
+impl AsWord for Val {
+    fn as_word(&self) -> Word {
+        match self {
+            Val::I32(x) => Word::I32(*x),
+            Val::I64(x) => Word::I64(*x),
+            unimplemented => panic!("{:?} type not supported.", unimplemented),
         }
     }
-    dist
 }
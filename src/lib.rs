@@ -1,12 +1,17 @@
+extern crate bus;
 extern crate chrono;
 extern crate clap;
 extern crate itertools;
+extern crate log;
 #[cfg(test)]
 #[macro_use]
 extern crate matches;
 extern crate num_cpus;
 extern crate parity_wasm;
 extern crate rand;
+extern crate rand_chacha;
+extern crate serde;
+extern crate serde_json;
 extern crate timer;
 extern crate wabt;
 extern crate wasmer_runtime;
@@ -16,24 +21,43 @@ extern crate wasmprinter;
 extern crate wast;
 extern crate wat;
 
-use crate::exec::InterpreterKind;
+use crate::cegis::CegisOpts;
+use crate::components::ComponentsOpts;
+use crate::enumerative::EnumerativeOpts;
+use crate::exec::{CostFunction, InterpreterKind};
+use crate::perf::bench::Summary;
+use crate::perf::{LatencyTable, PerfModel};
 use crate::stoke::StokeOpts;
-use parity_wasm::elements::{FuncBody, FunctionType, Instruction, Internal, Module};
+use parity_wasm::elements::{
+    FuncBody, FunctionType, Instruction, Instructions, Internal, Local, Module, ValueType,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
+pub mod cegis;
+pub mod checkpoint;
+pub mod components;
+pub mod driver;
 pub mod enumerative;
 pub mod exec;
 pub mod parity_wasm_utils;
+pub mod perf;
 pub mod solver;
+pub mod stats;
 pub mod stoke;
 pub mod wasm;
+pub mod worker;
 
 #[derive(Clone, Debug, StructOpt)]
 pub enum Algorithm {
     Stoke(StokeOpts),
-    Enumerative,
+    Enumerative(EnumerativeOpts),
+    Cegis(CegisOpts),
+    Components(ComponentsOpts),
 }
 
 #[derive(Clone, Debug, StructOpt)]
@@ -75,10 +99,144 @@ pub struct SuperoptimizerOpts {
     )]
     pub constants: Vec<i32>,
 
+    #[structopt(
+        long = "no-mine-constants",
+        help = "If set, don't add up to 8 constants of the spec and values derived from them to the constants.",
+        parse(from_flag = std::ops::Not::not)
+    )]
+    pub mine_constants: bool,
+
+    #[structopt(
+        long,
+        help="A comma separated list of the types of extra locals candidates can use as temporaries. The ones a verified candidate doesn't use are dropped.",
+        possible_values=&wasm::LocalType::variants(),
+        require_delimiter(true)
+    )]
+    pub scratch_locals: Vec<wasm::LocalType>,
+
+    #[structopt(
+        long,
+        help = "Comma separated opcodes of the instructions candidates can use, in the text format without immediates, e.g. `i32.and,i32.or,local.get`. `i32.const` stands for all the constants. Every supported instruction if not set.",
+        require_delimiter(true)
+    )]
+    pub opcodes: Vec<String>,
+
+    #[structopt(
+        long,
+        conflicts_with = "opcodes",
+        help = "A TOML file, or a JSON one if its extension isn't `.toml`, listing the opcodes of the instructions candidates can use under `opcodes`, like `--opcodes`.",
+        parse(from_os_str)
+    )]
+    pub whitelist: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Seed for the random number generator, a random one is picked and printed if not set. Runs are only reproducible with --num-workers 1, since workers share counterexamples in the order they find them."
+    )]
+    pub seed: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "The max number of candidate costs each interpreter caches.",
+        default_value = "4096"
+    )]
+    pub cache_size: usize,
+
+    #[structopt(
+        long,
+        help="The distance between expected and actual test case outputs to minimize.",
+        possible_values=&CostFunction::variants(),
+        default_value="Hamming")]
+    pub cost_function: CostFunction,
+
+    #[structopt(
+        long,
+        help = "The cost of each expected value when only one of the spec and the candidate traps, and of a test case where they trap differently.",
+        default_value = "32"
+    )]
+    pub trap_penalty: u32,
+
+    #[structopt(
+        long,
+        help = "The max number of function entries and loop iterations of one candidate execution.",
+        default_value = "100000"
+    )]
+    pub fuel: u32,
+
+    #[structopt(
+        long,
+        help = "The number of stochastic search chains or top-down enumerations run in parallel, the number of logical cores if not set. Parallel top-down enumerations may find a longer candidate before a shorter one."
+    )]
+    pub num_workers: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "A file of per opcode latencies to estimate the cost of candidates with, one `opcode latency` pair per line. Latencies of x86-64 are used for missing opcodes.",
+        parse(from_os_str)
+    )]
+    pub latencies: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "If set, benchmark verified candidates and rank them by measured run time."
+    )]
+    pub benchmark: bool,
+
+    #[structopt(
+        long,
+        help = "The number of untimed passes over the test inputs before benchmarking a function.",
+        default_value = "10"
+    )]
+    pub bench_warmup: usize,
+
+    #[structopt(
+        long,
+        help = "The number of timed passes over the test inputs to benchmark a function with.",
+        default_value = "100"
+    )]
+    pub bench_samples: usize,
+
+    #[structopt(
+        long,
+        help = "The number of seconds between two progress reports of each search worker, logged at info level.",
+        default_value = "10"
+    )]
+    pub progress_interval: u64,
+
+    #[structopt(
+        long,
+        help = "A directory to periodically write a checkpoint of each search worker to.",
+        parse(from_os_str)
+    )]
+    pub checkpoint_dir: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "The number of seconds between two checkpoints of each search worker.",
+        default_value = "60"
+    )]
+    pub checkpoint_interval: u64,
+
+    #[structopt(
+        long,
+        requires = "checkpoint-dir",
+        help = "If set, continue each search worker from its checkpoint in the checkpoint directory."
+    )]
+    pub resume: bool,
+
     #[structopt(subcommand)]
     pub algorithm: Algorithm,
 }
 
+impl SuperoptimizerOpts {
+    fn scratch_local_types(&self) -> Vec<ValueType> {
+        self.scratch_locals
+            .iter()
+            .map(|&local_type| ValueType::from(local_type))
+            .collect()
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Mode {
     Synthesis,
@@ -87,20 +245,68 @@ pub enum Mode {
 
 pub struct Superoptimizer {
     spec: Vec<u8>,
+    /// Assertions from the input `.wast` file, used to seed test cases and to check verified
+    /// candidates.
+    assertions: Vec<exec::Assertion>,
     options: SuperoptimizerOpts,
+    perf_model: LatencyTable,
 }
 
 impl Superoptimizer {
-    pub fn new(spec: Vec<u8>, options: SuperoptimizerOpts) -> Self {
-        Superoptimizer { spec, options }
+    pub fn new(
+        spec: Vec<u8>,
+        assertions: Vec<exec::Assertion>,
+        mut options: SuperoptimizerOpts,
+    ) -> Self {
+        if let Some(file) = &options.whitelist {
+            options.opcodes = wasm::read_opcodes(file);
+            assert!(!options.opcodes.is_empty(), "The whitelist has no opcodes.");
+        }
+        options.opcodes = wasm::validate_opcodes(&options.opcodes);
+        assert!(
+            options.fuel <= i32::max_value() as u32,
+            "--fuel must be at most {}.",
+            i32::max_value()
+        );
+        if let Algorithm::Enumerative(enumerative_options) = &options.algorithm {
+            assert!(
+                !(enumerative_options.holes
+                    && enumerative_options.strategy == enumerative::Strategy::BottomUp),
+                "--holes isn't supported by the BottomUp strategy."
+            );
+        }
+        if let Algorithm::Cegis(cegis_options) = &options.algorithm {
+            if let Some(max_length) = cegis_options.max_length {
+                assert!(
+                    cegis_options.min_length <= max_length,
+                    "--min-length must be at most --max-length."
+                );
+            }
+        }
+
+        let perf_model = match &options.latencies {
+            Some(file) => LatencyTable::from_file(file),
+            None => LatencyTable::default(),
+        };
+        Superoptimizer {
+            spec,
+            assertions,
+            options,
+            perf_model,
+        }
     }
 
     pub fn run(&self) {
         let module = Module::from_bytes(&self.spec).unwrap();
 
-        // TODO(taegyunkim): Use num_cpus crate to appropriately set the number of workers.
-        let num_workers = 1;
-        let mut candidates: Vec<wasm::Candidate> = Vec::with_capacity(num_workers);
+        let seed = self.options.seed.unwrap_or_else(rand::random);
+        println!("Using seed {}", seed);
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        if let Some(checkpoint_dir) = &self.options.checkpoint_dir {
+            std::fs::create_dir_all(checkpoint_dir)
+                .expect("Failed to create checkpoint directory.");
+        }
 
         let export_section = module
             .export_section()
@@ -112,100 +318,429 @@ impl Superoptimizer {
 
                 let (func_type, func_body) = parity_wasm_utils::func_by_name(&module, func_name);
 
-                // TODO(taegyunkim): Parallel processing.
-                for _ in 0..num_workers {
-                    if let Some(mut candidate) = self.invoke_search(
-                        func_name,
-                        func_type,
-                        func_body,
-                        &self.options,
-                        Mode::Synthesis,
-                    ) {
-                        candidate.strip_nops();
-                        candidates.push(candidate.clone());
-                        if !self.options.opti {
-                            continue;
-                        }
+                let mut func_candidates: Vec<wasm::Candidate> = Vec::new();
+                if let Some(mut candidate) = self.invoke_search(
+                    &mut rng,
+                    func_name,
+                    func_type,
+                    func_body,
+                    Mode::Synthesis,
+                    None,
+                ) {
+                    candidate.strip_nops();
+                    func_candidates.push(candidate.clone());
 
-                        if let Some(mut candidate) = self.invoke_search(
+                    if self.options.opti {
+                        if let Some(mut optimized) = self.invoke_search(
+                            &mut rng,
                             func_name,
                             func_type,
                             func_body,
-                            &self.options,
                             Mode::Optimization,
+                            Some(&candidate),
                         ) {
-                            candidate.strip_nops();
-                            candidates.push(candidate.clone());
+                            optimized.strip_nops();
+                            func_candidates.push(optimized);
                         }
                     }
                 }
+
+                // Scratch locals follow the parameters and the locals of the spec.
+                let num_locals = func_type.params().len()
+                    + func_body
+                        .locals()
+                        .iter()
+                        .map(|local| local.count() as usize)
+                        .sum::<usize>();
+                let scratch_local_types = self.options.scratch_local_types();
+                let func_candidates: Vec<(wasm::Candidate, Vec<Local>)> = func_candidates
+                    .into_iter()
+                    .map(|mut candidate| {
+                        let kept =
+                            candidate.drop_unused_locals(num_locals as u32, &scratch_local_types);
+                        if !kept.is_empty() {
+                            println!(
+                                "Candidate {:?} of {} uses scratch locals {:?}",
+                                candidate.instrs(),
+                                func_name,
+                                kept
+                            );
+                        }
+                        let locals = func_body
+                            .locals()
+                            .iter()
+                            .cloned()
+                            .chain(kept.into_iter().map(|local_type| Local::new(1, local_type)))
+                            .collect();
+                        (candidate, locals)
+                    })
+                    .collect();
+
+                let medians: Vec<Option<Duration>> =
+                    if self.options.benchmark && !func_candidates.is_empty() {
+                        self.benchmark(&mut rng, func_name, func_type, &func_candidates)
+                            .into_iter()
+                            .map(Some)
+                            .collect()
+                    } else {
+                        vec![None; func_candidates.len()]
+                    };
+                // Median run times are measured on the test cases of each function, so candidates
+                // are only ranked against the ones of the same function.
+                let func_candidates: Vec<(wasm::Candidate, Option<Duration>)> = func_candidates
+                    .into_iter()
+                    .map(|(candidate, _)| candidate)
+                    .zip(medians)
+                    .collect();
+                rank(func_name, &func_candidates, &self.perf_model);
+            }
+        }
+    }
+
+    /// Benchmarks the spec and given verified candidates of a function over the test inputs, and
+    /// prints them ranked by median run time along with their speedups over the spec. Each
+    /// candidate comes with the locals of its function body. Returns the median run times of the
+    /// candidates in the given order.
+    fn benchmark(
+        &self,
+        rng: &mut StdRng,
+        func_name: &str,
+        func_type: &FunctionType,
+        candidates: &[(wasm::Candidate, Vec<Local>)],
+    ) -> Vec<Duration> {
+        let options = &self.options;
+        let mut interpreter = exec::get_interpreter(
+            rng,
+            options.interpreter_kind,
+            &self.spec,
+            func_name,
+            options.cache_size,
+            exec::Cost::new(options.cost_function, options.trap_penalty),
+            options.fuel,
+        );
+        for assertion in &self.assertions {
+            if assertion.func_name == func_name {
+                interpreter.add_test_case(assertion.input.clone());
             }
         }
 
-        rank(&candidates);
+        let spec_summary =
+            Summary::new(&interpreter.benchmark(None, options.bench_warmup, options.bench_samples));
+        println!("Benchmark {} spec: {}", func_name, spec_summary);
+
+        let mut summaries: Vec<(&wasm::Candidate, Summary)> = candidates
+            .iter()
+            .map(|(candidate, locals)| {
+                let mut instrs = candidate.instrs().to_vec();
+                instrs.push(Instruction::End);
+                let binary = parity_wasm_utils::build_module(
+                    "candidate",
+                    func_type,
+                    FuncBody::new(locals.clone(), Instructions::new(instrs)),
+                )
+                .to_bytes()
+                .unwrap();
+                let samples = interpreter.benchmark(
+                    Some(&binary),
+                    options.bench_warmup,
+                    options.bench_samples,
+                );
+                (candidate, Summary::new(&samples))
+            })
+            .collect();
+        let medians = summaries
+            .iter()
+            .map(|(_, summary)| summary.median)
+            .collect();
+        summaries.sort_by_key(|(_, summary)| summary.median);
+
+        for (candidate, summary) in summaries {
+            println!(
+                "Benchmark {} {:?}: {}, speedup {:.2}x",
+                func_name,
+                candidate.instrs(),
+                summary,
+                summary.speedup(&spec_summary)
+            );
+        }
+        medians
     }
 
+    /// Runs the search on worker threads, each with its own interpreter and Z3 context, and
+    /// returns the cheapest candidate found by them. `synthesized` is the result of the synthesis
+    /// step in optimization mode.
     fn invoke_search(
         &self,
+        rng: &mut StdRng,
         func_name: &str,
         func_type: &FunctionType,
         func_body: &FuncBody,
-        options: &SuperoptimizerOpts,
         mode: Mode,
+        synthesized: Option<&wasm::Candidate>,
     ) -> Option<wasm::Candidate> {
-        // NOTE(taegyunkim): Interpreter is not thread safe.
-        let mut interpreter =
-            exec::get_interpreter(options.interpreter_kind, &self.spec, func_name);
-
-        let mut spec = wasm::Spec::new(func_type, func_body);
-
-        let cfg = z3::Config::new();
-        let ctx = z3::Context::new(&cfg);
-        let z3_solver = solver::Z3Solver::new(&ctx, func_type, func_body);
-
-        // Timer to terminate the search after given computing budget.
-        let timer = timer::Timer::new();
-        let (tx, rx) = std::sync::mpsc::channel();
-        // It's necessary to name this variable to trigger the callback.
-        let _guard =
-            timer.schedule_with_delay(chrono::Duration::minutes(options.time_budget), move || {
-                let _ = tx.send(());
-            });
-
-        match &options.algorithm {
-            Algorithm::Stoke(stoke_options) => stoke::search(
-                options,
-                stoke_options,
-                mode,
-                &rx,
-                &z3_solver,
-                interpreter.as_mut(),
-                &mut spec,
-            ),
-            Algorithm::Enumerative => {
-                enumerative::search(options, &rx, &z3_solver, interpreter.as_mut(), &mut spec)
+        let options = &self.options;
+        // The interpreters only generate i32 test case inputs and compare i32 outputs, and so do
+        // the searches that evaluate test cases without an interpreter, or solve for constants.
+        // Assertions of other values are parsed, but their functions are rejected here.
+        if !exec::is_i32_only(func_type) {
+            log::error!(
+                "The search only supports functions of i32 values, {} has type {:?}.",
+                func_name,
+                func_type
+            );
+            return None;
+        }
+        if let Algorithm::Stoke(stoke_options) = &options.algorithm {
+            // Synthesis would verify the spec itself as soon as a chain restarts from it.
+            if mode == Mode::Synthesis && stoke_options.restart_from == stoke::RestartFrom::Spec {
+                log::error!("--restart-from Spec isn't supported by the synthesis step.");
+                return None;
+            }
+            let from_spec = stoke_options.init == stoke::Init::Spec
+                || stoke_options.restart_from == stoke::RestartFrom::Spec;
+            if mode == Mode::Optimization && from_spec {
+                if let Some(error) = spec_candidate_error(options, stoke_options, func_body) {
+                    log::error!(
+                        "Chains can't start or restart from the spec of {}, {}.",
+                        func_name,
+                        error
+                    );
+                    return None;
+                }
+            }
+        }
+
+        let num_workers = match options.algorithm {
+            Algorithm::Stoke(_) => options.num_workers.unwrap_or_else(num_cpus::get),
+            Algorithm::Enumerative(ref enumerative_options)
+                if enumerative_options.strategy == enumerative::Strategy::TopDown =>
+            {
+                options.num_workers.unwrap_or_else(num_cpus::get)
             }
+            Algorithm::Enumerative(_) | Algorithm::Cegis(_) | Algorithm::Components(_) => 1,
+        };
+        let seeds: Vec<u64> = (0..num_workers).map(|_| rng.gen()).collect();
+
+        // Workers outlive this function call, so they get their own copies.
+        let spec_binary = self.spec.clone();
+        let func_name = String::from(func_name);
+        let func_type = func_type.clone();
+        let func_body = func_body.clone();
+        let mut options = options.clone();
+        if options.mine_constants {
+            let mut mined = Vec::new();
+            for constant in wasm::mine_constants(func_body.code().elements()) {
+                if mined.len() == wasm::MAX_MINED_CONSTANTS {
+                    break;
+                }
+                if !options.constants.contains(&constant) {
+                    options.constants.push(constant);
+                    mined.push(constant);
+                }
+            }
+            if !mined.is_empty() {
+                log::info!("Constants mined from {}: {:?}", func_name, mined);
+            }
+        }
+        let perf_model = self.perf_model.clone();
+        let synthesized = synthesized.cloned();
+        let assertions: Vec<exec::Assertion> = self
+            .assertions
+            .iter()
+            .filter(|assertion| assertion.func_name == func_name)
+            .cloned()
+            .collect();
+
+        let time_budget = chrono::Duration::minutes(options.time_budget);
+        let candidates = worker::run(&seeds, time_budget, mode, move |worker| {
+            search_worker(
+                worker,
+                &spec_binary,
+                &func_name,
+                &func_type,
+                &func_body,
+                &assertions,
+                &options,
+                mode,
+                synthesized.as_ref(),
+                &perf_model,
+            )
+        });
+
+        candidates
+            .into_iter()
+            .min_by_key(|candidate| self.perf_model.cost(candidate.instrs()))
+    }
+}
+
+/// Returns why stochastic search chains can't start or restart from given spec body, if they
+/// can't. The spec would seed them with instructions transforms can't produce, or with more
+/// instructions than candidates can have.
+fn spec_candidate_error(
+    options: &SuperoptimizerOpts,
+    stoke_options: &StokeOpts,
+    func_body: &FuncBody,
+) -> Option<String> {
+    let instrs = func_body.code().elements();
+    // Without the closing `end`.
+    let instrs = &instrs[..instrs.len() - 1];
+    if let Some(max_length) = stoke_options.max_length {
+        if instrs.len() > max_length {
+            return Some(format!(
+                "it has {} instructions, more than --max-length {}",
+                instrs.len(),
+                max_length
+            ));
         }
     }
+    let whitelisted = if options.opcodes.is_empty() {
+        wasm::supported_opcodes()
+    } else {
+        options.opcodes.clone()
+    };
+    let excluded: Vec<String> = instrs
+        .iter()
+        .map(perf::opcode_name)
+        .filter(|opcode| !whitelisted.contains(opcode))
+        .collect();
+    if !excluded.is_empty() {
+        return Some(format!(
+            "it uses opcodes {:?} outside the whitelist",
+            excluded
+        ));
+    }
+    None
 }
 
-pub fn rank(candidates: &[wasm::Candidate]) {
-    println!("Found {} programs", candidates.len());
+/// Runs the search of one worker, and returns the verified candidate it found if it passes all
+/// assertions.
+#[allow(clippy::too_many_arguments)]
+fn search_worker(
+    worker: &mut worker::Worker,
+    spec_binary: &[u8],
+    func_name: &str,
+    func_type: &FunctionType,
+    func_body: &FuncBody,
+    assertions: &[exec::Assertion],
+    options: &SuperoptimizerOpts,
+    mode: Mode,
+    synthesized: Option<&wasm::Candidate>,
+    perf_model: &dyn PerfModel,
+) -> Option<wasm::Candidate> {
+    // NOTE(taegyunkim): Interpreter is not thread safe, so each worker creates its own.
+    let mut interpreter = exec::get_interpreter(
+        worker.rng(),
+        options.interpreter_kind,
+        spec_binary,
+        func_name,
+        options.cache_size,
+        exec::Cost::new(options.cost_function, options.trap_penalty),
+        options.fuel,
+    );
+    for assertion in assertions {
+        interpreter.add_test_case(assertion.input.clone());
+    }
 
-    let best = candidates
-        .iter()
-        .min_by(|a, b| perf(a.instrs()).cmp(&perf(b.instrs())))
-        .unwrap();
+    let scratch_local_types = options.scratch_local_types();
+    let mut spec = wasm::Spec::new(func_type, func_body, options.fuel, &scratch_local_types);
 
-    println!("{:?}", best.instrs());
-}
+    let cfg = z3::Config::new();
+    let ctx = z3::Context::new(&cfg);
+    let z3_solver =
+        solver::Z3Solver::new(&ctx, func_type, func_body).with_scratch_locals(&scratch_local_types);
+
+    let mut checkpointer = options.checkpoint_dir.as_ref().map(|checkpoint_dir| {
+        checkpoint::Checkpointer::new(
+            checkpoint::checkpoint_path(checkpoint_dir, func_name, mode, worker.id()),
+            std::time::Duration::from_secs(options.checkpoint_interval),
+            options.resume,
+            checkpoint::Origin::new(spec_binary, func_name, &options.algorithm),
+        )
+    });
+
+    let result = match &options.algorithm {
+        Algorithm::Stoke(stoke_options) => stoke::search(
+            options,
+            stoke_options,
+            mode,
+            synthesized,
+            perf_model,
+            worker,
+            checkpointer.as_mut(),
+            &z3_solver,
+            interpreter.as_mut(),
+            &mut spec,
+        ),
+        Algorithm::Enumerative(enumerative_options) => enumerative::search(
+            options,
+            enumerative_options,
+            perf_model,
+            worker,
+            checkpointer.as_mut(),
+            &z3_solver,
+            interpreter.as_mut(),
+            &mut spec,
+        ),
+        Algorithm::Cegis(cegis_options) => cegis::search(
+            options,
+            cegis_options,
+            worker,
+            checkpointer.as_mut(),
+            &z3_solver,
+            interpreter.as_mut(),
+            &mut spec,
+        ),
+        Algorithm::Components(components_options) => components::search(
+            options,
+            components_options,
+            worker,
+            checkpointer.as_mut(),
+            &z3_solver,
+            interpreter.as_mut(),
+            &mut spec,
+        ),
+    };
+
+    log::info!(
+        "{:?} worker {} {:?}",
+        mode,
+        worker.id(),
+        interpreter.cache_stats()
+    );
 
-pub fn perf(instrs: &[Instruction]) -> u32 {
-    let mut cnt = 0;
-    for instr in instrs {
-        if *instr != Instruction::Nop {
-            cnt += 1;
+    result.filter(|candidate| {
+        let binary = spec.get_binary_with_instrs(candidate.instrs());
+        for assertion in assertions {
+            if !interpreter.check_assertion(binary, assertion) {
+                println!(
+                    "Verified candidate {:?} fails assertion {:?}",
+                    candidate.instrs(),
+                    assertion
+                );
+                return false;
+            }
         }
+        true
+    })
+}
+
+/// Prints the best of given candidates of a function. Benchmarked candidates, the ones with a
+/// median run time, are ranked by it, ties and the others by given performance model.
+pub fn rank(
+    func_name: &str,
+    candidates: &[(wasm::Candidate, Option<Duration>)],
+    perf_model: &dyn PerfModel,
+) {
+    println!("Found {} programs for {}", candidates.len(), func_name);
+
+    let best = candidates.iter().min_by_key(|(candidate, median)| {
+        (
+            median.is_none(),
+            *median,
+            perf_model.cost(candidate.instrs()),
+        )
+    });
+    if let Some((best, _)) = best {
+        println!("{:?}", best.instrs());
     }
-    cnt
 }
@@ -1,25 +1,76 @@
 extern crate rocinante;
 
+use rocinante::exec::{Assertion, Expected};
 use std::path::Path;
 use structopt::StructOpt;
 
-fn parse_module_from_wast(file: impl AsRef<Path>) -> Vec<Vec<u8>> {
-    let contents = std::fs::read_to_string(file).unwrap();
-    let buf = wast::parser::ParseBuffer::new(&contents).unwrap();
+/// Converts a constant expression in a `.wast` invocation to a value.
+fn to_value(expr: &wast::Expression) -> wasmer_runtime::Value {
+    match expr.instrs.as_slice() {
+        [wast::Instruction::I32Const(x)] => wasmer_runtime::Value::I32(*x),
+        [wast::Instruction::I64Const(x)] => wasmer_runtime::Value::I64(*x),
+        unsupported => panic!("{:?} argument not supported.", unsupported),
+    }
+}
+
+fn to_assertion(exec: &wast::WastExecute, expected: Expected) -> Option<Assertion> {
+    match exec {
+        wast::WastExecute::Invoke(invoke) => Some(Assertion {
+            func_name: String::from(invoke.name),
+            input: invoke.args.iter().map(to_value).collect(),
+            expected,
+        }),
+        _ => None,
+    }
+}
+
+/// Returns the modules in given `.wast` file, each paired with the `assert_return` and
+/// `assert_trap` directives that follow it.
+fn parse_module_from_wast(file: impl AsRef<Path>) -> Vec<(Vec<u8>, Vec<Assertion>)> {
+    parse_wast(&std::fs::read_to_string(file).unwrap())
+}
+
+/// See [parse_module_from_wast](parse_module_from_wast).
+fn parse_wast(contents: &str) -> Vec<(Vec<u8>, Vec<Assertion>)> {
+    let buf = wast::parser::ParseBuffer::new(contents).unwrap();
     let wast = wast::parser::parse::<wast::Wast>(&buf).unwrap();
 
-    let mut modules: Vec<Vec<u8>> = Vec::new();
+    let mut modules: Vec<(Vec<u8>, Vec<Assertion>)> = Vec::new();
     for directive in wast.directives {
         // NOTE(taegyunkim): Other directives can have modules in them, but
         // we're ingorning them for now.
-        if let wast::WastDirective::Module(mut module) = directive {
-            modules.push(module.encode().unwrap());
+        let assertion = match directive {
+            wast::WastDirective::Module(mut module) => {
+                modules.push((module.encode().unwrap(), Vec::new()));
+                continue;
+            }
+            wast::WastDirective::AssertReturn { exec, results, .. } => {
+                let values = results
+                    .iter()
+                    .map(|result| match result {
+                        wast::AssertExpression::I32(x) => wasmer_runtime::Value::I32(*x),
+                        wast::AssertExpression::I64(x) => wasmer_runtime::Value::I64(*x),
+                        unsupported => panic!("{:?} result not supported.", unsupported),
+                    })
+                    .collect();
+                to_assertion(&exec, Expected::Return(values))
+            }
+            wast::WastDirective::AssertTrap { exec, .. } => to_assertion(&exec, Expected::Trap),
+            _ => None,
+        };
+
+        // Assertions apply to the most recently defined module.
+        if let (Some(assertion), Some((_, assertions))) = (assertion, modules.last_mut()) {
+            assertions.push(assertion);
         }
     }
     modules
 }
 
 fn main() {
+    // Progress reports are logged at info level, set RUST_LOG to change it.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
     let options = rocinante::SuperoptimizerOpts::from_args();
 
     // Parse the extension of the input file.
@@ -30,9 +81,9 @@ fn main() {
         .unwrap();
 
     // Read the input file into binary format.
-    let binaries: Vec<Vec<u8>> = match ext {
-        "wasm" => vec![std::fs::read(&options.input).unwrap()],
-        "wat" => vec![wat::parse_file(&options.input).unwrap()],
+    let binaries: Vec<(Vec<u8>, Vec<Assertion>)> = match ext {
+        "wasm" => vec![(std::fs::read(&options.input).unwrap(), Vec::new())],
+        "wat" => vec![(wat::parse_file(&options.input).unwrap(), Vec::new())],
         "wast" => parse_module_from_wast(&options.input),
         _ => panic!(
             "{}: unrecognized file type",
@@ -41,7 +92,7 @@ fn main() {
     };
 
     // TODO(taegyunkim): Parallel processing of different binaries.
-    for binary in binaries {
+    for (binary, assertions) in binaries {
         // Validate raw binary.
         wasmparser::validate(&binary, None /* Uses default parser config */)
             .expect("Failed to validate.");
@@ -51,7 +102,82 @@ fn main() {
             wasmprinter::print_bytes(&binary).expect("Failed to convert to .wat")
         );
         // TODO(taegyunkim): Propagate the template function.
-        let optimizer = rocinante::Superoptimizer::new(binary, options.clone());
+        let optimizer = rocinante::Superoptimizer::new(binary, assertions, options.clone());
         optimizer.run();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmer_runtime::Value;
+
+    #[test]
+    fn to_assertion_test() {
+        let contents = r#"(assert_trap (invoke "f" (i32.const 1) (i64.const -2)) "unreachable")"#;
+        let buf = wast::parser::ParseBuffer::new(contents).unwrap();
+        let wast = wast::parser::parse::<wast::Wast>(&buf).unwrap();
+        let exec = match &wast.directives[0] {
+            wast::WastDirective::AssertTrap { exec, .. } => exec,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            to_assertion(exec, Expected::Trap),
+            Some(Assertion {
+                func_name: String::from("f"),
+                input: vec![Value::I32(1), Value::I64(-2)],
+                expected: Expected::Trap,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_wast_test() {
+        let modules = parse_wast(
+            r#"
+            (module
+              (func (export "add") (param i32 i32) (result i32)
+                local.get 0
+                local.get 1
+                i32.add))
+            (assert_return (invoke "add" (i32.const 1) (i32.const 2)) (i32.const 3))
+            (module
+              (func (export "div") (param i64 i64) (result i64)
+                local.get 0
+                local.get 1
+                i64.div_s))
+            (assert_return (invoke "div" (i64.const 6) (i64.const 3)) (i64.const 2))
+            (assert_trap (invoke "div" (i64.const 1) (i64.const 0)) "integer divide by zero")
+            "#,
+        );
+
+        assert_eq!(modules.len(), 2);
+        // Assertions belong to the module defined right before them.
+        assert_eq!(
+            modules[0].1,
+            vec![Assertion {
+                func_name: String::from("add"),
+                input: vec![Value::I32(1), Value::I32(2)],
+                expected: Expected::Return(vec![Value::I32(3)]),
+            }]
+        );
+        assert_eq!(
+            modules[1].1,
+            vec![
+                Assertion {
+                    func_name: String::from("div"),
+                    input: vec![Value::I64(6), Value::I64(3)],
+                    expected: Expected::Return(vec![Value::I64(2)]),
+                },
+                Assertion {
+                    func_name: String::from("div"),
+                    input: vec![Value::I64(1), Value::I64(0)],
+                    expected: Expected::Trap,
+                },
+            ]
+        );
+        for (binary, _) in &modules {
+            wasmparser::validate(binary, None).unwrap();
+        }
+    }
+}
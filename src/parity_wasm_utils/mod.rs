@@ -1,7 +1,15 @@
 use parity_wasm::elements::{
-    ExportEntry, ExportSection, Func, FuncBody, FunctionType, Internal, Module, Type,
+    BlockType, Deserialize, ExportEntry, ExportSection, Func, FuncBody, FunctionType, Instruction,
+    Internal, Module, Serialize, Type,
 };
 
+/// Name of the exported global that holds the remaining fuel of a candidate function.
+pub const FUEL_GLOBAL: &str = "fuel";
+
+/// Value the fuel global is set to right before a function traps for running out of fuel. The
+/// fuel is never negative otherwise, so traps of the function itself can't be mistaken for it.
+pub const OUT_OF_FUEL: i32 = -1;
+
 pub fn export_by_name(export_section: &ExportSection, name: &str) -> Option<ExportEntry> {
     for entry in export_section.entries() {
         if entry.field() == name {
@@ -87,6 +95,135 @@ pub fn build_module(func_name: &str, func_type: &FunctionType, func_body: FuncBo
     module
 }
 
+/// Same as [build_module](build_module), but also defines a mutable i32 global initialized to
+/// given fuel and exports it as [FUEL_GLOBAL](FUEL_GLOBAL). The global has index 0, use
+/// [inject_fuel](inject_fuel) to make the function consume it. The fuel must fit in an i32.
+pub fn build_module_with_fuel(
+    func_name: &str,
+    func_type: &FunctionType,
+    func_body: FuncBody,
+    fuel: u32,
+) -> Module {
+    #[rustfmt::skip]
+    let module = parity_wasm::builder::module()
+        .global()
+            .value_type().i32()
+            .mutable()
+            .init_expr(Instruction::I32Const(fuel_to_i32(fuel)))
+            .build()
+        .export()
+            .field(FUEL_GLOBAL)
+            .internal()
+            .global(0)
+            .build()
+        .export()
+            .field(func_name)
+            .internal()
+            .func(0)
+            .build()
+        .function()
+            .signature()
+                .with_params(func_type.params().to_vec())
+                .with_return_type(func_type.return_type())
+                .build()
+            .body()
+                .with_func(func_body)
+                .build()
+            .build()
+        .build();
+
+    module
+}
+
+/// Returns given fuel as the value of the fuel global, panics if it doesn't fit.
+pub fn fuel_to_i32(fuel: u32) -> i32 {
+    assert!(
+        fuel <= i32::max_value() as u32,
+        "Fuel must be at most {}, got {}.",
+        i32::max_value(),
+        fuel
+    );
+    fuel as i32
+}
+
+/// Returns the instructions that trap if the fuel global is zero, and decrement it otherwise.
+/// The global is set to [OUT_OF_FUEL](OUT_OF_FUEL) before trapping.
+fn consume_fuel(global_idx: u32) -> Vec<Instruction> {
+    vec![
+        Instruction::GetGlobal(global_idx),
+        Instruction::I32Eqz,
+        Instruction::If(BlockType::NoResult),
+        Instruction::I32Const(OUT_OF_FUEL),
+        Instruction::SetGlobal(global_idx),
+        Instruction::Unreachable,
+        Instruction::End,
+        Instruction::GetGlobal(global_idx),
+        Instruction::I32Const(1),
+        Instruction::I32Sub,
+        Instruction::SetGlobal(global_idx),
+    ]
+}
+
+/// Instruments given instructions to consume one unit of fuel on function entry and at the
+/// start of every loop iteration, so that a function with loops or (recursive) calls traps once
+/// the fuel global at given index runs out instead of running forever.
+pub fn inject_fuel(instrs: &[Instruction], global_idx: u32) -> Vec<Instruction> {
+    let mut instrumented = consume_fuel(global_idx);
+    for instr in instrs {
+        instrumented.push(instr.clone());
+        if let Instruction::Loop(_) = instr {
+            instrumented.extend(consume_fuel(global_idx));
+        }
+    }
+    instrumented
+}
+
+/// Encodes given instructions in the binary format.
+pub fn instrs_to_bytes(instrs: &[Instruction]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for instr in instrs {
+        instr
+            .clone()
+            .serialize(&mut bytes)
+            .expect("Failed to serialize instruction.");
+    }
+    bytes
+}
+
+/// Decodes instructions encoded by [instrs_to_bytes](instrs_to_bytes).
+pub fn instrs_from_bytes(bytes: &[u8]) -> Vec<Instruction> {
+    let mut reader = std::io::Cursor::new(bytes);
+    let mut instrs = Vec::new();
+    while (reader.position() as usize) < bytes.len() {
+        instrs.push(
+            Instruction::deserialize(&mut reader).expect("Failed to deserialize instruction."),
+        );
+    }
+    instrs
+}
+
+/// Serializes instructions in the binary format, for fields marked with
+/// `#[serde(with = "serde_instrs")]`.
+pub mod serde_instrs {
+    use super::{instrs_from_bytes, instrs_to_bytes};
+    use parity_wasm::elements::Instruction;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        instrs: &[Instruction],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&instrs_to_bytes(instrs))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Instruction>, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        Ok(instrs_from_bytes(&bytes))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -127,6 +264,94 @@ mod tests {
         );
     }
 
+    fn remaining_fuel(instance: &wasmi::ModuleRef) -> wasmi::RuntimeValue {
+        instance
+            .export_by_name(FUEL_GLOBAL)
+            .and_then(|export| export.as_global().cloned())
+            .expect("Fuel global is not exported.")
+            .get()
+    }
+
+    #[test]
+    fn inject_fuel_test() {
+        let func_type = FunctionType::new(vec![ValueType::I32], Some(ValueType::I32));
+
+        // Straight line code consumes one unit of fuel on entry.
+        let instrs = inject_fuel(
+            &[
+                Instruction::GetLocal(0),
+                Instruction::GetLocal(0),
+                Instruction::I32Add,
+                Instruction::End,
+            ],
+            0,
+        );
+        let func_body = FuncBody::new(vec![], Instructions::new(instrs));
+        let instance = instantiate(build_module_with_fuel("add", &func_type, func_body, 10));
+        assert_eq!(
+            instance
+                .invoke_export(
+                    "add",
+                    &[wasmi::RuntimeValue::I32(3)],
+                    &mut wasmi::NopExternals,
+                )
+                .expect("failed to execute the function"),
+            Some(wasmi::RuntimeValue::I32(6))
+        );
+        assert_eq!(remaining_fuel(&instance), wasmi::RuntimeValue::I32(9));
+
+        // An infinite loop traps once it runs out of fuel.
+        let instrs = inject_fuel(
+            &[
+                Instruction::Loop(BlockType::NoResult),
+                Instruction::Br(0),
+                Instruction::End,
+                Instruction::GetLocal(0),
+                Instruction::End,
+            ],
+            0,
+        );
+        let func_body = FuncBody::new(vec![], Instructions::new(instrs));
+        let instance = instantiate(build_module_with_fuel("spin", &func_type, func_body, 10));
+        assert!(instance
+            .invoke_export(
+                "spin",
+                &[wasmi::RuntimeValue::I32(3)],
+                &mut wasmi::NopExternals,
+            )
+            .is_err());
+        assert_eq!(
+            remaining_fuel(&instance),
+            wasmi::RuntimeValue::I32(OUT_OF_FUEL)
+        );
+
+        // Traps of the function itself leave the fuel alone, even when it's used up.
+        let instrs = inject_fuel(&[Instruction::Unreachable, Instruction::End], 0);
+        let func_body = FuncBody::new(vec![], Instructions::new(instrs));
+        let instance = instantiate(build_module_with_fuel("trap", &func_type, func_body, 1));
+        assert!(instance
+            .invoke_export(
+                "trap",
+                &[wasmi::RuntimeValue::I32(3)],
+                &mut wasmi::NopExternals,
+            )
+            .is_err());
+        assert_eq!(remaining_fuel(&instance), wasmi::RuntimeValue::I32(0));
+    }
+
+    #[test]
+    fn instrs_bytes_test() {
+        let instrs = vec![
+            Instruction::GetLocal(1),
+            Instruction::I32Const(-7),
+            Instruction::Nop,
+            Instruction::I32Add,
+            Instruction::I64Const(1 << 40),
+        ];
+        assert_eq!(instrs_from_bytes(&instrs_to_bytes(&instrs)), instrs);
+        assert!(instrs_from_bytes(&[]).is_empty());
+    }
+
     #[test]
     fn build_module_empty() {
         let func_type = FunctionType::new(vec![ValueType::I32], Some(ValueType::I32));
@@ -0,0 +1,85 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Statistical summary of the timed samples of a benchmark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub min: Duration,
+    pub median: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+    pub std_dev: Duration,
+}
+
+impl Summary {
+    pub fn new(samples: &[Duration]) -> Self {
+        assert!(!samples.is_empty(), "No samples to summarize.");
+
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        let secs: Vec<f64> = sorted.iter().map(Duration::as_secs_f64).collect();
+        let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+        let variance = secs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / secs.len() as f64;
+        let mid = sorted.len() / 2;
+        let median = if sorted.len() % 2 == 0 {
+            (sorted[mid - 1] + sorted[mid]) / 2
+        } else {
+            sorted[mid]
+        };
+
+        Self {
+            min: sorted[0],
+            median,
+            mean: Duration::from_secs_f64(mean),
+            max: sorted[sorted.len() - 1],
+            std_dev: Duration::from_secs_f64(variance.sqrt()),
+        }
+    }
+
+    /// How many times faster this is than given baseline, comparing medians. Medians too short to
+    /// measure count as infinitely fast, or as fast as the baseline if both are.
+    pub fn speedup(&self, baseline: &Summary) -> f64 {
+        let zero = Duration::from_secs(0);
+        match (baseline.median == zero, self.median == zero) {
+            (true, true) => 1.0,
+            (false, true) => std::f64::INFINITY,
+            _ => baseline.median.as_secs_f64() / self.median.as_secs_f64(),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "median {:?}, mean {:?} ± {:?}, min {:?}, max {:?}",
+            self.median, self.mean, self.std_dev, self.min, self.max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_test() {
+        let samples: Vec<Duration> = [4, 1, 3, 2]
+            .iter()
+            .map(|&ms| Duration::from_millis(ms))
+            .collect();
+        let summary = Summary::new(&samples);
+        assert_eq!(summary.min, Duration::from_millis(1));
+        assert_eq!(summary.max, Duration::from_millis(4));
+        assert_eq!(summary.median, Duration::from_micros(2500));
+        assert!((summary.mean.as_secs_f64() - 0.0025).abs() < 1e-9);
+
+        let baseline = Summary::new(&[Duration::from_millis(5)]);
+        assert!((summary.speedup(&baseline) - 2.0).abs() < 1e-9);
+
+        let instant = Summary::new(&[Duration::from_secs(0)]);
+        assert!(instant.speedup(&baseline).is_infinite());
+        assert!((instant.speedup(&instant) - 1.0).abs() < 1e-9);
+        assert!(baseline.speedup(&instant).abs() < 1e-9);
+    }
+}
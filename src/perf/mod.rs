@@ -0,0 +1,173 @@
+use crate::wasm;
+use parity_wasm::elements::Instruction;
+use std::collections::HashMap;
+use std::mem::{discriminant, Discriminant};
+use std::path::Path;
+
+pub mod bench;
+
+/// Latency of opcodes missing from a [LatencyTable](LatencyTable).
+const DEFAULT_LATENCY: u32 = 1;
+
+/// Latencies in cycles of the code cranelift and singlepass generate for each opcode on x86-64.
+/// Locals and constants are assumed to live in registers. Immediates are ignored.
+const X86_64_LATENCIES: [(Instruction, u32); 34] = [
+    (Instruction::Nop, 0),
+    (Instruction::GetLocal(0), 1),
+    (Instruction::SetLocal(0), 1),
+    (Instruction::TeeLocal(0), 1),
+    (Instruction::I32Const(0), 1),
+    (Instruction::I32Add, 1),
+    (Instruction::I32Sub, 1),
+    (Instruction::I32Mul, 3),
+    (Instruction::I32DivS, 26),
+    (Instruction::I32DivU, 26),
+    (Instruction::I32RemS, 26),
+    (Instruction::I32RemU, 26),
+    (Instruction::I32And, 1),
+    (Instruction::I32Or, 1),
+    (Instruction::I32Xor, 1),
+    (Instruction::I32Shl, 2),
+    (Instruction::I32ShrS, 2),
+    (Instruction::I32ShrU, 2),
+    (Instruction::I32Rotl, 2),
+    (Instruction::I32Rotr, 2),
+    (Instruction::I32Clz, 3),
+    (Instruction::I32Ctz, 3),
+    (Instruction::I32Popcnt, 3),
+    (Instruction::I32Eqz, 2),
+    (Instruction::I32Eq, 2),
+    (Instruction::I32Ne, 2),
+    (Instruction::I32LtS, 2),
+    (Instruction::I32LtU, 2),
+    (Instruction::I32GtS, 2),
+    (Instruction::I32GtU, 2),
+    (Instruction::I32LeS, 2),
+    (Instruction::I32LeU, 2),
+    (Instruction::I32GeS, 2),
+    (Instruction::I32GeU, 2),
+];
+
+/// Estimates how expensive a sequence of instructions is to execute.
+pub trait PerfModel: Send + Sync {
+    fn cost(&self, instrs: &[Instruction]) -> u32;
+}
+
+/// Returns the opcode name of given instruction as printed in the text format, without operands.
+pub fn opcode_name(instr: &Instruction) -> String {
+    let text = instr.to_string();
+    text.split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Returns an instruction with given opcode, written as [opcode_name](opcode_name) prints it or
+/// as in the text format.
+fn parse_opcode(opcode: &str) -> Option<Instruction> {
+    X86_64_LATENCIES
+        .iter()
+        .map(|(instr, _)| instr)
+        .find(|instr| opcode_name(instr) == opcode)
+        .cloned()
+        .or_else(|| wasm::parse_opcode(opcode))
+}
+
+/// A performance model that sums per opcode latencies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyTable {
+    /// Keyed by the opcode, the variant of the instruction regardless of its immediates.
+    latencies: HashMap<Discriminant<Instruction>, u32>,
+}
+
+impl Default for LatencyTable {
+    fn default() -> Self {
+        Self {
+            latencies: X86_64_LATENCIES
+                .iter()
+                .map(|(instr, latency)| (discriminant(instr), *latency))
+                .collect(),
+        }
+    }
+}
+
+impl LatencyTable {
+    /// Parses a table with one opcode and its latency separated by whitespace on each line, e.g.
+    /// `i32.div_u 26`. Empty lines and lines starting with `#` are ignored. Opcodes missing from
+    /// given table keep their default latencies. Panics on unknown opcodes.
+    pub fn parse(contents: &str) -> Self {
+        let mut table = Self::default();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [opcode, latency] => {
+                    let instr = parse_opcode(opcode)
+                        .unwrap_or_else(|| panic!("Unknown opcode in line: {}", line));
+                    let latency = latency
+                        .parse()
+                        .unwrap_or_else(|_| panic!("Invalid latency in line: {}", line));
+                    table.latencies.insert(discriminant(&instr), latency);
+                }
+                _ => panic!("Expected an opcode and a latency in line: {}", line),
+            }
+        }
+        table
+    }
+
+    pub fn from_file(file: impl AsRef<Path>) -> Self {
+        let contents = std::fs::read_to_string(file).expect("Failed to read latency table.");
+        Self::parse(&contents)
+    }
+
+    pub fn latency(&self, instr: &Instruction) -> u32 {
+        self.latencies
+            .get(&discriminant(instr))
+            .cloned()
+            .unwrap_or(DEFAULT_LATENCY)
+    }
+}
+
+impl PerfModel for LatencyTable {
+    fn cost(&self, instrs: &[Instruction]) -> u32 {
+        instrs
+            .iter()
+            .fold(0u32, |cost, instr| cost.saturating_add(self.latency(instr)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_table_test() {
+        let table = LatencyTable::parse(
+            "
+            # Division is cheap on this machine.
+            i32.div_u 2
+            get_local 0
+            i64.add 4
+            local.set 5
+            ",
+        );
+
+        assert_eq!(opcode_name(&Instruction::GetLocal(3)), "get_local");
+        assert_eq!(table.latency(&Instruction::I32DivU), 2);
+        assert_eq!(table.latency(&Instruction::I32DivS), 26);
+        assert_eq!(table.latency(&Instruction::I64Add), 4);
+        assert_eq!(table.latency(&Instruction::SetLocal(1)), 5);
+        assert_eq!(table.latency(&Instruction::I64Mul), DEFAULT_LATENCY);
+
+        assert_eq!(
+            table.cost(&[
+                Instruction::GetLocal(0),
+                Instruction::Nop,
+                Instruction::I32Const(3),
+                Instruction::I32DivU,
+            ]),
+            3
+        );
+    }
+}
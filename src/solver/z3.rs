@@ -114,6 +114,48 @@ fn popcnt<'a>(ctx: &'a Context, input: &ast::BV<'a>) -> ast::BV<'a> {
         .fold(ast::BV::from_u64(ctx, 0, bit_width), |acc, x| acc.bvadd(&x))
 }
 
+/// Returns whether [step](Converter::step) can apply given instruction.
+pub fn is_supported(instr: &Instruction) -> bool {
+    match instr {
+        Instruction::I32Add
+        | Instruction::I32Sub
+        | Instruction::I32Mul
+        | Instruction::I32DivS
+        | Instruction::I32DivU
+        | Instruction::I32RemS
+        | Instruction::I32RemU
+        | Instruction::I32And
+        | Instruction::I32Or
+        | Instruction::I32Xor
+        | Instruction::I32Shl
+        | Instruction::I32ShrS
+        | Instruction::I32ShrU
+        | Instruction::I32Rotl
+        | Instruction::I32Rotr
+        | Instruction::GetLocal(_)
+        | Instruction::SetLocal(_)
+        | Instruction::TeeLocal(_)
+        | Instruction::I32Const(_)
+        | Instruction::I32Eq
+        | Instruction::I32Ne
+        | Instruction::I32LtS
+        | Instruction::I32LtU
+        | Instruction::I32GtS
+        | Instruction::I32GtU
+        | Instruction::I32LeS
+        | Instruction::I32LeU
+        | Instruction::I32GeS
+        | Instruction::I32GeU
+        | Instruction::I32Eqz
+        | Instruction::I32Clz
+        | Instruction::I32Ctz
+        | Instruction::I32Popcnt
+        | Instruction::Nop
+        | Instruction::End => true,
+        _ => false,
+    }
+}
+
 impl<'ctx> Converter<'ctx> {
     pub fn new(ctx: &'ctx Context, func_type: &FunctionType, locals: &[Local]) -> Self {
         let mut z3_params: Vec<ast::Dynamic<'ctx>> = Vec::with_capacity(func_type.params().len());
@@ -147,6 +189,17 @@ impl<'ctx> Converter<'ctx> {
         }
     }
 
+    /// Lets the converted instructions use scratch locals of given types, which follow the locals
+    /// of the spec and start at zero like them.
+    pub fn with_scratch_locals(mut self, local_types: &[ValueType]) -> Self {
+        self.local_types.extend_from_slice(local_types);
+        self
+    }
+
+    pub fn ctx(&self) -> &'ctx Context {
+        self.ctx
+    }
+
     pub fn bounds(&self) -> Vec<&ast::Dynamic<'ctx>> {
         self.z3_params.iter().collect::<Vec<&ast::Dynamic<'ctx>>>()
     }
@@ -176,232 +229,243 @@ impl<'ctx> Converter<'ctx> {
         locals
     }
 
+    /// Applies given instruction to the symbolic stack and locals. Panics on instructions that
+    /// aren't [supported](is_supported).
+    pub fn step(
+        &self,
+        instr: &Instruction,
+        stack: &mut ValueStack<'ctx>,
+        locals: &mut [ast::Dynamic<'ctx>],
+    ) {
+        match instr {
+            // I32 binops
+            Instruction::I32Add => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvadd(&rhs);
+                stack.push(res);
+            }
+            Instruction::I32Sub => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvsub(&rhs);
+                stack.push(res);
+            }
+            Instruction::I32Mul => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvmul(&rhs);
+                stack.push(res);
+            }
+            Instruction::I32DivS => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvsdiv(&rhs);
+                stack.push(res);
+            }
+            Instruction::I32DivU => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvudiv(&rhs);
+                stack.push(res);
+            }
+            Instruction::I32RemS => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvsrem(&rhs);
+                stack.push(res);
+            }
+            Instruction::I32RemU => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvurem(&rhs);
+                stack.push(res);
+            }
+            Instruction::I32And => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvand(&rhs);
+                stack.push(res);
+            }
+            Instruction::I32Or => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvor(&rhs);
+                stack.push(res);
+            }
+            Instruction::I32Xor => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvxor(&rhs);
+                stack.push(res);
+            }
+            Instruction::I32Shl => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                // NOTE(taegyunkim): The WASM spec tests performs shifts by modulo 32, ditto
+                // all shift and rotate instructions.
+                let shift_cnt = rhs.bvand(&ast::BV::from_i64(&self.ctx, 31, 32));
+                let res = lhs.bvshl(&shift_cnt);
+                stack.push(res);
+            }
+            Instruction::I32ShrS => {
+                // NOTE(taegyunkim): sign-replicating (arithmetic) shift right.
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let shift_cnt = rhs.bvand(&ast::BV::from_i64(&self.ctx, 31, 32));
+                let res = lhs.bvashr(&shift_cnt);
+                stack.push(res);
+            }
+            Instruction::I32ShrU => {
+                // NOTE(taegyunkim): zero-replicating (logical) shift right.
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let shift_cnt = rhs.bvand(&ast::BV::from_i64(&self.ctx, 31, 32));
+                let res = lhs.bvlshr(&shift_cnt);
+                stack.push(res);
+            }
+            Instruction::I32Rotl => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let rotate_cnt = rhs.bvand(&ast::BV::from_i64(&self.ctx, 31, 32));
+                let res = lhs.bvrotl(&rotate_cnt);
+                stack.push(res);
+            }
+            Instruction::I32Rotr => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let rotate_cnt = rhs.bvand(&ast::BV::from_i64(&self.ctx, 31, 32));
+                let res = lhs.bvrotr(&rotate_cnt);
+                stack.push(res);
+            }
+            // local variable ops
+            Instruction::GetLocal(idx) => {
+                let val = &locals[*idx as usize];
+                stack.push(val.clone());
+            }
+            Instruction::SetLocal(idx) => {
+                let val = stack.pop();
+                locals[*idx as usize] = val;
+            }
+            Instruction::TeeLocal(idx) => {
+                let val = stack.pop();
+                stack.push(val.clone());
+                // stack.push(val.clone());
+                // val = stack.pop();
+                locals[*idx as usize] = val;
+            }
+            Instruction::I32Const(c) => {
+                let val = ast::BV::from_i64(&self.ctx, *c as i64, 32);
+                stack.push(val);
+            }
+            // I32 relops
+            Instruction::I32Eq => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs._eq(&rhs);
+                stack.push(res.ite(
+                    &ast::BV::from_i64(&self.ctx, 1, 32),
+                    &ast::BV::from_i64(&self.ctx, 0, 32),
+                ));
+            }
+            Instruction::I32Ne => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs._eq(&rhs);
+                stack.push(res.ite(
+                    &ast::BV::from_i64(&self.ctx, 0, 32),
+                    &ast::BV::from_i64(&self.ctx, 1, 32),
+                ));
+            }
+            Instruction::I32LtS => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvslt(&rhs);
+                stack.push(res.ite(
+                    &ast::BV::from_i64(&self.ctx, 1, 32),
+                    &ast::BV::from_i64(&self.ctx, 0, 32),
+                ));
+            }
+            Instruction::I32LtU => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvult(&rhs);
+                stack.push(res.ite(
+                    &ast::BV::from_i64(&self.ctx, 1, 32),
+                    &ast::BV::from_i64(&self.ctx, 0, 32),
+                ));
+            }
+            Instruction::I32GtS => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvsgt(&rhs);
+                stack.push(res.ite(
+                    &ast::BV::from_i64(&self.ctx, 1, 32),
+                    &ast::BV::from_i64(&self.ctx, 0, 32),
+                ));
+            }
+            Instruction::I32GtU => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvugt(&rhs);
+                stack.push(res.ite(
+                    &ast::BV::from_i64(&self.ctx, 1, 32),
+                    &ast::BV::from_i64(&self.ctx, 0, 32),
+                ));
+            }
+            Instruction::I32LeS => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvsle(&rhs);
+                stack.push(res.ite(
+                    &ast::BV::from_i64(&self.ctx, 1, 32),
+                    &ast::BV::from_i64(&self.ctx, 0, 32),
+                ));
+            }
+            Instruction::I32LeU => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res: ast::Bool<'ctx> = lhs.bvule(&rhs);
+                stack.push(res.ite(
+                    &ast::BV::from_i64(&self.ctx, 1, 32),
+                    &ast::BV::from_i64(&self.ctx, 0, 32),
+                ));
+            }
+            Instruction::I32GeS => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvsge(&rhs);
+                stack.push(res.ite(
+                    &ast::BV::from_i64(&self.ctx, 1, 32),
+                    &ast::BV::from_i64(&self.ctx, 0, 32),
+                ));
+            }
+            Instruction::I32GeU => {
+                let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
+                let res = lhs.bvuge(&rhs);
+                stack.push(res.ite(
+                    &ast::BV::from_i64(&self.ctx, 1, 32),
+                    &ast::BV::from_i64(&self.ctx, 0, 32),
+                ));
+            }
+            // i32 testop
+            Instruction::I32Eqz => {
+                let val = stack.pop_as::<ast::BV<'ctx>>();
+                let res = val._eq(&ast::BV::from_i64(&self.ctx, 0, 32));
+                stack.push(res.ite(
+                    &ast::BV::from_i64(&self.ctx, 1, 32),
+                    &ast::BV::from_i64(&self.ctx, 0, 32),
+                ));
+            }
+            // i32 unops
+            Instruction::I32Clz => {
+                let val = stack.pop_as::<ast::BV<'ctx>>();
+                stack.push(clz(&self.ctx, &val));
+            }
+            Instruction::I32Ctz => {
+                let val = stack.pop_as::<ast::BV<'ctx>>();
+                stack.push(ctz(&self.ctx, &val));
+            }
+            Instruction::I32Popcnt => {
+                let val = stack.pop_as::<ast::BV<'ctx>>();
+                stack.push(popcnt(&self.ctx, &val));
+            }
+            // control instructions
+            Instruction::Nop => {
+                // Do nothing
+            }
+            Instruction::End => {
+                // NOTE: no need to handle this for programs without loops
+                // and control structures.
+            }
+            _ => {
+                panic!("{} not supported", instr);
+            }
+        }
+    }
+
     // TODO(taegyunkim): Add test for each case.
     pub fn convert(&self, instrs: &[Instruction]) -> ast::Dynamic<'ctx> {
         let mut locals: Vec<ast::Dynamic<'ctx>> = self.init_locals();
         let mut stack: ValueStack<'ctx> = ValueStack::new();
         for instr in instrs {
-            match instr {
-                // I32 binops
-                Instruction::I32Add => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvadd(&rhs);
-                    stack.push(res);
-                }
-                Instruction::I32Sub => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvsub(&rhs);
-                    stack.push(res);
-                }
-                Instruction::I32Mul => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvmul(&rhs);
-                    stack.push(res);
-                }
-                Instruction::I32DivS => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvsdiv(&rhs);
-                    stack.push(res);
-                }
-                Instruction::I32DivU => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvudiv(&rhs);
-                    stack.push(res);
-                }
-                Instruction::I32RemS => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvsrem(&rhs);
-                    stack.push(res);
-                }
-                Instruction::I32RemU => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvurem(&rhs);
-                    stack.push(res);
-                }
-                Instruction::I32And => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvand(&rhs);
-                    stack.push(res);
-                }
-                Instruction::I32Or => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvor(&rhs);
-                    stack.push(res);
-                }
-                Instruction::I32Xor => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvxor(&rhs);
-                    stack.push(res);
-                }
-                Instruction::I32Shl => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    // NOTE(taegyunkim): The WASM spec tests performs shifts by modulo 32, ditto
-                    // all shift and rotate instructions.
-                    let shift_cnt = rhs.bvand(&ast::BV::from_i64(&self.ctx, 31, 32));
-                    let res = lhs.bvshl(&shift_cnt);
-                    stack.push(res);
-                }
-                Instruction::I32ShrS => {
-                    // NOTE(taegyunkim): sign-replicating (arithmetic) shift right.
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let shift_cnt = rhs.bvand(&ast::BV::from_i64(&self.ctx, 31, 32));
-                    let res = lhs.bvashr(&shift_cnt);
-                    stack.push(res);
-                }
-                Instruction::I32ShrU => {
-                    // NOTE(taegyunkim): zero-replicating (logical) shift right.
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let shift_cnt = rhs.bvand(&ast::BV::from_i64(&self.ctx, 31, 32));
-                    let res = lhs.bvlshr(&shift_cnt);
-                    stack.push(res);
-                }
-                Instruction::I32Rotl => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let rotate_cnt = rhs.bvand(&ast::BV::from_i64(&self.ctx, 31, 32));
-                    let res = lhs.bvrotl(&rotate_cnt);
-                    stack.push(res);
-                }
-                Instruction::I32Rotr => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let rotate_cnt = rhs.bvand(&ast::BV::from_i64(&self.ctx, 31, 32));
-                    let res = lhs.bvrotr(&rotate_cnt);
-                    stack.push(res);
-                }
-                // local variable ops
-                Instruction::GetLocal(idx) => {
-                    let val = &locals[*idx as usize];
-                    stack.push(val.clone());
-                }
-                Instruction::SetLocal(idx) => {
-                    let val = stack.pop();
-                    locals[*idx as usize] = val;
-                }
-                Instruction::TeeLocal(idx) => {
-                    let val = stack.pop();
-                    stack.push(val.clone());
-                    // stack.push(val.clone());
-                    // val = stack.pop();
-                    locals[*idx as usize] = val;
-                }
-                Instruction::I32Const(c) => {
-                    let val = ast::BV::from_i64(&self.ctx, *c as i64, 32);
-                    stack.push(val);
-                }
-                // I32 relops
-                Instruction::I32Eq => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs._eq(&rhs);
-                    stack.push(res.ite(
-                        &ast::BV::from_i64(&self.ctx, 1, 32),
-                        &ast::BV::from_i64(&self.ctx, 0, 32),
-                    ));
-                }
-                Instruction::I32Ne => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs._eq(&rhs);
-                    stack.push(res.ite(
-                        &ast::BV::from_i64(&self.ctx, 0, 32),
-                        &ast::BV::from_i64(&self.ctx, 1, 32),
-                    ));
-                }
-                Instruction::I32LtS => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvslt(&rhs);
-                    stack.push(res.ite(
-                        &ast::BV::from_i64(&self.ctx, 1, 32),
-                        &ast::BV::from_i64(&self.ctx, 0, 32),
-                    ));
-                }
-                Instruction::I32LtU => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvult(&rhs);
-                    stack.push(res.ite(
-                        &ast::BV::from_i64(&self.ctx, 1, 32),
-                        &ast::BV::from_i64(&self.ctx, 0, 32),
-                    ));
-                }
-                Instruction::I32GtS => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvsgt(&rhs);
-                    stack.push(res.ite(
-                        &ast::BV::from_i64(&self.ctx, 1, 32),
-                        &ast::BV::from_i64(&self.ctx, 0, 32),
-                    ));
-                }
-                Instruction::I32GtU => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvugt(&rhs);
-                    stack.push(res.ite(
-                        &ast::BV::from_i64(&self.ctx, 1, 32),
-                        &ast::BV::from_i64(&self.ctx, 0, 32),
-                    ));
-                }
-                Instruction::I32LeS => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvsle(&rhs);
-                    stack.push(res.ite(
-                        &ast::BV::from_i64(&self.ctx, 1, 32),
-                        &ast::BV::from_i64(&self.ctx, 0, 32),
-                    ));
-                }
-                Instruction::I32LeU => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res: ast::Bool<'ctx> = lhs.bvule(&rhs);
-                    stack.push(res.ite(
-                        &ast::BV::from_i64(&self.ctx, 1, 32),
-                        &ast::BV::from_i64(&self.ctx, 0, 32),
-                    ));
-                }
-                Instruction::I32GeS => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvsge(&rhs);
-                    stack.push(res.ite(
-                        &ast::BV::from_i64(&self.ctx, 1, 32),
-                        &ast::BV::from_i64(&self.ctx, 0, 32),
-                    ));
-                }
-                Instruction::I32GeU => {
-                    let (lhs, rhs) = stack.pop_pair_as::<ast::BV<'ctx>>();
-                    let res = lhs.bvuge(&rhs);
-                    stack.push(res.ite(
-                        &ast::BV::from_i64(&self.ctx, 1, 32),
-                        &ast::BV::from_i64(&self.ctx, 0, 32),
-                    ));
-                }
-                // i32 testop
-                Instruction::I32Eqz => {
-                    let val = stack.pop_as::<ast::BV<'ctx>>();
-                    let res = val._eq(&ast::BV::from_i64(&self.ctx, 0, 32));
-                    stack.push(res.ite(
-                        &ast::BV::from_i64(&self.ctx, 1, 32),
-                        &ast::BV::from_i64(&self.ctx, 0, 32),
-                    ));
-                }
-                // i32 unops
-                Instruction::I32Clz => {
-                    let val = stack.pop_as::<ast::BV<'ctx>>();
-                    stack.push(clz(&self.ctx, &val));
-                }
-                Instruction::I32Ctz => {
-                    let val = stack.pop_as::<ast::BV<'ctx>>();
-                    stack.push(ctz(&self.ctx, &val));
-                }
-                Instruction::I32Popcnt => {
-                    let val = stack.pop_as::<ast::BV<'ctx>>();
-                    stack.push(popcnt(&self.ctx, &val));
-                }
-                // control instructions
-                Instruction::Nop => {
-                    // Do nothing
-                }
-                Instruction::End => {
-                    // NOTE: no need to handle this for programs without loops
-                    // and control structures.
-                }
-                _ => {
-                    panic!("{} not supported", instr);
-                }
-            }
+            self.step(instr, &mut stack, &mut locals);
         }
 
         match self.func_type.return_type() {
@@ -436,6 +500,17 @@ impl<'ctx> Z3Solver<'ctx> {
         }
     }
 
+    /// Lets candidates use scratch locals of given types, see
+    /// [with_scratch_locals](Converter::with_scratch_locals).
+    pub fn with_scratch_locals(mut self, local_types: &[ValueType]) -> Self {
+        self.converter = self.converter.with_scratch_locals(local_types);
+        self
+    }
+
+    pub fn converter(&self) -> &Converter<'ctx> {
+        &self.converter
+    }
+
     pub fn verify(&self, instrs: &[Instruction]) -> VerifyResult {
         let candidate_f = self.converter.convert(instrs);
         let solver = Solver::new(&self.ctx);
@@ -482,6 +557,54 @@ impl<'ctx> Z3Solver<'ctx> {
             }
         }
     }
+
+    /// Returns values for the `I32Const` instructions at given positions, the holes, such that the
+    /// instructions return the expected output of every test case, or `None` if there are no such
+    /// values. The test cases take and return i32 values, and the instructions must keep the stack
+    /// valid.
+    pub fn solve_constants(
+        &self,
+        instrs: &[Instruction],
+        holes: &[usize],
+        test_cases: &[(Vec<i32>, i32)],
+    ) -> Option<Vec<i32>> {
+        let constants: Vec<ast::BV<'ctx>> = holes
+            .iter()
+            .map(|_| ast::BV::fresh_const(&self.ctx, "c", 32))
+            .collect();
+        let solver = Solver::new(&self.ctx);
+
+        for (input, expected) in test_cases {
+            let mut locals: Vec<ast::Dynamic<'ctx>> = input
+                .iter()
+                .map(|value| ast::BV::from_i64(&self.ctx, i64::from(*value), 32).into())
+                .chain(self.converter.init_locals().into_iter().skip(input.len()))
+                .collect();
+            let mut stack: ValueStack<'ctx> = ValueStack::new();
+            for (idx, instr) in instrs.iter().enumerate() {
+                match holes.iter().position(|hole| *hole == idx) {
+                    Some(i) => stack.push(constants[i].clone()),
+                    None => self.converter.step(instr, &mut stack, &mut locals),
+                }
+            }
+
+            let output = stack.pop_as::<ast::BV<'ctx>>();
+            solver.assert(&output._eq(&ast::BV::from_i64(&self.ctx, i64::from(*expected), 32)));
+        }
+
+        match solver.check() {
+            z3::SatResult::Sat => {
+                let model = solver.get_model();
+                Some(
+                    constants
+                        .iter()
+                        .map(|constant| model.eval(constant).unwrap().as_i64().unwrap() as i32)
+                        .collect(),
+                )
+            }
+            z3::SatResult::Unsat | z3::SatResult::Unknown => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -550,6 +673,39 @@ mod tests {
         );
     }
 
+    // Solves the mask of x & 0x55555555 from the test cases.
+    #[test]
+    fn solve_constants_test() {
+        let spec_module: parity_wasm::elements::Module = wat2module(
+            r#"(module
+                (type $t0 (func (param i32) (result i32)))
+                (func $mask (type $t0) (param $p0 i32) (result i32)
+                  local.get $p0
+                  i32.const 0x55555555
+                  i32.and)
+                (export "mask" (func $mask)))"#,
+        );
+        let (spec_func_type, spec_func_body) =
+            parity_wasm_utils::func_by_name(&spec_module, "mask");
+
+        let cfg = z3::Config::new();
+        let ctx = z3::Context::new(&cfg);
+
+        let solver = Z3Solver::new(&ctx, spec_func_type, spec_func_body);
+        let instrs = [
+            Instruction::GetLocal(0),
+            Instruction::I32Const(0),
+            Instruction::I32And,
+        ];
+        let test_cases = [(vec![-1], 0x5555_5555), (vec![7], 5)];
+        assert_eq!(
+            solver.solve_constants(&instrs, &[1], &test_cases),
+            Some(vec![0x5555_5555])
+        );
+        // No mask makes 7 & c equal 2.
+        assert_eq!(solver.solve_constants(&instrs, &[1], &[(vec![7], 2)]), None);
+    }
+
     // Verifies that x + x == x << 1.
     #[test]
     fn verify_shl_test() {
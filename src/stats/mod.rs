@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Counters of a search.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchStats {
+    /// Proposed candidates in stochastic search, enumerated candidates in enumerative search.
+    pub iterations: u64,
+    pub acceptances: u64,
+    /// The lowest cost of the current candidates, only set by stochastic search.
+    pub current_cost: Option<u32>,
+    /// The lowest cost seen so far, only set by stochastic search.
+    pub best_cost: Option<u32>,
+    pub num_test_cases: usize,
+    pub verifier_calls: u64,
+    pub verifier_time: Duration,
+}
+
+impl SearchStats {
+    /// Calls `verify` and counts it as a verifier call.
+    pub fn verify<T, F: FnOnce() -> T>(&mut self, verify: F) -> T {
+        let start = Instant::now();
+        let result = verify();
+        self.verifier_calls += 1;
+        self.verifier_time += start.elapsed();
+        result
+    }
+
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.iterations == 0 {
+            0.0
+        } else {
+            self.acceptances as f64 / self.iterations as f64
+        }
+    }
+
+    /// Records given cost as the current cost, and as the best cost if it is the lowest so far.
+    pub fn update_cost(&mut self, cost: u32) {
+        self.current_cost = Some(cost);
+        if self.best_cost.map_or(true, |best_cost| cost < best_cost) {
+            self.best_cost = Some(cost);
+        }
+    }
+}
+
+impl fmt::Display for SearchStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} iterations", self.iterations)?;
+        if let (Some(current_cost), Some(best_cost)) = (self.current_cost, self.best_cost) {
+            write!(
+                f,
+                ", cost {} (best {}), acceptance rate {:.3}",
+                current_cost,
+                best_cost,
+                self.acceptance_rate()
+            )?;
+        }
+        write!(
+            f,
+            ", {} test cases, {} verifier calls in {:?}",
+            self.num_test_cases, self.verifier_calls, self.verifier_time
+        )
+    }
+}
+
+/// Logs the progress of a search periodically, and a summary when it ends.
+pub struct Reporter {
+    name: String,
+    interval: Duration,
+    start: Instant,
+    last_report: Instant,
+    last_iterations: u64,
+}
+
+impl Reporter {
+    pub fn new(name: String, interval: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            name,
+            interval,
+            start: now,
+            last_report: now,
+            last_iterations: 0,
+        }
+    }
+
+    /// Logs given stats and the iterations per second since the last report, if the reporting
+    /// interval has passed. Searches that take long steps, like the ones solving with Z3, count
+    /// few iterations, so the check doesn't depend on them.
+    pub fn report(&mut self, stats: &SearchStats) {
+        if self.last_report.elapsed() < self.interval {
+            return;
+        }
+
+        let iters_per_sec = (stats.iterations - self.last_iterations) as f64
+            / self.last_report.elapsed().as_secs_f64();
+        log::info!(
+            "{}: {:.0} iterations/s, {}",
+            self.name,
+            iters_per_sec,
+            stats
+        );
+        self.last_report = Instant::now();
+        self.last_iterations = stats.iterations;
+    }
+
+    pub fn summarize(&self, stats: &SearchStats) {
+        let elapsed = self.start.elapsed();
+        log::info!(
+            "{} finished in {:?}: {:.0} iterations/s, {}",
+            self.name,
+            elapsed,
+            stats.iterations as f64 / elapsed.as_secs_f64(),
+            stats
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_stats_test() {
+        let mut stats = SearchStats::default();
+        assert_eq!(stats.acceptance_rate(), 0.0);
+
+        stats.update_cost(5);
+        stats.update_cost(3);
+        stats.update_cost(4);
+        assert_eq!(stats.current_cost, Some(4));
+        assert_eq!(stats.best_cost, Some(3));
+
+        assert!(stats.verify(|| true));
+        assert_eq!(stats.verifier_calls, 1);
+
+        stats.iterations = 4;
+        stats.acceptances = 1;
+        assert!((stats.acceptance_rate() - 0.25).abs() < std::f64::EPSILON);
+        assert_eq!(
+            stats.to_string(),
+            format!(
+                "4 iterations, cost 4 (best 3), acceptance rate 0.250, 0 test cases, 1 verifier calls in {:?}",
+                stats.verifier_time
+            )
+        );
+    }
+
+    #[test]
+    fn reporter_test() {
+        let mut reporter = Reporter::new(String::from("Search"), Duration::from_secs(0));
+        let stats = SearchStats {
+            iterations: 3,
+            ..SearchStats::default()
+        };
+        // Reports as soon as the interval has passed, whatever the number of iterations.
+        reporter.report(&stats);
+        assert_eq!(reporter.last_iterations, 3);
+
+        let mut reporter = Reporter::new(String::from("Search"), Duration::from_secs(3600));
+        reporter.report(&stats);
+        assert_eq!(reporter.last_iterations, 0);
+    }
+}
@@ -1,17 +1,49 @@
+use crate::checkpoint::{Checkpoint, Checkpointer};
+use crate::perf::PerfModel;
+use crate::stats::{Reporter, SearchStats};
 use crate::wasm::{Candidate, Spec, StackState};
-use crate::{exec, perf, solver, wasm, Mode, SuperoptimizerOpts};
+use crate::worker::Worker;
+use crate::{exec, solver, wasm, Mode, SuperoptimizerOpts};
 use clap::arg_enum;
 use rand::distributions::{Bernoulli, Distribution};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use structopt::StructOpt;
 
+use self::restart::*;
+use self::schedule::*;
 use self::transform::*;
+pub mod restart;
+pub mod schedule;
 pub mod transform;
 
 arg_enum! {
+    /// The initial candidate of the optimization step.
+    ///
+    /// - `Nop`: a candidate of only `Nop`s, the same as in synthesis.
+    /// - `Spec`: the spec function body.
+    /// - `Synthesis`: the result of the synthesis step.
+    /// - `Random`: a random stack valid program.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Init {
+        Nop,
+        Spec,
+        Synthesis,
+        Random,
+    }
+}
+
+arg_enum! {
+    /// - `Random`: accepts every transform.
+    /// - `MCMC`: Metropolis-Hastings at a single inverse temperature `beta`.
+    /// - `ParallelTempering`: one Metropolis-Hastings chain per inverse temperature of the
+    ///   `ladder`, periodically swapping candidates between neighboring chains.
     #[derive(Clone, Debug)]
     pub enum Sampler {
         Random,
         MCMC,
+        ParallelTempering,
     }
 }
 
@@ -31,11 +63,174 @@ pub struct StokeOpts {
 
     #[structopt(short, long, default_value = "0.2")]
     pub beta: f64,
+
+    #[structopt(
+        long,
+        help = "Comma separated inverse temperatures of the parallel tempering chains, from hottest to coldest.",
+        default_value = "0.05,0.1,0.2,0.4,0.8",
+        require_delimiter(true)
+    )]
+    pub ladder: Vec<f64>,
+
+    #[structopt(
+        long,
+        help = "The number of iterations between swaps of parallel tempering chains.",
+        default_value = "100"
+    )]
+    pub swap_interval: usize,
+
+    #[structopt(
+        long,
+        help="How the inverse temperatures change over the course of a search.",
+        possible_values=&Schedule::variants(),
+        default_value="Constant")]
+    pub schedule: Schedule,
+
+    #[structopt(
+        long,
+        help = "The factor linear and exponential schedules scale the inverse temperatures by in the end.",
+        default_value = "10"
+    )]
+    pub anneal_scale: f64,
+
+    #[structopt(
+        long,
+        help = "The number of iterations for linear and exponential schedules to reach the final scale.",
+        default_value = "100000"
+    )]
+    pub anneal_iters: usize,
+
+    #[structopt(
+        long,
+        help = "The acceptance rate the adaptive schedule aims for.",
+        default_value = "0.25"
+    )]
+    pub target_acceptance: f64,
+
+    #[structopt(
+        long,
+        help="When to restart a chain.",
+        possible_values=&Restart::variants(),
+        default_value="Never")]
+    pub restart: Restart,
+
+    #[structopt(
+        long,
+        help = "The number of iterations without progress before a chain restarts.",
+        default_value = "100000"
+    )]
+    pub restart_iters: usize,
+
+    #[structopt(
+        long,
+        help="What a chain restarts from. Spec is rejected by the synthesis step, which would find the spec itself.",
+        possible_values=&RestartFrom::variants(),
+        default_value="Best")]
+    pub restart_from: RestartFrom,
+
+    #[structopt(
+        long,
+        help = "The max number of instructions of a candidate, the length of the spec if not set."
+    )]
+    pub max_length: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "Comma separated weights of the Opcode, Operand, Swap, Instruction, Insert, Delete, Rotate and Hole transforms. Hole solves constants with Z3, for functions of i32 values only.",
+        default_value = "1,1,1,1,1,1,1,0",
+        require_delimiter(true)
+    )]
+    pub transform_weights: Vec<f64>,
+
+    #[structopt(
+        long,
+        help = "If set, shift weight toward transforms that recently produced accepted improvements."
+    )]
+    pub adaptive_weights: bool,
+
+    #[structopt(
+        long,
+        help="The initial candidate of the optimization step. Spec is rejected for specs with unsupported or more than --max-length instructions.",
+        possible_values=&Init::variants(),
+        default_value="Nop")]
+    pub init: Init,
+}
+
+impl StokeOpts {
+    /// Returns whether the Hole transform has a weight. It solves constants for functions of i32
+    /// values only.
+    pub fn solves_holes(&self) -> bool {
+        TRANSFORM_KINDS
+            .iter()
+            .zip(&self.transform_weights)
+            .any(|(kind, &weight)| *kind == TransformKind::Hole && weight > 0.0)
+    }
+}
+
+/// A Markov chain of candidates sampled at an inverse temperature, which is scaled by the
+/// [Annealer](Annealer).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Chain {
+    candidate: Candidate,
+    cost: u32,
+    beta: f64,
+    /// The lowest cost candidate of the chain since the last restart or change of test cases.
+    best: Candidate,
+    progress: Progress,
+}
+
+impl Chain {
+    fn new(candidate: Candidate, cost: u32, beta: f64) -> Self {
+        Self {
+            best: candidate.clone(),
+            candidate,
+            cost,
+            beta,
+            progress: Progress::new(cost, 0),
+        }
+    }
+
+    /// Moves the chain to given candidate and forgets its progress.
+    fn reset(&mut self, candidate: Candidate, cost: u32, iter: usize) {
+        self.best = candidate.clone();
+        self.candidate = candidate;
+        self.cost = cost;
+        self.progress = Progress::new(cost, iter);
+    }
+}
+
+/// The state of a stochastic search kept in a [Checkpoint](Checkpoint).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SearchState {
+    /// The iteration to continue from.
+    iter: usize,
+    chains: Vec<Chain>,
+    annealer: Annealer,
+    best: Option<Candidate>,
+    best_cost: u32,
+}
+
+/// Proposes swapping the candidates of each pair of neighboring chains, and accepts with
+/// probability min(1, exp((beta_i - beta_j) * (cost_i - cost_j))).
+fn swap_neighbors<R: Rng + ?Sized>(rng: &mut R, chains: &mut [Chain], scale: f64) {
+    for i in 1..chains.len() {
+        let (hot, cold) = (&chains[i - 1], &chains[i]);
+        let p: f64 = (1.0 as f64).min(
+            (scale * (hot.beta - cold.beta) * (f64::from(hot.cost) - f64::from(cold.cost))).exp(),
+        );
+        if Bernoulli::new(p).unwrap().sample(rng) {
+            // Swap the states of the chains, but keep their temperatures.
+            chains.swap(i - 1, i);
+            let (hot, cold) = chains.split_at_mut(i);
+            std::mem::swap(&mut hot[i - 1].beta, &mut cold[0].beta);
+        }
+    }
 }
 
 fn eval_candidate(
     stoke_options: &StokeOpts,
     mode: Mode,
+    perf_model: &dyn PerfModel,
     instr_whitelist: &wasm::Whitelist,
     interpreter: &dyn exec::Interpreter,
     spec: &mut Spec,
@@ -49,8 +244,9 @@ fn eval_candidate(
             }
             StackState::Invalid(cnt) => {
                 // If the program is invalid we penalize it the stack value count difference.
-                interpreter.score_invalid()
-                    + (i32::abs(interpreter.return_type_len() as i32 - cnt) as u32 + 1)
+                interpreter
+                    .score_invalid()
+                    .saturating_add(i32::abs(interpreter.return_type_len() as i32 - cnt) as u32 + 1)
             }
         }
     } else {
@@ -59,103 +255,348 @@ fn eval_candidate(
     };
 
     if mode == Mode::Optimization {
-        cost += perf(candidate.instrs());
+        cost = cost.saturating_add(perf_model.cost(candidate.instrs()));
     }
 
     cost
 }
 
+/// Runs a single chain until it finds a verified candidate in synthesis mode, or until the worker
+/// is stopped. In optimization mode, the chain keeps looking for cheaper candidates and returns
+/// the best verified one. `synthesized` is the result of the synthesis step in optimization mode.
+/// The search continues from the checkpoint `checkpointer` loads if there is one, and saves
+/// checkpoints periodically and when it ends.
+#[allow(clippy::too_many_arguments)]
 pub fn search(
     options: &SuperoptimizerOpts,
     stoke_options: &StokeOpts,
     mode: Mode,
-    rx: &std::sync::mpsc::Receiver<()>,
+    synthesized: Option<&Candidate>,
+    perf_model: &dyn PerfModel,
+    worker: &mut Worker,
+    mut checkpointer: Option<&mut Checkpointer>,
     z3_solver: &solver::Z3Solver,
     interpreter: &mut dyn exec::Interpreter,
     spec: &mut Spec,
 ) -> Option<Candidate> {
-    let mut rng = rand::thread_rng();
-
-    let instr_whitelist =
-        wasm::Whitelist::new(spec.num_params(), spec.num_locals(), &options.constants);
+    let instr_whitelist = wasm::Whitelist::new(
+        worker.rng(),
+        spec.num_params(),
+        spec.num_locals(),
+        &options.constants,
+        &options.opcodes,
+    );
 
-    let mut candidate = Candidate::new(spec.num_instrs());
+    let max_length = stoke_options
+        .max_length
+        .unwrap_or_else(|| spec.num_instrs());
+    let transform = Transform::new(
+        spec.param_types().to_vec(),
+        spec.local_types().to_vec(),
+        max_length,
+    );
 
-    let transform = Transform::new(spec.param_types().to_vec(), spec.local_types().to_vec());
+    let mut selector = TransformSelector::new(
+        &stoke_options.transform_weights,
+        stoke_options.adaptive_weights,
+    );
 
-    let mut curr_cost = eval_candidate(
+    let betas = match stoke_options.sampler {
+        Sampler::ParallelTempering => stoke_options.ladder.clone(),
+        Sampler::Random | Sampler::MCMC => vec![stoke_options.beta],
+    };
+    assert!(!betas.is_empty(), "Ladder must not be empty.");
+    let length = spec.num_instrs().min(max_length);
+    let candidate = match (mode, stoke_options.init) {
+        (Mode::Synthesis, _) | (Mode::Optimization, Init::Nop) => Candidate::new(length),
+        (Mode::Optimization, Init::Spec) => Candidate::from_instrs(spec.instrs().to_vec()),
+        (Mode::Optimization, Init::Synthesis) => synthesized
+            .expect("Optimization starts only after synthesis finds a candidate.")
+            .clone(),
+        (Mode::Optimization, Init::Random) => {
+            Candidate::new_random_valid(worker.rng(), &instr_whitelist, length)
+        }
+    };
+    let cost = eval_candidate(
         stoke_options,
         mode,
+        perf_model,
         &instr_whitelist,
         interpreter,
         spec,
         &candidate,
     );
-    let initial_cost = curr_cost;
+    let mut chains: Vec<Chain> = betas
+        .into_iter()
+        .map(|beta| Chain::new(candidate.clone(), cost, beta))
+        .collect();
+    let mut annealer = Annealer::new(
+        stoke_options.schedule,
+        stoke_options.anneal_scale,
+        stoke_options.anneal_iters,
+        stoke_options.target_acceptance,
+    );
 
-    loop {
-        if (mode == Mode::Optimization && curr_cost < initial_cost)
-            || (mode == Mode::Synthesis && curr_cost == 0)
-        {
-            match z3_solver.verify(&candidate.instrs()) {
-                solver::VerifyResult::Verified => {
-                    return Some(candidate);
-                }
-                solver::VerifyResult::CounterExample(values) => {
-                    interpreter.add_test_case(values);
-                    curr_cost = interpreter.return_bit_width();
+    // The best verified candidate and its cost, only used in optimization mode.
+    let mut best: Option<Candidate> = None;
+    let mut best_cost = cost;
+
+    let mut stats = SearchStats::default();
+    stats.update_cost(cost);
+    let mut reporter = Reporter::new(
+        format!("Stochastic search {:?} worker {}", mode, worker.id()),
+        Duration::from_secs(options.progress_interval),
+    );
+
+    let mut start_iter = 1;
+    if let Some(checkpoint) = checkpointer
+        .as_ref()
+        .and_then(|checkpointer| checkpointer.load::<SearchState>())
+    {
+        let (resumed_stats, state) = checkpoint.restore(worker, interpreter);
+        stats = resumed_stats;
+        start_iter = state.iter;
+        chains = state.chains;
+        annealer = state.annealer;
+        best = state.best;
+        best_cost = state.best_cost;
+        if mode == Mode::Synthesis && best.is_some() {
+            reporter.summarize(&stats);
+            return best;
+        }
+    }
+
+    let solves_holes = stoke_options.solves_holes();
+    // The test cases the Hole transform solves constants against, taken from the first
+    // `num_hole_test_cases` test cases of the interpreter.
+    let mut hole_test_cases = Vec::new();
+    let mut num_hole_test_cases = 0;
+
+    let mut next_iter = start_iter;
+    'search: for iter in start_iter.. {
+        next_iter = iter;
+        let mut num_new_tests = 0;
+        // Whether the cost of each chain is to be evaluated again on new test cases. Chains that
+        // fail verification are kept at the max cost instead to move them away.
+        let mut needs_reeval = vec![true; chains.len()];
+        for (chain, needs_reeval) in chains.iter_mut().zip(needs_reeval.iter_mut()) {
+            if (mode == Mode::Optimization && chain.cost < best_cost)
+                || (mode == Mode::Synthesis && chain.cost == 0)
+            {
+                match stats.verify(|| z3_solver.verify(&chain.candidate.instrs())) {
+                    solver::VerifyResult::Verified => {
+                        best = Some(chain.candidate.clone());
+                        best_cost = chain.cost;
+                        if mode == Mode::Synthesis {
+                            break 'search;
+                        }
+                    }
+                    solver::VerifyResult::CounterExample(values) => {
+                        interpreter.add_test_case(values.clone());
+                        worker.share_counterexample(values);
+                        num_new_tests += 1;
+                        chain.cost = interpreter.max_test_case_cost();
+                        *needs_reeval = false;
+                    }
                 }
             }
         }
 
-        if rx.try_recv().is_ok() {
-            println!("Stochastic search {:?} timed out", mode);
-            break;
+        match worker.sync(interpreter) {
+            None => {
+                log::debug!(
+                    "Stochastic search {:?} worker {} stopped",
+                    mode,
+                    worker.id()
+                );
+                break;
+            }
+            Some(num_shared_tests) => num_new_tests += num_shared_tests,
+        }
+        if num_new_tests > 0 {
+            // New test cases make the costs of chains stale.
+            for (chain, needs_reeval) in chains.iter_mut().zip(needs_reeval) {
+                let cost = if needs_reeval {
+                    eval_candidate(
+                        stoke_options,
+                        mode,
+                        perf_model,
+                        &instr_whitelist,
+                        interpreter,
+                        spec,
+                        &chain.candidate,
+                    )
+                } else {
+                    chain.cost
+                };
+                let candidate = chain.candidate.clone();
+                chain.reset(candidate, cost, iter);
+            }
         }
 
-        let transform_info = transform.operate(&mut rng, &instr_whitelist, &mut candidate);
-        let new_cost = eval_candidate(
-            stoke_options,
-            mode,
-            &instr_whitelist,
-            interpreter,
-            spec,
-            &candidate,
-        );
+        if solves_holes && num_hole_test_cases != interpreter.num_test_cases() {
+            hole_test_cases = exec::i32_test_cases(&*interpreter);
+            num_hole_test_cases = interpreter.num_test_cases();
+        }
 
-        #[cfg(debug_assertions)]
-        println!("curr_cost: {}, new_cost: {}", curr_cost, new_cost);
-        match stoke_options.sampler {
-            Sampler::Random => {
+        for chain in chains.iter_mut() {
+            let solve_hole = |instrs: &[parity_wasm::elements::Instruction], idx: usize| {
+                z3_solver
+                    .solve_constants(instrs, &[idx], &hole_test_cases)
+                    .map(|constants| constants[0])
+            };
+            let transform_info = transform.operate(
+                worker.rng(),
+                &selector,
+                &instr_whitelist,
+                &mut chain.candidate,
+                &solve_hole,
+            );
+            let new_cost = eval_candidate(
+                stoke_options,
+                mode,
+                perf_model,
+                &instr_whitelist,
+                interpreter,
+                spec,
+                &chain.candidate,
+            );
+
+            log::trace!("curr_cost: {}, new_cost: {}", chain.cost, new_cost);
+            let accept = match stoke_options.sampler {
                 // Always accept transform.
-                curr_cost = new_cost;
-            }
-            Sampler::MCMC => {
-                if new_cost < curr_cost {
-                    // Accept this transform.
-                    curr_cost = new_cost;
-                } else {
-                    // Following computes min(1, exp(-0.4 * new_cost/ curr_cost))
-                    // TODO(taegyunkim): Use parameter \beta instead of -0.4
-                    let p: f64 = (1.0 as f64)
-                        .min((-stoke_options.beta * (new_cost as f64) / (curr_cost as f64)).exp());
-                    let d = Bernoulli::new(p).unwrap();
-                    #[cfg(debug_assertions)]
-                    println!("p: {}", p);
-                    let accept = d.sample(&mut rng);
-                    if !accept {
-                        #[cfg(debug_assertions)]
-                        println!("undoing...");
-                        transform.undo(&transform_info, &mut candidate);
-                    } else {
-                        #[cfg(debug_assertions)]
-                        println!("accepted...");
-                        curr_cost = new_cost;
+                Sampler::Random => true,
+                Sampler::MCMC => {
+                    new_cost < chain.cost || {
+                        // Following computes min(1, exp(-beta * new_cost / curr_cost))
+                        let beta = chain.beta * annealer.scale();
+                        let p: f64 = (1.0 as f64)
+                            .min((-beta * f64::from(new_cost) / f64::from(chain.cost)).exp());
+                        Bernoulli::new(p).unwrap().sample(worker.rng())
+                    }
+                }
+                Sampler::ParallelTempering => {
+                    // Swaps between chains assume each chain samples from exp(-beta * cost),
+                    // so this uses the cost difference rather than the ratio.
+                    new_cost <= chain.cost || {
+                        let beta = chain.beta * annealer.scale();
+                        let p: f64 = (-beta * (f64::from(new_cost) - f64::from(chain.cost))).exp();
+                        Bernoulli::new(p).unwrap().sample(worker.rng())
                     }
                 }
+            };
+
+            let old_cost = chain.cost;
+            if accept {
+                chain.cost = new_cost;
+                if chain.progress.update(new_cost, iter) {
+                    chain.best = chain.candidate.clone();
+                }
+            } else {
+                transform.undo(&transform_info, &mut chain.candidate);
             }
+            annealer.update(iter, accept);
+            stats.iterations += 1;
+            stats.acceptances += accept as u64;
+            selector.record(transform_info.kind(), accept, accept && new_cost < old_cost);
+
+            if chain.progress.should_restart(
+                stoke_options.restart,
+                stoke_options.restart_iters,
+                iter,
+            ) {
+                let candidate = match stoke_options.restart_from {
+                    RestartFrom::Empty => Candidate::new(length),
+                    RestartFrom::Best => chain.best.clone(),
+                    RestartFrom::Spec => Candidate::from_instrs(spec.instrs().to_vec()),
+                };
+                let cost = eval_candidate(
+                    stoke_options,
+                    mode,
+                    perf_model,
+                    &instr_whitelist,
+                    interpreter,
+                    spec,
+                    &candidate,
+                );
+                chain.reset(candidate, cost, iter);
+            }
+        }
+
+        if chains.len() > 1
+            && stoke_options.swap_interval > 0
+            && iter % stoke_options.swap_interval == 0
+        {
+            swap_neighbors(worker.rng(), &mut chains, annealer.scale());
         }
+
+        if let Some(cost) = chains.iter().map(|chain| chain.cost).min() {
+            stats.update_cost(cost);
+        }
+        stats.num_test_cases = interpreter.num_test_cases();
+        reporter.report(&stats);
+
+        if let Some(checkpointer) = checkpointer.as_mut() {
+            if checkpointer.is_due() {
+                let state = SearchState {
+                    iter: iter + 1,
+                    chains: chains.clone(),
+                    annealer: annealer.clone(),
+                    best: best.clone(),
+                    best_cost,
+                };
+                checkpointer.save(&Checkpoint::new(worker, interpreter, stats.clone(), state));
+            }
+        }
+    }
+
+    stats.num_test_cases = interpreter.num_test_cases();
+    reporter.summarize(&stats);
+    if let Some(checkpointer) = checkpointer {
+        let state = SearchState {
+            iter: next_iter,
+            chains,
+            annealer,
+            best: best.clone(),
+            best_cost,
+        };
+        checkpointer.save(&Checkpoint::new(worker, interpreter, stats, state));
+    }
+    log::info!(
+        "Stochastic search {:?} worker {} transforms:",
+        mode,
+        worker.id()
+    );
+    for (kind, kind_stats) in selector.stats() {
+        log::info!("  {:?}: {:?}", kind, kind_stats);
     }
 
-    None
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(cost: u32, beta: f64) -> Chain {
+        Chain::new(Candidate::new(1), cost, beta)
+    }
+
+    #[test]
+    fn swap_neighbors_test() {
+        // A colder chain with a higher cost always swaps with its hotter neighbor.
+        let mut chains = vec![chain(1, 0.1), chain(100, 1.0)];
+        swap_neighbors(&mut rand::thread_rng(), &mut chains, 1.0);
+        assert_eq!(chains[0].cost, 100);
+        assert_eq!(chains[1].cost, 1);
+        // Temperatures stay with the chains.
+        assert!((chains[0].beta - 0.1).abs() < std::f64::EPSILON);
+        assert!((chains[1].beta - 1.0).abs() < std::f64::EPSILON);
+
+        // A hot chain with a much higher cost practically never moves to a cold one.
+        let mut chains = vec![chain(100, 0.1), chain(1, 10.0)];
+        swap_neighbors(&mut rand::thread_rng(), &mut chains, 1.0);
+        assert_eq!(chains[0].cost, 100);
+        assert_eq!(chains[1].cost, 1);
+    }
 }
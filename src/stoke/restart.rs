@@ -0,0 +1,94 @@
+use clap::arg_enum;
+use serde::{Deserialize, Serialize};
+
+arg_enum! {
+    /// When to restart a chain.
+    ///
+    /// - `Never`: chains are never restarted.
+    /// - `Stall`: after `restart_iters` iterations without lowering the lowest cost of the chain.
+    /// - `Plateau`: after `restart_iters` iterations without any change of the cost of the chain.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Restart {
+        Never,
+        Stall,
+        Plateau,
+    }
+}
+
+arg_enum! {
+    /// What a chain restarts from.
+    ///
+    /// - `Empty`: a candidate of only `Nop`s.
+    /// - `Best`: the lowest cost candidate of the chain so far.
+    /// - `Spec`: the spec function body. Only supported by the optimization step, since synthesis
+    ///   would find the spec itself.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum RestartFrom {
+        Empty,
+        Best,
+        Spec,
+    }
+}
+
+/// Tracks the cost of a chain over time to decide when to restart it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Progress {
+    lowest_cost: u32,
+    last_improvement: usize,
+    last_cost: u32,
+    last_change: usize,
+}
+
+impl Progress {
+    pub fn new(cost: u32, iter: usize) -> Self {
+        Self {
+            lowest_cost: cost,
+            last_improvement: iter,
+            last_cost: cost,
+            last_change: iter,
+        }
+    }
+
+    /// Records the cost of the chain at given iteration. Returns whether it is the lowest so far.
+    pub fn update(&mut self, cost: u32, iter: usize) -> bool {
+        if cost != self.last_cost {
+            self.last_cost = cost;
+            self.last_change = iter;
+        }
+        if cost < self.lowest_cost {
+            self.lowest_cost = cost;
+            self.last_improvement = iter;
+            return true;
+        }
+        false
+    }
+
+    pub fn should_restart(&self, restart: Restart, restart_iters: usize, iter: usize) -> bool {
+        match restart {
+            Restart::Never => false,
+            Restart::Stall => iter - self.last_improvement >= restart_iters,
+            Restart::Plateau => iter - self.last_change >= restart_iters,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_restart_test() {
+        let mut progress = Progress::new(10, 0);
+        // The cost changes but never gets lower than 10.
+        for iter in 1..=5 {
+            assert!(!progress.update(10 + iter as u32 % 2, iter));
+        }
+        assert!(progress.should_restart(Restart::Stall, 5, 5));
+        assert!(!progress.should_restart(Restart::Plateau, 5, 5));
+        assert!(!progress.should_restart(Restart::Never, 5, 5));
+
+        assert!(progress.update(3, 6));
+        assert!(!progress.should_restart(Restart::Stall, 5, 10));
+        assert!(progress.should_restart(Restart::Plateau, 4, 10));
+    }
+}
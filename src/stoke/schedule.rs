@@ -0,0 +1,129 @@
+use clap::arg_enum;
+use serde::{Deserialize, Serialize};
+
+arg_enum! {
+    /// How the inverse temperatures of the chains change over the course of a search.
+    ///
+    /// - `Constant`: they stay fixed.
+    /// - `Linear`: they grow linearly to `anneal_scale` times their initial values.
+    /// - `Exponential`: they grow geometrically to `anneal_scale` times their initial values.
+    /// - `Adaptive`: they are raised when the acceptance rate is above `target_acceptance`, and
+    ///   lowered otherwise.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub enum Schedule {
+        Constant,
+        Linear,
+        Exponential,
+        Adaptive,
+    }
+}
+
+/// The number of proposals between two adjustments of the adaptive schedule.
+const ADAPTIVE_WINDOW: u32 = 100;
+
+/// The factor the adaptive schedule scales the inverse temperatures by at each adjustment.
+const ADAPTIVE_STEP: f64 = 1.1;
+
+/// Scales the inverse temperatures of the chains according to a [Schedule](Schedule).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Annealer {
+    schedule: Schedule,
+    /// The scale linear and exponential schedules reach after `num_iters` iterations.
+    final_scale: f64,
+    num_iters: usize,
+    target_acceptance: f64,
+    scale: f64,
+    // Proposals and acceptances since the last adjustment of the adaptive schedule.
+    num_proposals: u32,
+    num_acceptances: u32,
+}
+
+impl Annealer {
+    pub fn new(
+        schedule: Schedule,
+        final_scale: f64,
+        num_iters: usize,
+        target_acceptance: f64,
+    ) -> Self {
+        Self {
+            schedule,
+            final_scale,
+            num_iters,
+            target_acceptance,
+            scale: 1.0,
+            num_proposals: 0,
+            num_acceptances: 0,
+        }
+    }
+
+    /// The factor to multiply the inverse temperature of each chain by.
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// Records whether a proposal made at given iteration was accepted, and updates the scale.
+    pub fn update(&mut self, iter: usize, accepted: bool) {
+        let progress = if self.num_iters == 0 {
+            1.0
+        } else {
+            (iter as f64 / self.num_iters as f64).min(1.0)
+        };
+
+        match self.schedule {
+            Schedule::Constant => {}
+            Schedule::Linear => self.scale = 1.0 + (self.final_scale - 1.0) * progress,
+            Schedule::Exponential => self.scale = self.final_scale.powf(progress),
+            Schedule::Adaptive => {
+                self.num_proposals += 1;
+                self.num_acceptances += accepted as u32;
+                if self.num_proposals == ADAPTIVE_WINDOW {
+                    let acceptance = f64::from(self.num_acceptances) / f64::from(ADAPTIVE_WINDOW);
+                    if acceptance > self.target_acceptance {
+                        self.scale *= ADAPTIVE_STEP;
+                    } else {
+                        self.scale /= ADAPTIVE_STEP;
+                    }
+                    self.num_proposals = 0;
+                    self.num_acceptances = 0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(x: f64, y: f64) {
+        assert!((x - y).abs() < 1e-9, "{} != {}", x, y);
+    }
+
+    #[test]
+    fn schedule_test() {
+        let mut constant = Annealer::new(Schedule::Constant, 10.0, 100, 0.25);
+        let mut linear = Annealer::new(Schedule::Linear, 10.0, 100, 0.25);
+        let mut exponential = Annealer::new(Schedule::Exponential, 100.0, 100, 0.25);
+        for annealer in &mut [&mut constant, &mut linear, &mut exponential] {
+            annealer.update(50, true);
+        }
+        assert_close(constant.scale(), 1.0);
+        assert_close(linear.scale(), 5.5);
+        assert_close(exponential.scale(), 10.0);
+
+        // Schedules stop at the final scale.
+        linear.update(1000, true);
+        assert_close(linear.scale(), 10.0);
+
+        // Accepting everything makes the adaptive schedule colder.
+        let mut adaptive = Annealer::new(Schedule::Adaptive, 10.0, 100, 0.25);
+        for iter in 0..ADAPTIVE_WINDOW as usize {
+            adaptive.update(iter, true);
+        }
+        assert_close(adaptive.scale(), ADAPTIVE_STEP);
+        for iter in 0..ADAPTIVE_WINDOW as usize {
+            adaptive.update(iter, false);
+        }
+        assert_close(adaptive.scale(), 1.0);
+    }
+}
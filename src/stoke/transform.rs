@@ -1,6 +1,6 @@
-use crate::wasm::{Candidate, Whitelist};
+use crate::wasm::{Candidate, StackState, Whitelist};
 use parity_wasm::elements::{Instruction, ValueType};
-use rand::distributions::{Distribution, Standard};
+use rand::distributions::{Distribution, WeightedIndex};
 use rand::seq::SliceRandom;
 use rand::Rng;
 
@@ -10,69 +10,222 @@ pub enum TransformKind {
     Operand,
     Swap,
     Instruction,
+    /// Inserts a random instruction, unless the candidate is at its max length.
+    Insert,
+    /// Removes a random instruction.
+    Delete,
+    /// Moves a random block of instructions to another position.
+    Rotate,
+    /// Replaces a random constant with a value solved against the test cases, so that constants
+    /// aren't limited to the whitelisted ones.
+    Hole,
 }
 
-impl Distribution<TransformKind> for Standard {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> TransformKind {
-        match rng.gen_range(0, 4) {
-            0 => TransformKind::Opcode,
-            1 => TransformKind::Operand,
-            2 => TransformKind::Swap,
-            _ => TransformKind::Instruction,
+/// All transform kinds, in the order of their weights in
+/// [TransformSelector](TransformSelector).
+pub const TRANSFORM_KINDS: [TransformKind; 8] = [
+    TransformKind::Opcode,
+    TransformKind::Operand,
+    TransformKind::Swap,
+    TransformKind::Instruction,
+    TransformKind::Insert,
+    TransformKind::Delete,
+    TransformKind::Rotate,
+    TransformKind::Hole,
+];
+
+/// The number of proposals between two weight updates in adaptive mode.
+const ADAPTIVE_WINDOW: u64 = 1000;
+
+/// How much the improvement rate of a transform kind in the last window raises its weight in
+/// adaptive mode.
+const ADAPTIVE_GAIN: f64 = 10.0;
+
+/// Counts of proposed transforms of one kind, and how many of them were accepted, and how many of
+/// those lowered the cost.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TransformStats {
+    pub proposals: u64,
+    pub acceptances: u64,
+    pub improvements: u64,
+}
+
+impl TransformStats {
+    fn record(&mut self, accepted: bool, improved: bool) {
+        self.proposals += 1;
+        self.acceptances += accepted as u64;
+        self.improvements += improved as u64;
+    }
+}
+
+/// Picks transform kinds with given weights. In adaptive mode, the weight of each kind is
+/// periodically raised in proportion to how often it recently produced accepted improvements.
+pub struct TransformSelector {
+    base_weights: Vec<f64>,
+    adaptive: bool,
+    dist: WeightedIndex<f64>,
+    stats: [TransformStats; 8],
+    /// Stats since the last weight update, only used in adaptive mode.
+    window: [TransformStats; 8],
+}
+
+impl TransformSelector {
+    pub fn new(weights: &[f64], adaptive: bool) -> Self {
+        assert_eq!(
+            weights.len(),
+            TRANSFORM_KINDS.len(),
+            "Expected a weight for each of {:?}",
+            TRANSFORM_KINDS
+        );
+        Self {
+            base_weights: weights.to_vec(),
+            adaptive,
+            dist: WeightedIndex::new(weights).expect("Invalid transform weights."),
+            stats: Default::default(),
+            window: Default::default(),
+        }
+    }
+
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> TransformKind {
+        TRANSFORM_KINDS[self.dist.sample(rng)]
+    }
+
+    /// Records the outcome of a proposed transform of given kind.
+    pub fn record(&mut self, kind: TransformKind, accepted: bool, improved: bool) {
+        let i = TRANSFORM_KINDS.iter().position(|k| *k == kind).unwrap();
+        self.stats[i].record(accepted, improved);
+        if !self.adaptive {
+            return;
+        }
+
+        self.window[i].record(accepted, improved);
+        if self.window.iter().map(|stats| stats.proposals).sum::<u64>() == ADAPTIVE_WINDOW {
+            let weights: Vec<f64> = self
+                .base_weights
+                .iter()
+                .zip(self.window.iter())
+                .map(|(weight, stats)| {
+                    let improvement_rate =
+                        stats.improvements as f64 / stats.proposals.max(1) as f64;
+                    weight * (1.0 + ADAPTIVE_GAIN * improvement_rate)
+                })
+                .collect();
+            self.dist = WeightedIndex::new(&weights).unwrap();
+            self.window = Default::default();
         }
     }
+
+    /// Returns the stats of each transform kind.
+    pub fn stats(&self) -> Vec<(TransformKind, TransformStats)> {
+        TRANSFORM_KINDS
+            .iter()
+            .cloned()
+            .zip(self.stats.iter().cloned())
+            .collect()
+    }
 }
 
 pub struct TransformInfo {
-    #[allow(dead_code)]
+    /// Whether the candidate was changed, there is nothing to undo if not.
     success: bool,
     kind: TransformKind,
-    undo_indices: [usize; 2],
+    undo_indices: [usize; 3],
     undo_instr: Instruction,
 }
 
+impl TransformInfo {
+    pub fn kind(&self) -> TransformKind {
+        self.kind
+    }
+
+    /// A transform that couldn't be applied to the candidate and left it as it is.
+    fn failed(kind: TransformKind) -> Self {
+        Self {
+            success: false,
+            kind,
+            undo_indices: [0, 0, 0],
+            undo_instr: Instruction::Nop,
+        }
+    }
+}
+
 pub struct Transform {
     spec_param_types: Vec<ValueType>,
+    /// The types of the locals candidates can use, the scratch locals included.
     spec_local_types: Vec<ValueType>,
+    /// The max number of instructions of a candidate.
+    max_length: usize,
 }
 
 impl Transform {
-    pub fn new(spec_param_types: Vec<ValueType>, spec_local_types: Vec<ValueType>) -> Self {
+    pub fn new(
+        spec_param_types: Vec<ValueType>,
+        spec_local_types: Vec<ValueType>,
+        max_length: usize,
+    ) -> Self {
         Self {
             spec_param_types,
             spec_local_types,
+            max_length,
         }
     }
 
+    /// Applies a transform of given kind to the candidate. `solve_hole` returns the value of the
+    /// constant at given position that makes given instructions pass the test cases, if any.
     fn do_transform<R: Rng + ?Sized>(
         &self,
         rng: &mut R,
         transform_kind: TransformKind,
         instr_whitelist: &Whitelist,
         candidate_func: &mut Candidate,
+        solve_hole: &dyn Fn(&[Instruction], usize) -> Option<i32>,
     ) -> TransformInfo {
+        // All transforms other than insert pick an existing instruction.
+        if candidate_func.instrs().is_empty() && transform_kind != TransformKind::Insert {
+            return TransformInfo::failed(transform_kind);
+        }
+
         match transform_kind {
             TransformKind::Opcode => self.opcode(rng, instr_whitelist, candidate_func),
             TransformKind::Operand => self.operand(rng, instr_whitelist, candidate_func),
             TransformKind::Swap => self.swap(rng, candidate_func),
             TransformKind::Instruction => self.instruction(rng, instr_whitelist, candidate_func),
+            TransformKind::Insert => self.insert(rng, instr_whitelist, candidate_func),
+            TransformKind::Delete => self.delete(rng, candidate_func),
+            TransformKind::Rotate => self.rotate(rng, candidate_func),
+            TransformKind::Hole => self.hole(rng, instr_whitelist, candidate_func, solve_hole),
         }
     }
 
     pub fn operate<R: Rng + ?Sized>(
         &self,
         rng: &mut R,
+        selector: &TransformSelector,
         instr_whitelist: &Whitelist,
         candidate_func: &mut Candidate,
+        solve_hole: &dyn Fn(&[Instruction], usize) -> Option<i32>,
     ) -> TransformInfo {
-        let transform_kind = rng.gen::<TransformKind>();
-
-        self.do_transform(rng, transform_kind, instr_whitelist, candidate_func)
+        let transform_kind = selector.sample(rng);
+
+        self.do_transform(
+            rng,
+            transform_kind,
+            instr_whitelist,
+            candidate_func,
+            solve_hole,
+        )
     }
 
     pub fn undo(&self, transform_info: &TransformInfo, candidate_func: &mut Candidate) {
+        if !transform_info.success {
+            return;
+        }
+
         match transform_info.kind {
-            TransformKind::Opcode | TransformKind::Operand | TransformKind::Instruction => {
+            TransformKind::Opcode
+            | TransformKind::Operand
+            | TransformKind::Instruction
+            | TransformKind::Hole => {
                 candidate_func.instrs_mut()[transform_info.undo_indices[0]] =
                     transform_info.undo_instr.clone();
             }
@@ -82,6 +235,21 @@ impl Transform {
                     transform_info.undo_indices[1],
                 );
             }
+            TransformKind::Insert => {
+                candidate_func
+                    .instrs_mut()
+                    .remove(transform_info.undo_indices[0]);
+            }
+            TransformKind::Delete => {
+                candidate_func.instrs_mut().insert(
+                    transform_info.undo_indices[0],
+                    transform_info.undo_instr.clone(),
+                );
+            }
+            TransformKind::Rotate => {
+                let [start, end, mid] = transform_info.undo_indices;
+                candidate_func.instrs_mut()[start..end].rotate_right(mid - start);
+            }
         }
     }
 
@@ -100,7 +268,7 @@ impl Transform {
         TransformInfo {
             success: new_instr != undo_instr,
             kind: TransformKind::Opcode,
-            undo_indices: [idx, 0],
+            undo_indices: [idx, 0, 0],
             undo_instr,
         }
     }
@@ -163,7 +331,7 @@ impl Transform {
         TransformInfo {
             success: new_instr != undo_instr,
             kind: TransformKind::Operand,
-            undo_indices: [instr_idx, 0],
+            undo_indices: [instr_idx, 0, 0],
             undo_instr,
         }
     }
@@ -177,8 +345,8 @@ impl Transform {
         TransformInfo {
             success: idx1 != idx2 && instr1 != instr2,
             kind: TransformKind::Swap,
-            undo_indices: [idx1, idx2],
-            undo_instr: parity_wasm::elements::Instruction::Nop,
+            undo_indices: [idx1, idx2, 0],
+            undo_instr: Instruction::Nop,
         }
     }
 
@@ -197,7 +365,109 @@ impl Transform {
         TransformInfo {
             success: new_instr != undo_instr,
             kind: TransformKind::Instruction,
-            undo_indices: [instr_idx, 0],
+            undo_indices: [instr_idx, 0, 0],
+            undo_instr,
+        }
+    }
+
+    fn insert<R: Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+        instr_whitelist: &Whitelist,
+        candidate_func: &mut Candidate,
+    ) -> TransformInfo {
+        let len = candidate_func.instrs().len();
+        if len >= self.max_length {
+            return TransformInfo::failed(TransformKind::Insert);
+        }
+
+        let instr_idx = rng.gen_range(0, len + 1);
+        candidate_func
+            .instrs_mut()
+            .insert(instr_idx, instr_whitelist.sample(rng));
+
+        TransformInfo {
+            success: true,
+            kind: TransformKind::Insert,
+            undo_indices: [instr_idx, 0, 0],
+            undo_instr: Instruction::Nop,
+        }
+    }
+
+    fn delete<R: Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+        candidate_func: &mut Candidate,
+    ) -> TransformInfo {
+        let (instr_idx, undo_instr) = candidate_func.get_rand_instr(rng);
+        candidate_func.instrs_mut().remove(instr_idx);
+
+        TransformInfo {
+            success: true,
+            kind: TransformKind::Delete,
+            undo_indices: [instr_idx, 0, 0],
+            undo_instr,
+        }
+    }
+
+    /// Picks a random range of instructions and moves its first part to the end.
+    fn rotate<R: Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+        candidate_func: &mut Candidate,
+    ) -> TransformInfo {
+        let len = candidate_func.instrs().len();
+        let start = rng.gen_range(0, len);
+        let end = rng.gen_range(start + 1, len + 1);
+        let mid = rng.gen_range(start, end);
+
+        candidate_func.instrs_mut()[start..end].rotate_left(mid - start);
+
+        TransformInfo {
+            success: mid != start,
+            kind: TransformKind::Rotate,
+            undo_indices: [start, end, mid],
+            undo_instr: Instruction::Nop,
+        }
+    }
+
+    fn hole<R: Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+        instr_whitelist: &Whitelist,
+        candidate_func: &mut Candidate,
+        solve_hole: &dyn Fn(&[Instruction], usize) -> Option<i32>,
+    ) -> TransformInfo {
+        let holes: Vec<usize> = candidate_func
+            .instrs()
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, instr)| match instr {
+                Instruction::I32Const(_) => Some(idx),
+                _ => None,
+            })
+            .collect();
+        let idx = match holes.choose(rng) {
+            Some(&idx) => idx,
+            None => return TransformInfo::failed(TransformKind::Hole),
+        };
+        // The solver can only run candidates that keep the stack valid.
+        if let StackState::Invalid(_) = candidate_func.is_stack_valid(instr_whitelist) {
+            return TransformInfo::failed(TransformKind::Hole);
+        }
+        let value = match solve_hole(candidate_func.instrs(), idx) {
+            Some(value) => value,
+            None => return TransformInfo::failed(TransformKind::Hole),
+        };
+
+        let new_instr = Instruction::I32Const(value);
+        let undo_instr =
+            std::mem::replace(&mut candidate_func.instrs_mut()[idx], new_instr.clone());
+
+        TransformInfo {
+            success: new_instr != undo_instr,
+            kind: TransformKind::Hole,
+            undo_indices: [idx, 0, 0],
             undo_instr,
         }
     }
@@ -209,8 +479,8 @@ mod test {
     use parity_wasm::elements::{Instruction, ValueType};
     #[test]
     fn opcode_transform_test() {
-        let transform = Transform::new(vec![ValueType::I32], vec![]);
-        let instr_whitelist = Whitelist::new(1, 0, &[1]);
+        let transform = Transform::new(vec![ValueType::I32], vec![], 3);
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 1, 0, &[1], &[]);
 
         let original = Candidate::from_instrs(vec![Instruction::Nop, Instruction::I32Const(1)]);
 
@@ -220,6 +490,7 @@ mod test {
             TransformKind::Opcode,
             &instr_whitelist,
             &mut transformed,
+            &|_, _| None,
         );
 
         if transform_info.success {
@@ -236,8 +507,8 @@ mod test {
 
     #[test]
     fn operand_transform_test() {
-        let transform = Transform::new(vec![ValueType::I32], vec![]);
-        let instr_whitelist = Whitelist::new(1, 0, &[1]);
+        let transform = Transform::new(vec![ValueType::I32], vec![], 3);
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 1, 0, &[1], &[]);
 
         let original =
             Candidate::from_instrs(vec![Instruction::GetLocal(0), Instruction::I32Const(1)]);
@@ -248,6 +519,7 @@ mod test {
             TransformKind::Operand,
             &instr_whitelist,
             &mut transformed,
+            &|_, _| None,
         );
 
         if transform_info.success {
@@ -262,10 +534,89 @@ mod test {
         println!("{:?}", original);
     }
 
+    #[test]
+    fn insert_delete_rotate_transform_test() {
+        let transform = Transform::new(vec![ValueType::I32], vec![], 3);
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 1, 0, &[1], &[]);
+
+        let original = Candidate::from_instrs(vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+        ]);
+
+        for &kind in &[
+            TransformKind::Insert,
+            TransformKind::Delete,
+            TransformKind::Rotate,
+        ] {
+            let mut transformed = original.clone();
+            let transform_info = transform.do_transform(
+                &mut rand::thread_rng(),
+                kind,
+                &instr_whitelist,
+                &mut transformed,
+                &|_, _| None,
+            );
+
+            if kind == TransformKind::Insert {
+                // The candidate is already at its max length.
+                assert!(!transform_info.success);
+            }
+            if transform_info.success {
+                assert_ne!(transformed.instrs(), original.instrs());
+            }
+
+            transform.undo(&transform_info, &mut transformed);
+            assert_eq!(transformed.instrs(), original.instrs());
+        }
+
+        let mut empty = Candidate::from_instrs(vec![]);
+        let transform_info = transform.do_transform(
+            &mut rand::thread_rng(),
+            TransformKind::Insert,
+            &instr_whitelist,
+            &mut empty,
+            &|_, _| None,
+        );
+        assert!(transform_info.success);
+        assert_eq!(empty.instrs().len(), 1);
+        transform.undo(&transform_info, &mut empty);
+        assert!(empty.instrs().is_empty());
+    }
+
+    #[test]
+    fn transform_selector_test() {
+        // Only insert has weight.
+        let mut selector = TransformSelector::new(&[0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0], true);
+        for _ in 0..ADAPTIVE_WINDOW {
+            let kind = selector.sample(&mut rand::thread_rng());
+            assert_eq!(kind, TransformKind::Insert);
+            selector.record(kind, true, false);
+        }
+        // Weight updates keep zero weights at zero.
+        assert_eq!(
+            selector.sample(&mut rand::thread_rng()),
+            TransformKind::Insert
+        );
+
+        let stats = selector.stats();
+        assert_eq!(stats[4].0, TransformKind::Insert);
+        assert_eq!(
+            stats[4].1,
+            TransformStats {
+                proposals: ADAPTIVE_WINDOW,
+                acceptances: ADAPTIVE_WINDOW,
+                improvements: 0,
+            }
+        );
+        assert_eq!(stats[0].1, TransformStats::default());
+    }
+
     #[test]
     fn swap_transform_test() {
-        let transform = Transform::new(vec![ValueType::I32], vec![]);
-        let instr_whitelist = Whitelist::new(1, 0, &[1]);
+        let transform = Transform::new(vec![ValueType::I32], vec![], 3);
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 1, 0, &[1], &[]);
 
         let original =
             Candidate::from_instrs(vec![Instruction::GetLocal(0), Instruction::I32Const(1)]);
@@ -276,6 +627,7 @@ mod test {
             TransformKind::Swap,
             &instr_whitelist,
             &mut transformed,
+            &|_, _| None,
         );
 
         if transform_info.success {
@@ -289,4 +641,66 @@ mod test {
         println!("{:?}", transformed);
         println!("{:?}", original);
     }
+
+    #[test]
+    fn equiv_local_idx_test() {
+        // The last local is a scratch local of the same type as the parameter.
+        let transform = Transform::new(
+            vec![ValueType::I32],
+            vec![ValueType::I64, ValueType::I32],
+            3,
+        );
+        let mut seen = [false; 3];
+        for _ in 0..100 {
+            let idx = transform.get_equiv_local_idx(&mut rand::thread_rng(), 0);
+            seen[idx as usize] = true;
+            assert_eq!(transform.get_equiv_local_idx(&mut rand::thread_rng(), 1), 1);
+        }
+        assert_eq!(seen, [true, false, true]);
+    }
+
+    #[test]
+    fn hole_transform_test() {
+        let transform = Transform::new(vec![ValueType::I32], vec![], 3);
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 1, 0, &[1], &[]);
+        let solve_hole = |instrs: &[Instruction], idx: usize| {
+            assert_eq!(instrs[idx], Instruction::I32Const(1));
+            Some(0x5555_5555)
+        };
+
+        let original = Candidate::from_instrs(vec![
+            Instruction::GetLocal(0),
+            Instruction::I32Const(1),
+            Instruction::I32And,
+        ]);
+        let mut transformed = original.clone();
+        let transform_info = transform.do_transform(
+            &mut rand::thread_rng(),
+            TransformKind::Hole,
+            &instr_whitelist,
+            &mut transformed,
+            &solve_hole,
+        );
+        assert!(transform_info.success);
+        assert_eq!(transformed.instrs()[1], Instruction::I32Const(0x5555_5555));
+        transform.undo(&transform_info, &mut transformed);
+        assert_eq!(transformed.instrs(), original.instrs());
+
+        // Neither candidates without constants nor stack invalid ones have holes to fill.
+        for instrs in &[
+            vec![Instruction::GetLocal(0), Instruction::I32Eqz],
+            vec![Instruction::I32Const(1), Instruction::I32And],
+        ] {
+            let mut transformed = Candidate::from_instrs(instrs.clone());
+            let transform_info = transform.do_transform(
+                &mut rand::thread_rng(),
+                TransformKind::Hole,
+                &instr_whitelist,
+                &mut transformed,
+                &solve_hole,
+            );
+            assert!(!transform_info.success);
+            assert_eq!(transformed.instrs(), &instrs[..]);
+        }
+    }
 }
@@ -1,33 +1,33 @@
+use crate::parity_wasm_utils::serde_instrs;
 use crate::wasm::Whitelist;
-use parity_wasm::elements::Instruction;
+use parity_wasm::elements::{Instruction, ValueType};
+use rand::seq::SliceRandom;
 use rand::Rng;
-use std::cmp::Ordering;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::result::Result;
 
-#[derive(Clone, Debug, Eq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Candidate {
     instrs: Vec<Instruction>,
 
     // Enumerative Search Specific Fields.
     next_index: usize,
     num_values_on_stack: i32,
+    /// The accumulated cost of the instructions appended so far.
+    cost: u32,
 }
 
-impl Ord for Candidate {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.next_index.cmp(&other.next_index()).reverse()
-    }
-}
-
-impl PartialOrd for Candidate {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+/// Candidates are serialized as their instructions in the binary format, the enumerative search
+/// specific fields are not kept.
+impl Serialize for Candidate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde_instrs::serialize(&self.instrs, serializer)
     }
 }
 
-impl PartialEq for Candidate {
-    fn eq(&self, other: &Self) -> bool {
-        self.next_index == other.next_index()
+impl<'de> Deserialize<'de> for Candidate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        serde_instrs::deserialize(deserializer).map(Self::from_instrs)
     }
 }
 
@@ -51,7 +51,41 @@ impl Candidate {
             instrs: vec![Instruction::Nop; max_length],
             next_index: 0,
             num_values_on_stack: 0,
+            cost: 0,
+        }
+    }
+
+    /// Random stack valid program with given length. Every prefix is assumed to be completable to
+    /// a valid program, which holds as long as the whitelist has instructions that push a value,
+    /// and instructions that pop two values and push one. Falls back to Nop otherwise.
+    pub fn new_random_valid<R: Rng + ?Sized>(
+        rng: &mut R,
+        instr_whitelist: &Whitelist,
+        length: usize,
+    ) -> Self {
+        let nop = Instruction::Nop;
+        let mut instrs = Vec::with_capacity(length);
+        let mut num_values_on_stack: i32 = 0;
+        for i in 0..length {
+            let num_remaining = (length - i - 1) as i32;
+            // Each remaining instruction can change the number of values on the stack by one.
+            let choices: Vec<&Instruction> = instr_whitelist
+                .iter()
+                .chain(std::iter::once(&nop))
+                .filter(|instr| {
+                    let (pop, push) = instr_whitelist.push_pop_cnts(instr);
+                    let cnt = num_values_on_stack - pop + push;
+                    pop <= num_values_on_stack && (cnt - 1).abs() <= num_remaining
+                })
+                .collect();
+            let instr = choices
+                .choose(rng)
+                .map_or(Instruction::Nop, |instr| (*instr).clone());
+            let (pop, push) = instr_whitelist.push_pop_cnts(&instr);
+            num_values_on_stack += push - pop;
+            instrs.push(instr);
         }
+        Self::from_instrs(instrs)
     }
 
     pub fn from_instrs(instrs: Vec<Instruction>) -> Self {
@@ -60,6 +94,7 @@ impl Candidate {
             instrs,
             next_index: 0,
             num_values_on_stack: 0,
+            cost: 0,
         }
     }
 
@@ -95,6 +130,19 @@ impl Candidate {
         Ok(candidate)
     }
 
+    /// Attempts to append the instruction like [try_append](Candidate::try_append), and adds
+    /// given cost of the instruction to the accumulated cost.
+    pub fn try_append_with_cost(
+        &self,
+        instr_whitelist: &Whitelist,
+        instr: Instruction,
+        cost: u32,
+    ) -> Result<Self, AppendError> {
+        let mut candidate = self.try_append(instr_whitelist, instr)?;
+        candidate.cost = candidate.cost.saturating_add(cost);
+        Ok(candidate)
+    }
+
     pub fn instrs(&self) -> &[Instruction] {
         &self.instrs
     }
@@ -111,6 +159,10 @@ impl Candidate {
         self.num_values_on_stack
     }
 
+    pub fn cost(&self) -> u32 {
+        self.cost
+    }
+
     /// Removes Nop instructions.
     pub fn strip_nops(&mut self) {
         self.instrs = self
@@ -121,6 +173,38 @@ impl Candidate {
             .collect();
     }
 
+    /// Drops the scratch locals the candidate doesn't use, renumbering the ones it uses so that
+    /// they follow the other locals in the same order. The scratch locals start at index `first`
+    /// and have given types. Returns the types of the kept scratch locals.
+    pub fn drop_unused_locals(&mut self, first: u32, local_types: &[ValueType]) -> Vec<ValueType> {
+        let mut renumbered = Vec::with_capacity(local_types.len());
+        let mut kept = Vec::new();
+        for (idx, local_type) in (first..).zip(local_types) {
+            let used = self.instrs.iter().any(|instr| match instr {
+                Instruction::GetLocal(i) | Instruction::SetLocal(i) | Instruction::TeeLocal(i) => {
+                    *i == idx
+                }
+                _ => false,
+            });
+            renumbered.push(first + kept.len() as u32);
+            if used {
+                kept.push(*local_type);
+            }
+        }
+
+        for instr in self.instrs.iter_mut() {
+            match instr {
+                Instruction::GetLocal(i) | Instruction::SetLocal(i) | Instruction::TeeLocal(i)
+                    if *i >= first =>
+                {
+                    *i = renumbered[(*i - first) as usize];
+                }
+                _ => {}
+            }
+        }
+        kept
+    }
+
     pub fn get_rand_instr<R: Rng + ?Sized>(&self, rng: &mut R) -> (usize, Instruction) {
         let indices = rand::seq::index::sample(rng, self.instrs.len(), 1);
         (indices.index(0), self.instrs[indices.index(0)].clone())
@@ -175,9 +259,23 @@ mod tests {
         assert_eq!(candidate.next_index(), 0);
     }
 
+    #[test]
+    fn random_valid_test() {
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 1, 0, &[1], &[]);
+        for length in 1..8 {
+            let candidate =
+                Candidate::new_random_valid(&mut rand::thread_rng(), &instr_whitelist, length);
+            assert_eq!(candidate.instrs().len(), length);
+            assert_eq!(
+                candidate.is_stack_valid(&instr_whitelist),
+                StackState::Valid
+            );
+        }
+    }
+
     #[test]
     fn try_append_index_out_of_bounds_test() {
-        let instr_whitelist = Whitelist::new(1, 0, &[]);
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 1, 0, &[], &[]);
         let candidate: Candidate = Candidate::new(0);
         let result = candidate.try_append(&instr_whitelist, Instruction::Nop);
         assert!(result.is_err());
@@ -186,7 +284,7 @@ mod tests {
 
     #[test]
     fn try_append_stack_underflow_test() {
-        let instr_whitelist = Whitelist::new(1, 0, &[]);
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 1, 0, &[], &[]);
         let candidate: Candidate = Candidate::new(1);
 
         let result = candidate.try_append(&instr_whitelist, Instruction::I32Add);
@@ -196,7 +294,7 @@ mod tests {
 
     #[test]
     fn try_append_stack_overflow_test() {
-        let instr_whitelist = Whitelist::new(1, 0, &[1]);
+        let instr_whitelist = Whitelist::new(&mut rand::thread_rng(), 1, 0, &[1], &[]);
         let mut candidate: Candidate = Candidate::new(3);
         let mut result = candidate.try_append(&instr_whitelist, Instruction::I32Const(1));
         assert!(result.is_ok());
@@ -209,4 +307,31 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), AppendError::StackOverflow);
     }
+
+    #[test]
+    fn drop_unused_locals_test() {
+        // Locals 1 to 3 are scratch locals, only the i64 one is unused.
+        let mut candidate = Candidate::from_instrs(vec![
+            Instruction::GetLocal(0),
+            Instruction::TeeLocal(3),
+            Instruction::SetLocal(1),
+            Instruction::GetLocal(3),
+        ]);
+        let kept =
+            candidate.drop_unused_locals(1, &[ValueType::I32, ValueType::I64, ValueType::I32]);
+        assert_eq!(kept, vec![ValueType::I32, ValueType::I32]);
+        assert_eq!(
+            candidate.instrs(),
+            &[
+                Instruction::GetLocal(0),
+                Instruction::TeeLocal(2),
+                Instruction::SetLocal(1),
+                Instruction::GetLocal(2),
+            ]
+        );
+
+        assert!(candidate
+            .drop_unused_locals(3, &[ValueType::I32])
+            .is_empty());
+    }
 }
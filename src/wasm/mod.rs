@@ -1,5 +1,7 @@
 pub use candidate::*;
 pub mod candidate;
+pub use semantics::*;
+pub mod semantics;
 pub use spec::*;
 pub mod spec;
 pub use whitelist::*;
@@ -0,0 +1,128 @@
+use parity_wasm::elements::Instruction;
+
+/// Returns the number of operands of given side effect free i32 instruction, or `None` if it is
+/// not one. Constants and `get_local` have none.
+pub fn pure_i32_arity(instr: &Instruction) -> Option<usize> {
+    match instr {
+        Instruction::I32Const(_) | Instruction::GetLocal(_) => Some(0),
+        Instruction::I32Clz
+        | Instruction::I32Ctz
+        | Instruction::I32Popcnt
+        | Instruction::I32Eqz => Some(1),
+        Instruction::I32Add
+        | Instruction::I32Sub
+        | Instruction::I32Mul
+        | Instruction::I32DivS
+        | Instruction::I32DivU
+        | Instruction::I32RemS
+        | Instruction::I32RemU
+        | Instruction::I32And
+        | Instruction::I32Or
+        | Instruction::I32Xor
+        | Instruction::I32Shl
+        | Instruction::I32ShrS
+        | Instruction::I32ShrU
+        | Instruction::I32Rotl
+        | Instruction::I32Rotr
+        | Instruction::I32Eq
+        | Instruction::I32Ne
+        | Instruction::I32LtS
+        | Instruction::I32LtU
+        | Instruction::I32GtS
+        | Instruction::I32GtU
+        | Instruction::I32LeS
+        | Instruction::I32LeU
+        | Instruction::I32GeS
+        | Instruction::I32GeU => Some(2),
+        _ => None,
+    }
+}
+
+/// Applies given side effect free i32 operator to its operands in the order they were pushed.
+/// Returns `None` if the operator traps. Panics on instructions with operands that
+/// [pure_i32_arity](pure_i32_arity) doesn't know, or on the wrong number of operands.
+pub fn eval_i32(instr: &Instruction, operands: &[i32]) -> Option<i32> {
+    let value = match (instr, operands) {
+        (Instruction::I32Clz, [x]) => x.leading_zeros() as i32,
+        (Instruction::I32Ctz, [x]) => x.trailing_zeros() as i32,
+        (Instruction::I32Popcnt, [x]) => x.count_ones() as i32,
+        (Instruction::I32Eqz, [x]) => (*x == 0) as i32,
+        (Instruction::I32Add, [x, y]) => x.wrapping_add(*y),
+        (Instruction::I32Sub, [x, y]) => x.wrapping_sub(*y),
+        (Instruction::I32Mul, [x, y]) => x.wrapping_mul(*y),
+        // Division by zero and overflow trap, the remainder of overflowing division is zero.
+        (Instruction::I32DivS, [x, y]) => x.checked_div(*y)?,
+        (Instruction::I32DivU, [x, y]) => (*x as u32).checked_div(*y as u32)? as i32,
+        (Instruction::I32RemS, [x, y]) => {
+            if *y == 0 {
+                return None;
+            }
+            x.wrapping_rem(*y)
+        }
+        (Instruction::I32RemU, [x, y]) => (*x as u32).checked_rem(*y as u32)? as i32,
+        (Instruction::I32And, [x, y]) => x & y,
+        (Instruction::I32Or, [x, y]) => x | y,
+        (Instruction::I32Xor, [x, y]) => x ^ y,
+        // Shift counts are taken modulo the bit width.
+        (Instruction::I32Shl, [x, y]) => x.wrapping_shl(*y as u32),
+        (Instruction::I32ShrS, [x, y]) => x.wrapping_shr(*y as u32),
+        (Instruction::I32ShrU, [x, y]) => (*x as u32).wrapping_shr(*y as u32) as i32,
+        (Instruction::I32Rotl, [x, y]) => x.rotate_left(*y as u32 % 32),
+        (Instruction::I32Rotr, [x, y]) => x.rotate_right(*y as u32 % 32),
+        (Instruction::I32Eq, [x, y]) => (x == y) as i32,
+        (Instruction::I32Ne, [x, y]) => (x != y) as i32,
+        (Instruction::I32LtS, [x, y]) => (x < y) as i32,
+        (Instruction::I32LtU, [x, y]) => ((*x as u32) < (*y as u32)) as i32,
+        (Instruction::I32GtS, [x, y]) => (x > y) as i32,
+        (Instruction::I32GtU, [x, y]) => (*x as u32 > *y as u32) as i32,
+        (Instruction::I32LeS, [x, y]) => (x <= y) as i32,
+        (Instruction::I32LeU, [x, y]) => (*x as u32 <= *y as u32) as i32,
+        (Instruction::I32GeS, [x, y]) => (x >= y) as i32,
+        (Instruction::I32GeU, [x, y]) => (*x as u32 >= *y as u32) as i32,
+        unexpected => panic!("Can't evaluate {:?}", unexpected),
+    };
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eval_i32_test() {
+        assert_eq!(
+            eval_i32(&Instruction::I32Add, &[i32::max_value(), 1]),
+            Some(i32::min_value())
+        );
+        assert_eq!(eval_i32(&Instruction::I32Sub, &[1, 3]), Some(-2));
+        assert_eq!(eval_i32(&Instruction::I32DivS, &[7, 0]), None);
+        assert_eq!(
+            eval_i32(&Instruction::I32DivS, &[i32::min_value(), -1]),
+            None
+        );
+        assert_eq!(
+            eval_i32(&Instruction::I32RemS, &[i32::min_value(), -1]),
+            Some(0)
+        );
+        assert_eq!(
+            eval_i32(&Instruction::I32DivU, &[-2, 2]),
+            Some(i32::max_value())
+        );
+        assert_eq!(eval_i32(&Instruction::I32Shl, &[1, 33]), Some(2));
+        assert_eq!(eval_i32(&Instruction::I32ShrU, &[-1, 28]), Some(15));
+        assert_eq!(eval_i32(&Instruction::I32ShrS, &[-16, 2]), Some(-4));
+        assert_eq!(
+            eval_i32(&Instruction::I32Rotl, &[i32::min_value(), 1]),
+            Some(1)
+        );
+        assert_eq!(eval_i32(&Instruction::I32LtU, &[1, -1]), Some(1));
+        assert_eq!(eval_i32(&Instruction::I32LtS, &[1, -1]), Some(0));
+        assert_eq!(eval_i32(&Instruction::I32Clz, &[1]), Some(31));
+        assert_eq!(eval_i32(&Instruction::I32Eqz, &[0]), Some(1));
+
+        assert_eq!(pure_i32_arity(&Instruction::GetLocal(0)), Some(0));
+        assert_eq!(pure_i32_arity(&Instruction::I32Popcnt), Some(1));
+        assert_eq!(pure_i32_arity(&Instruction::I32GeU), Some(2));
+        assert_eq!(pure_i32_arity(&Instruction::TeeLocal(0)), None);
+    }
+}
@@ -1,14 +1,37 @@
 use crate::parity_wasm_utils;
+use clap::arg_enum;
 use parity_wasm::elements::serialize;
-use parity_wasm::elements::{FuncBody, FunctionType, Instruction, Instructions, ValueType};
+use parity_wasm::elements::{
+    FuncBody, FunctionType, Instruction, Instructions, Local, Serialize, ValueType, VarUint32,
+};
+
+arg_enum! {
+    /// The types of scratch locals candidates can use on top of the locals of the spec. Only i32
+    /// for now, since the local instructions of the whitelist take every local to be an i32 one.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum LocalType {
+        I32,
+    }
+}
+
+impl From<LocalType> for ValueType {
+    fn from(local_type: LocalType) -> Self {
+        match local_type {
+            LocalType::I32 => ValueType::I32,
+        }
+    }
+}
 
 /// Struct to hold spec function metadata.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Spec {
     // Fields representing the spec.
     spec_func_type: FunctionType,
-    spec_local_types: Vec<ValueType>,
+    /// The types of the locals of the spec followed by the scratch locals.
+    local_types: Vec<ValueType>,
     spec_func_body: FuncBody,
+    /// The locals of candidate function bodies, the ones of the spec and the scratch locals.
+    locals: Vec<Local>,
 
     /// This field contains WASM binary generated from above func_type, with function name
     /// 'candidate'. It is initialized once when this struct is initialized and reused to avoid
@@ -18,11 +41,20 @@ pub struct Spec {
 }
 
 impl Spec {
-    pub fn new(spec_func_type: &FunctionType, spec_func_body: &FuncBody) -> Self {
-        let mut binary = parity_wasm_utils::build_module(
+    /// The candidate function in the generated binary is instrumented to trap after consuming
+    /// given fuel, see [inject_fuel](parity_wasm_utils::inject_fuel). Candidates can use scratch
+    /// locals of given types on top of the locals of the spec.
+    pub fn new(
+        spec_func_type: &FunctionType,
+        spec_func_body: &FuncBody,
+        fuel: u32,
+        scratch_local_types: &[ValueType],
+    ) -> Self {
+        let mut binary = parity_wasm_utils::build_module_with_fuel(
             "candidate",
             &spec_func_type,
             FuncBody::new(vec![], Instructions::new(vec![])),
+            fuel,
         )
         .to_bytes()
         .unwrap();
@@ -40,17 +72,26 @@ impl Spec {
         let binary_len = binary.len();
 
         // Keep track of the local types of the spec.
-        let mut spec_local_types = Vec::new();
+        let mut local_types = Vec::new();
         for l in locals {
             for _ in 0..l.count() {
-                spec_local_types.push(l.value_type());
+                local_types.push(l.value_type());
             }
         }
+        local_types.extend_from_slice(scratch_local_types);
+
+        let mut locals = locals.to_vec();
+        locals.extend(
+            scratch_local_types
+                .iter()
+                .map(|local_type| Local::new(1, *local_type)),
+        );
 
         Self {
             spec_func_type: spec_func_type.clone(),
-            spec_local_types,
+            local_types,
             spec_func_body: spec_func_body.clone(),
+            locals,
             binary,
             binary_len,
         }
@@ -76,8 +117,10 @@ impl Spec {
         &self.spec_func_type.params()
     }
 
+    /// Returns the types of the locals candidates can use, the locals of the spec followed by the
+    /// scratch locals. This doesn't include the parameters.
     pub fn local_types(&self) -> &[ValueType] {
-        &self.spec_local_types
+        &self.local_types
     }
 
     /// Returns the number of parameterss. This doesn't include the number of locals.
@@ -87,11 +130,12 @@ impl Spec {
         self.spec_func_type.params().len()
     }
 
-    /// Returns the number of locals. This doesn't include the number of parameters.
+    /// Returns the number of locals candidates can use, including the scratch locals. This doesn't
+    /// include the number of parameters.
     ///
     /// See [num_params](Spec.num_params).
     pub fn num_locals(&self) -> usize {
-        self.spec_local_types.len()
+        self.local_types.len()
     }
 
     /// Returns the number of instructions, excluding END instruction at the end.
@@ -102,24 +146,53 @@ impl Spec {
         self.spec_func_body.code().elements().len() - 1
     }
 
+    /// Returns the instructions of the spec function, excluding END instruction at the end.
+    pub fn instrs(&self) -> &[Instruction] {
+        let instrs = self.spec_func_body.code().elements();
+        &instrs[..instrs.len() - 1]
+    }
+
     pub fn get_binary_with_instrs(&mut self, instrs: &[Instruction]) -> &[u8] {
         // NOTE(taegyunkim): As commented in the constructor we need to append an END instruction,
         // to make this a valid function representation.
-        let mut instrs = instrs.to_vec();
+        let mut instrs = parity_wasm_utils::inject_fuel(instrs, 0);
         instrs.push(Instruction::End);
 
-        let func_body = FuncBody::new(
-            self.spec_func_body.locals().to_vec(),
-            Instructions::new(instrs),
-        );
+        let func_body = FuncBody::new(self.locals.clone(), Instructions::new(instrs));
 
         // TODO(taegyunkim): Avoid conversion to FuncBody and convert instruction list to binary.
         let func_binary = serialize::<FuncBody>(func_body).unwrap();
 
+        // The code section starts with its size in LEB128, which counts the function count byte
+        // too, followed by the function count.
         self.binary.truncate(self.binary_len);
-        self.binary.extend(&[func_binary.len() as u8 + 1, 1]);
+        VarUint32::from(func_binary.len() as u32 + 1)
+            .serialize(&mut self.binary)
+            .unwrap();
+        self.binary.push(1);
         self.binary.extend(func_binary);
 
         &self.binary
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_candidate_binary_test() {
+        let func_type = FunctionType::new(vec![ValueType::I32], Some(ValueType::I32));
+        let func_body = FuncBody::new(vec![], Instructions::new(vec![Instruction::End]));
+        let mut spec = Spec::new(&func_type, &func_body, 100, &[]);
+
+        // The code section of this candidate is longer than 127 bytes, so its size takes more
+        // than one byte.
+        let mut instrs = vec![Instruction::GetLocal(0)];
+        for _ in 0..100 {
+            instrs.push(Instruction::I32Const(1));
+            instrs.push(Instruction::I32Add);
+        }
+        wasmparser::validate(spec.get_binary_with_instrs(&instrs), None).unwrap();
+    }
+}
@@ -1,6 +1,12 @@
-use parity_wasm::elements::Instruction;
+use crate::parity_wasm_utils::serde_instrs;
+use crate::perf::opcode_name;
+use crate::solver;
+use parity_wasm::elements::{Instruction, Module};
 use rand::seq::SliceRandom;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::path::Path;
 
 const I32BINOP: [Instruction; 15] = [
     Instruction::I32Add,
@@ -47,18 +53,215 @@ const LOCALOP: [fn(u32) -> Instruction; 3] = [
     |i| Instruction::TeeLocal(i),
 ];
 
+/// The max number of constants mined from a spec that are added to the whitelisted ones. Each one
+/// multiplies the candidates of each length enumerative search goes through.
+pub const MAX_MINED_CONSTANTS: usize = 8;
+
+/// Returns the constants of given instructions, followed by the values derived from each of them:
+/// its negation, the values one above and one below, its bitwise not and, for a shift amount, the
+/// amount shifting by the rest of its width. Only i32 constants are whitelisted, so values derived
+/// from an `I64Const` that don't fit in an i32 are dropped.
+pub fn mine_constants(instrs: &[Instruction]) -> Vec<i32> {
+    let spec_constants: Vec<(i64, i64)> = instrs
+        .iter()
+        .filter_map(|instr| match instr {
+            Instruction::I32Const(c) => Some((i64::from(*c), 32)),
+            Instruction::I64Const(c) => Some((*c, 64)),
+            _ => None,
+        })
+        .collect();
+    let mut values: Vec<(i64, i64)> = spec_constants.clone();
+    for &(value, width) in &spec_constants {
+        let mut derived = vec![
+            value.wrapping_neg(),
+            value.wrapping_add(1),
+            value.wrapping_sub(1),
+            !value,
+        ];
+        if 0 < value && value < width {
+            derived.push(width - value);
+        }
+        values.extend(derived.into_iter().map(|value| (value, width)));
+    }
+
+    let mut constants = Vec::new();
+    for (value, width) in values {
+        let value = if width == 32 {
+            // Wraps like i32 arithmetic does.
+            Some(value as i32)
+        } else {
+            i32::try_from(value).ok()
+        };
+        if let Some(value) = value {
+            if !constants.contains(&value) {
+                constants.push(value);
+            }
+        }
+    }
+    constants
+}
+
+/// Returns a pair of numbers, the number of values the given instruction pops from the WASM
+/// runtime stack, and the number of values the given instruction pushes to the stack, or `None` if
+/// the stack check doesn't support the instruction.
+fn stack_effect(instr: &Instruction) -> Option<(i32, i32)> {
+    match *instr {
+        // i32 binary operators
+        Instruction::I32Add
+        | Instruction::I32Sub
+        | Instruction::I32Mul
+        | Instruction::I32DivS
+        | Instruction::I32DivU
+        | Instruction::I32RemS
+        | Instruction::I32RemU
+        | Instruction::I32And
+        | Instruction::I32Or
+        | Instruction::I32Xor
+        | Instruction::I32Shl
+        | Instruction::I32ShrS
+        | Instruction::I32ShrU
+        | Instruction::I32Rotl
+        | Instruction::I32Rotr => Some((2, 1)),
+        // i32 relative operators
+        Instruction::I32Eq
+        | Instruction::I32Ne
+        | Instruction::I32LtS
+        | Instruction::I32LtU
+        | Instruction::I32GtS
+        | Instruction::I32GtU
+        | Instruction::I32LeS
+        | Instruction::I32LeU
+        | Instruction::I32GeS
+        | Instruction::I32GeU => Some((2, 1)),
+        // i32 testop
+        Instruction::I32Eqz => Some((1, 1)),
+        // i32 unop
+        Instruction::I32Clz | Instruction::I32Ctz | Instruction::I32Popcnt => Some((1, 1)),
+        Instruction::I32Const(_) => Some((0, 1)),
+        Instruction::GetLocal(_) => Some((0, 1)),
+        Instruction::SetLocal(_) => Some((1, 0)),
+        Instruction::TeeLocal(_) => Some((1, 1)),
+        Instruction::Nop => Some((0, 0)),
+        _ => None,
+    }
+}
+
+/// The opcodes to whitelist, as read from a file.
+#[derive(Debug, Deserialize)]
+struct WhitelistConfig {
+    opcodes: Vec<String>,
+}
+
+/// Reads the opcodes to whitelist from a TOML file if its extension is `.toml`, or from a JSON
+/// file otherwise. Both list them under `opcodes`, e.g. `opcodes = ["i32.and", "local.get"]`.
+pub fn read_opcodes(file: impl AsRef<Path>) -> Vec<String> {
+    let file = file.as_ref();
+    let contents = std::fs::read_to_string(file).expect("Failed to read whitelist.");
+    let is_toml = file.extension().map_or(false, |ext| ext == "toml");
+    parse_opcodes(&contents, is_toml)
+}
+
+fn parse_opcodes(contents: &str, is_toml: bool) -> Vec<String> {
+    let config: WhitelistConfig = if is_toml {
+        toml::from_str(contents).expect("Failed to parse TOML whitelist.")
+    } else {
+        serde_json::from_str(contents).expect("Failed to parse JSON whitelist.")
+    };
+    config.opcodes
+}
+
+/// Returns the instruction with given opcode in the text format, with a zero immediate for the
+/// opcodes that take one, like the ones of locals and constants.
+pub fn parse_opcode(opcode: &str) -> Option<Instruction> {
+    [opcode.to_string(), format!("{} 0", opcode)]
+        .iter()
+        .find_map(|text| {
+            let binary = wat::parse_str(format!("(module (func (local i32) {}))", text)).ok()?;
+            let module = Module::from_bytes(binary).ok()?;
+            match module.code_section()?.bodies()[0].code().elements() {
+                [instr, Instruction::End] => Some(instr.clone()),
+                _ => None,
+            }
+        })
+}
+
+/// Checks that both the stack check and the solver support the instructions with given opcodes,
+/// and returns the names the whitelist knows them by. Opcodes are written as in the text format
+/// without immediates, e.g. `i32.add` or `local.get`, and `i32.const` stands for all the
+/// constants. Candidates can't be built unless one of the instructions pushes a value without
+/// popping any.
+pub fn validate_opcodes(opcodes: &[String]) -> Vec<String> {
+    let instrs: Vec<Instruction> = opcodes
+        .iter()
+        .map(|opcode| {
+            let instr =
+                parse_opcode(opcode).unwrap_or_else(|| panic!("Unknown opcode {}.", opcode));
+            // Candidates are padded with it, it is never enumerated.
+            assert!(
+                instr != Instruction::Nop,
+                "Opcode {} can't be whitelisted.",
+                opcode
+            );
+            assert!(
+                stack_effect(&instr).is_some(),
+                "Opcode {} is not supported by the stack check.",
+                opcode
+            );
+            assert!(
+                solver::is_supported(&instr),
+                "Opcode {} is not supported by the solver.",
+                opcode
+            );
+            instr
+        })
+        .collect();
+    assert!(
+        instrs.is_empty()
+            || instrs
+                .iter()
+                .any(|instr| stack_effect(instr) == Some((0, 1))),
+        "The whitelist has no instruction that pushes a value, like local.get or i32.const."
+    );
+    instrs.iter().map(opcode_name).collect()
+}
+
+/// Returns the opcodes of all the instructions [Whitelist::new](Whitelist::new) supports, the ones
+/// it whitelists when no opcode is given.
+pub fn supported_opcodes() -> Vec<String> {
+    I32BINOP
+        .iter()
+        .chain(&I32UNOP)
+        .chain(&I32RELOP)
+        .chain(&I32TESTOP)
+        .cloned()
+        .chain(LOCALOP.iter().map(|op| op(0)))
+        .chain(std::iter::once(Instruction::I32Const(0)))
+        .map(|instr| opcode_name(&instr))
+        .collect()
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Whitelist {
     _num_params: usize,
-    // TODO(taegyunkim): Support increasing the number of locals.
+    /// The number of locals, including the scratch locals of the spec.
     _num_locals: usize,
     // TODO(taegyunkim): Support other primitive types.
     _constants: Vec<i32>,
 
+    #[serde(with = "serde_instrs")]
     instrs: Vec<Instruction>,
 }
 
 impl Whitelist {
-    pub fn new(num_params: usize, num_locals: usize, constants: &[i32]) -> Self {
+    /// Whitelists the supported instructions with given opcodes, as returned by
+    /// [validate_opcodes](validate_opcodes), or all of them if no opcode is given.
+    pub fn new<R: Rng + ?Sized>(
+        rng: &mut R,
+        num_params: usize,
+        num_locals: usize,
+        constants: &[i32],
+        opcodes: &[String],
+    ) -> Self {
         let mut instrs = Vec::new();
         instrs.extend_from_slice(&I32BINOP);
         instrs.extend_from_slice(&I32UNOP);
@@ -75,7 +278,10 @@ impl Whitelist {
             instrs.push(Instruction::I32Const(*c));
         }
 
-        instrs.shuffle(&mut rand::thread_rng());
+        if !opcodes.is_empty() {
+            instrs.retain(|instr| opcodes.contains(&opcode_name(instr)));
+        }
+        instrs.shuffle(rng);
 
         Self {
             _num_params: num_params,
@@ -103,44 +309,9 @@ impl Whitelist {
     /// Returns a pair of numbers, the number of values the given instruction pops from the WASM
     /// runtime stack, and the number of values the given instruction pushes to the stack.
     pub fn push_pop_cnts(&self, instr: &Instruction) -> (i32, i32) {
-        match *instr {
-            // i32 binary operators
-            Instruction::I32Add
-            | Instruction::I32Sub
-            | Instruction::I32Mul
-            | Instruction::I32DivS
-            | Instruction::I32DivU
-            | Instruction::I32RemS
-            | Instruction::I32RemU
-            | Instruction::I32And
-            | Instruction::I32Or
-            | Instruction::I32Xor
-            | Instruction::I32Shl
-            | Instruction::I32ShrS
-            | Instruction::I32ShrU
-            | Instruction::I32Rotl
-            | Instruction::I32Rotr => (2, 1),
-            // i32 relative operators
-            Instruction::I32Eq
-            | Instruction::I32Ne
-            | Instruction::I32LtS
-            | Instruction::I32LtU
-            | Instruction::I32GtS
-            | Instruction::I32GtU
-            | Instruction::I32LeS
-            | Instruction::I32LeU
-            | Instruction::I32GeS
-            | Instruction::I32GeU => (2, 1),
-            // i32 testop
-            Instruction::I32Eqz => (1, 1),
-            // i32 unop
-            Instruction::I32Clz | Instruction::I32Ctz | Instruction::I32Popcnt => (1, 1),
-            Instruction::I32Const(_) => (0, 1),
-            Instruction::GetLocal(_) => (0, 1),
-            Instruction::SetLocal(_) => (1, 0),
-            Instruction::TeeLocal(_) => (1, 1),
-            Instruction::Nop => (0, 0),
-            _ => {
+        match stack_effect(instr) {
+            Some(cnts) => cnts,
+            None => {
                 if self.instrs.contains(instr) {
                     panic!("Forgot to implement instruction {}", instr);
                 } else {
@@ -171,7 +342,7 @@ impl Whitelist {
             | Instruction::I32ShrS
             | Instruction::I32ShrU
             | Instruction::I32Rotl
-            | Instruction::I32Rotr => I32BINOP.choose(rng).unwrap().clone(),
+            | Instruction::I32Rotr => self.choose_whitelisted(rng, I32BINOP.to_vec(), instr),
             Instruction::I32Eq
             | Instruction::I32Ne
             | Instruction::I32LtS
@@ -181,14 +352,14 @@ impl Whitelist {
             | Instruction::I32LeS
             | Instruction::I32LeU
             | Instruction::I32GeS
-            | Instruction::I32GeU => I32RELOP.choose(rng).unwrap().clone(),
+            | Instruction::I32GeU => self.choose_whitelisted(rng, I32RELOP.to_vec(), instr),
             Instruction::I32Eqz => Instruction::I32Eqz,
             Instruction::I32Clz | Instruction::I32Ctz | Instruction::I32Popcnt => {
-                I32UNOP.choose(rng).unwrap().clone()
+                self.choose_whitelisted(rng, I32UNOP.to_vec(), instr)
             }
             Instruction::I32Const(i) => Instruction::I32Const(i),
             Instruction::GetLocal(i) | Instruction::SetLocal(i) | Instruction::TeeLocal(i) => {
-                LOCALOP.choose(rng).unwrap()(i)
+                self.choose_whitelisted(rng, LOCALOP.iter().map(|op| op(i)).collect(), instr)
             }
             Instruction::Nop => Instruction::Nop,
             _ => {
@@ -201,6 +372,18 @@ impl Whitelist {
         }
     }
 
+    /// Returns a random whitelisted instruction among given ones, or given instruction if none of
+    /// them is whitelisted.
+    fn choose_whitelisted<R: Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+        mut instrs: Vec<Instruction>,
+        instr: &Instruction,
+    ) -> Instruction {
+        instrs.retain(|instr| self.is_instr_whitelisted(instr));
+        instrs.choose(rng).cloned().unwrap_or_else(|| instr.clone())
+    }
+
     pub fn iter(&self) -> std::slice::Iter<Instruction> {
         self.instrs.iter()
     }
@@ -212,7 +395,7 @@ mod tests {
 
     #[test]
     fn check_test() {
-        let whitelist = Whitelist::new(3, 0, &[0, 1, 2]);
+        let whitelist = Whitelist::new(&mut rand::thread_rng(), 3, 0, &[0, 1, 2], &[]);
 
         for instr in &I32BINOP {
             assert!(whitelist.is_instr_whitelisted(instr));
@@ -236,9 +419,122 @@ mod tests {
         }
     }
 
+    #[test]
+    fn supported_opcodes_test() {
+        let opcodes = supported_opcodes();
+        for opcode in &["i32.add", "get_local", "tee_local", "i32.const"] {
+            assert!(opcodes.contains(&opcode.to_string()));
+        }
+        for opcode in &["i64.add", "nop", "select"] {
+            assert!(!opcodes.contains(&opcode.to_string()));
+        }
+    }
+
+    #[test]
+    fn opcodes_test() {
+        let opcodes = validate_opcodes(&[
+            "i32.and".to_string(),
+            "local.get".to_string(),
+            "i32.const".to_string(),
+        ]);
+        assert_eq!(opcodes, vec!["i32.and", "get_local", "i32.const"]);
+
+        let whitelist = Whitelist::new(&mut rand::thread_rng(), 2, 0, &[0, 1], &opcodes);
+        let mut instrs: Vec<String> = whitelist.iter().map(|instr| instr.to_string()).collect();
+        instrs.sort();
+        assert_eq!(
+            instrs,
+            vec![
+                "get_local 0",
+                "get_local 1",
+                "i32.and",
+                "i32.const 0",
+                "i32.const 1"
+            ]
+        );
+        // Operators are only replaced by whitelisted ones.
+        assert_eq!(
+            whitelist.get_equiv_instr(&mut rand::thread_rng(), &Instruction::I32And),
+            Instruction::I32And
+        );
+        assert_eq!(
+            whitelist.get_equiv_instr(&mut rand::thread_rng(), &Instruction::GetLocal(1)),
+            Instruction::GetLocal(1)
+        );
+    }
+
+    #[test]
+    fn parse_opcodes_test() {
+        let opcodes = vec!["i32.add".to_string(), "i32.shl".to_string()];
+        assert_eq!(
+            parse_opcodes(r#"opcodes = ["i32.add", "i32.shl"]"#, true),
+            opcodes
+        );
+        assert_eq!(
+            parse_opcodes(r#"{"opcodes": ["i32.add", "i32.shl"]}"#, false),
+            opcodes
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Opcode i64.add is not supported by the stack check.")]
+    fn unsupported_opcode_test() {
+        validate_opcodes(&["i64.add".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Opcode nop can't be whitelisted.")]
+    fn nop_opcode_test() {
+        validate_opcodes(&["local.get".to_string(), "nop".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "The whitelist has no instruction that pushes a value")]
+    fn no_leaf_opcode_test() {
+        validate_opcodes(&["i32.add".to_string(), "local.tee".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown opcode i32.frobnicate.")]
+    fn unknown_opcode_test() {
+        validate_opcodes(&["i32.frobnicate".to_string()]);
+    }
+
+    #[test]
+    fn mine_constants_test() {
+        assert_eq!(
+            mine_constants(&[
+                Instruction::GetLocal(0),
+                Instruction::I32Const(7),
+                Instruction::I32Shl,
+            ]),
+            vec![7, -7, 8, 6, -8, 25]
+        );
+        assert_eq!(
+            mine_constants(&[Instruction::I32Const(i32::max_value())]),
+            vec![
+                i32::max_value(),
+                -i32::max_value(),
+                i32::min_value(),
+                i32::max_value() - 1
+            ]
+        );
+        // Values past the i32 range are dropped, 64 - 40 is kept.
+        assert_eq!(
+            mine_constants(&[Instruction::I64Const(40), Instruction::I64Const(1 << 40)]),
+            vec![40, -40, 41, 39, -41, 24]
+        );
+        // The constants of the spec come before the derived values.
+        assert_eq!(
+            mine_constants(&[Instruction::I32Const(3), Instruction::I32Const(-3)]),
+            vec![3, -3, 4, 2, -4, 29, -2]
+        );
+        assert!(mine_constants(&[Instruction::I32Add]).is_empty());
+    }
+
     #[test]
     fn stack_cnt_whitelist_test() {
-        let whitelist = Whitelist::new(1, 0, &[0, 1, 2]);
+        let whitelist = Whitelist::new(&mut rand::thread_rng(), 1, 0, &[0, 1, 2], &[]);
         for instr in whitelist.instrs.iter() {
             let _cnts = whitelist.push_pop_cnts(instr);
         }
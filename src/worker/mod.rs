@@ -0,0 +1,194 @@
+use crate::{exec, wasm, Mode};
+use bus::{Bus, BusReader};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use std::sync::mpsc::{channel, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+/// The number of signals a worker can fall behind. Counterexamples are dropped for all workers
+/// once one falls further behind, they are only shared to speed up the search.
+const BUS_CAPACITY: usize = 1024;
+
+/// Messages broadcast from the coordinator to all workers.
+#[derive(Clone, Debug)]
+enum Signal {
+    /// A counterexample found by the worker with given id.
+    CounterExample(usize, Vec<wasmer_runtime::Value>),
+    Stop,
+}
+
+/// Messages sent from workers to the coordinator.
+enum Event {
+    CounterExample(usize, Vec<wasmer_runtime::Value>),
+    /// A worker returned, the flag is set if it found a candidate.
+    Done(bool),
+    Timeout,
+}
+
+/// The worker side of the channels to the coordinator, along with the worker's own random number
+/// generator. Search functions poll it to pick up counterexamples from other workers and to know
+/// when to stop.
+pub struct Worker {
+    id: usize,
+    num_workers: usize,
+    /// The seed `rng` was created with.
+    seed: u64,
+    rng: ChaCha20Rng,
+    tx: Sender<Event>,
+    rx: BusReader<Signal>,
+}
+
+impl Worker {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The number of workers run in parallel, including this one.
+    pub fn num_workers(&self) -> usize {
+        self.num_workers
+    }
+
+    pub fn rng(&mut self) -> &mut ChaCha20Rng {
+        &mut self.rng
+    }
+
+    /// Returns the seed of the random number generator and the number of 32 bit words drawn from
+    /// it, see [restore_rng](Worker::restore_rng).
+    pub fn rng_state(&self) -> (u64, u128) {
+        (self.seed, self.rng.get_word_pos())
+    }
+
+    /// Puts the random number generator back in the state given by
+    /// [rng_state](Worker::rng_state).
+    pub fn restore_rng(&mut self, seed: u64, word_pos: u128) {
+        self.seed = seed;
+        self.rng = ChaCha20Rng::seed_from_u64(seed);
+        self.rng.set_word_pos(word_pos);
+    }
+
+    /// Shares a counterexample found by this worker with all the other workers.
+    pub fn share_counterexample(&self, values: Vec<wasmer_runtime::Value>) {
+        let _ = self.tx.send(Event::CounterExample(self.id, values));
+    }
+
+    /// Adds counterexamples shared by other workers since the last call to given interpreter.
+    /// Returns the number of added test cases, or `None` if the search should stop.
+    pub fn sync(&mut self, interpreter: &mut dyn exec::Interpreter) -> Option<usize> {
+        let mut num_added = 0;
+        loop {
+            match self.rx.try_recv() {
+                Ok(Signal::CounterExample(id, values)) => {
+                    if id != self.id {
+                        interpreter.add_test_case(values);
+                        num_added += 1;
+                    }
+                }
+                Ok(Signal::Stop) | Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => return Some(num_added),
+            }
+        }
+    }
+}
+
+/// Runs `search` on given number of threads, each with a worker seeded by the corresponding
+/// seed. Workers are stopped when the time budget runs out, or in synthesis mode, as soon as one
+/// of them finds a candidate. Returns the candidates found by all workers.
+pub fn run<F>(
+    seeds: &[u64],
+    time_budget: chrono::Duration,
+    mode: Mode,
+    search: F,
+) -> Vec<wasm::Candidate>
+where
+    F: Fn(&mut Worker) -> Option<wasm::Candidate> + Send + Sync + 'static,
+{
+    let num_workers = seeds.len();
+    let search = Arc::new(search);
+    let (tx, rx) = channel();
+    let mut bus = Bus::new(BUS_CAPACITY);
+
+    let handles: Vec<_> = seeds
+        .iter()
+        .enumerate()
+        .map(|(id, &seed)| {
+            let mut worker = Worker {
+                id,
+                num_workers,
+                seed,
+                rng: ChaCha20Rng::seed_from_u64(seed),
+                tx: tx.clone(),
+                rx: bus.add_rx(),
+            };
+            let search = Arc::clone(&search);
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let result = search(&mut worker);
+                let _ = tx.send(Event::Done(result.is_some()));
+                result
+            })
+        })
+        .collect();
+
+    // Timer to terminate the search after given computing budget.
+    let timer = timer::Timer::new();
+    // It's necessary to name this variable to trigger the callback.
+    let _guard = timer.schedule_with_delay(time_budget, move || {
+        let _ = tx.send(Event::Timeout);
+    });
+
+    let mut num_done = 0;
+    while num_done < num_workers {
+        match rx.recv().unwrap() {
+            Event::CounterExample(id, values) => {
+                // Don't block on a worker that is busy, and would mostly get stale
+                // counterexamples anyway.
+                let _ = bus.try_broadcast(Signal::CounterExample(id, values));
+            }
+            Event::Done(found) => {
+                num_done += 1;
+                if found && mode == Mode::Synthesis {
+                    break;
+                }
+            }
+            Event::Timeout => {
+                println!("{:?} timed out", mode);
+                break;
+            }
+        }
+    }
+    bus.broadcast(Signal::Stop);
+
+    handles
+        .into_iter()
+        .filter_map(|handle| handle.join().expect("Search worker panicked."))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn restore_rng_test() {
+        let (tx, _rx) = channel();
+        let mut bus = Bus::new(1);
+        let mut worker = Worker {
+            id: 0,
+            num_workers: 1,
+            seed: 7,
+            rng: ChaCha20Rng::seed_from_u64(7),
+            tx,
+            rx: bus.add_rx(),
+        };
+
+        let _: u64 = worker.rng().gen();
+        let (seed, word_pos) = worker.rng_state();
+        let drawn: Vec<u32> = (0..4).map(|_| worker.rng().gen()).collect();
+
+        worker.restore_rng(seed, word_pos);
+        let redrawn: Vec<u32> = (0..4).map(|_| worker.rng().gen()).collect();
+        assert_eq!(drawn, redrawn);
+    }
+}